@@ -1,32 +1,47 @@
-use std::net::SocketAddr;
+use std::path::Path;
 
-use bearcub::{say_hello, server::connection::Connection, protocol::wire::Frame};
+use bearcub::{server::{connection::Connection, tls::{self, ClientTlsFiles}}, protocol::{auth, wire::Frame}};
 use bytes::Bytes;
-use tokio::{net::TcpStream, io::AsyncWriteExt};
-use anyhow::*;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
 
+const ADDR: &str = "127.0.0.1:9444";
 
 #[tokio::main]
 async fn main() {
-    let mut stream = TcpStream::connect("127.0.0.1:9444").await.unwrap();
+    #[cfg(unix)]
+    if let Ok(path) = std::env::var("BEARCUB_UNIX_SOCKET") {
+        return run(bearcub::server::unix::connect(Path::new(&path)).await.unwrap()).await;
+    }
+    // TLS when the server's CA is given, with a client certificate if the server wants one.
+    match std::env::var("BEARCUB_TLS_CA_FILE") {
+        Ok(ca_file) => {
+            let files = ClientTlsFiles {
+                ca_file,
+                cert_file: std::env::var("BEARCUB_TLS_CLIENT_CERT_FILE").ok(),
+                key_file: std::env::var("BEARCUB_TLS_CLIENT_KEY_FILE").ok(),
+            };
+            let server_name = std::env::var("BEARCUB_TLS_SERVER_NAME").unwrap_or_else(|_| "localhost".to_string());
+            let connector = tls::connector(&files).unwrap();
+            run(tls::connect(&connector, ADDR, &server_name).await.unwrap()).await;
+        },
+        Err(_) => run(Connection::new(TcpStream::connect(ADDR).await.unwrap())).await,
+    }
+}
 
+async fn run<S: AsyncRead + AsyncWrite + Unpin>(mut conn: Connection<S>) {
     // Write some data.
     // stream.write_all(b"hello world!").await;
-    let mut conn = Connection::new(stream);
-    let frame = Frame::new(Some("e17ca57f-a8db-4a0d-b9a9-6ff9edc983fd".to_string()), 1 as u32, 'G' as u8, Bytes::from_static(b"abc"));
-    conn.write_frame(&frame).await;
-    conn.write_frame(&frame).await;
-    let z = conn.read_frame().await.unwrap();
-    loop {
-        let read_res = conn.read_frame().await;
-        if let Some(frame) = read_res.ok() {
-            match frame {
-                Some(data) => {
-                    println!("GOT FRAME: {:?}", data);
-                },
-                None => break,
-            }
-        }
+    if let Ok(path) = std::env::var("BEARCUB_SECRET_FILE") {
+        let secret = auth::load_secret(Path::new(&path)).unwrap();
+        conn.authenticate(&secret).await.unwrap();
+    }
+    let frame = Frame::new(Some("e17ca57f-a8db-4a0d-b9a9-6ff9edc983fd".to_string()), 1, b'G', Bytes::from_static(b"abc"));
+    conn.write_frame(&frame).await.unwrap();
+    conn.write_frame(&frame).await.unwrap();
+    let _ = conn.read_frame().await.unwrap();
+    while let Ok(Some(data)) = conn.read_frame().await {
+        println!("GOT FRAME: {:?}", data);
     }
     /*let addr_str = "localhost:6739";
     let addr = addr_str.parse::<SocketAddr>().unwrap();
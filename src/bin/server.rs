@@ -1,41 +1,228 @@
 
-use bearcub::{server::connection::Connection, protocol::wire::Frame};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use bearcub::server::{config::ServerConfig, connection::Connection, handler::{self, ConnectionOptions}, metrics, provider::{ProviderOptions, TREE_FILE_NAME}, registry::ProviderRegistry, tls};
+#[cfg(unix)]
+use bearcub::server::unix;
+use bearcub::storage::{crypto::Keyring, schema::{self, MigrationOutcome, CURRENT_SCHEMA_VERSION}};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
-use bytes::Bytes;
 
 #[tokio::main]
 async fn main() {
-    // Bind the listener to the address
-    let listener = TcpListener::bind("127.0.0.1:9444").await.unwrap();
-    println!("Waiting...");
+    let config = ServerConfig::from_env();
 
-    loop {
-        // The second item contains the IP and port of the new connection.
-        let (socket, _) = listener.accept().await.unwrap();
+    let provider_options = match config.provider_options() {
+        Ok(options) => options,
+        Err(e) => {
+            println!("bad configuration: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(|a| a.as_str()) {
+        Some("migrate") => {
+            let data_dir = args.get(2).cloned().unwrap_or(config.data_dir);
+            std::process::exit(migrate(&data_dir, &provider_options));
+        },
+        Some("keygen") => {
+            let Some(key_file) = args.get(2).or(config.key_file.as_ref()) else {
+                println!("usage: server keygen <key file> (or set BEARCUB_KEY_FILE)");
+                std::process::exit(1);
+            };
+            match Keyring::generate(Path::new(key_file)) {
+                Ok(id) => println!("added key {} to {}", id, key_file),
+                Err(e) => {
+                    println!("cannot add a key to {}: {:?}", key_file, e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        },
+        _ => (),
+    }
+
+    // Only serving needs the secret; the subcommands above work without it.
+    let connection_options = match config.connection_options() {
+        Ok(options) => Arc::new(options),
+        Err(e) => {
+            println!("bad configuration: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let tls = match config.tls_files().and_then(|files| files.map(|f| tls::acceptor(&f)).transpose()) {
+        Ok(tls) => tls,
+        Err(e) => {
+            println!("bad TLS configuration: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(metrics_addr) = config.metrics_addr.clone() {
         tokio::spawn(async move {
-            process(socket).await;
+            if let Err(e) = metrics::serve(&metrics_addr).await {
+                println!("metrics endpoint failed: {:?}", e);
+            }
         });
     }
-}
 
-async fn process(socket: TcpStream) {
-    // The `Connection` lets us read/write redis **frames** instead of
-    // byte streams. The `Connection` type is defined by mini-redis.
-    let mut connection = Connection::new(socket);
+    let registry = Arc::new(ProviderRegistry::new(config.data_dir.clone(), config.provider_shards, config.provider_cache_bytes, provider_options));
+
+    let prune_registry = registry.clone();
+    let mut prune_interval = tokio::time::interval(config.history_prune_interval);
+    tokio::spawn(async move {
+        loop {
+            prune_interval.tick().await;
+            match prune_registry.prune_history().await {
+                Ok(n) if n > 0 => println!("pruned {} old revisions", n),
+                Err(e) => println!("history pruning failed: {:?}", e),
+                _ => (),
+            }
+        }
+    });
+
+    let trash_registry = registry.clone();
+    let trash_max_age = config.trash_max_age;
+    let mut purge_interval = tokio::time::interval(config.trash_purge_interval);
+    tokio::spawn(async move {
+        loop {
+            purge_interval.tick().await;
+            match trash_registry.purge_expired_trash(trash_max_age).await {
+                Ok(n) if n > 0 => println!("purged {} expired trash entries", n),
+                Err(e) => println!("trash purge failed: {:?}", e),
+                _ => (),
+            }
+        }
+    });
+
+    // Picks up files left under an older key after the key file gained a new one.
+    let rotation_registry = registry.clone();
+    let mut rotation_interval = tokio::time::interval(config.key_rotation_interval);
+    tokio::spawn(async move {
+        loop {
+            rotation_interval.tick().await;
+            match rotation_registry.rotate_keys().await {
+                Ok(n) if n > 0 => println!("re-encrypted {} files with the current key", n),
+                Err(e) => println!("key rotation failed: {:?}", e),
+                _ => (),
+            }
+        }
+    });
+
+    if config.listen_addr.is_none() && config.unix_socket.is_none() {
+        println!("bad configuration: set BEARCUB_LISTEN_ADDR or BEARCUB_UNIX_SOCKET");
+        std::process::exit(1);
+    }
+
+    // Connections on the Unix socket are local and guarded by the socket file's
+    // permissions, so they skip TLS.
+    #[cfg(unix)]
+    if let Some(path) = config.unix_socket.clone() {
+        let listener = match unix::bind(Path::new(&path), config.unix_socket_mode) {
+            Ok(listener) => listener,
+            Err(e) => {
+                println!("bad configuration: {:?}", e);
+                std::process::exit(1);
+            }
+        };
+        let registry = registry.clone();
+        let options = connection_options.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => spawn_connection(stream, registry.clone(), options.clone()),
+                    Err(e) => println!("accept failed on {}: {:?}", path, e),
+                }
+            }
+        });
+    }
+
+    // Bind the listener to the address
+    let listener = match &config.listen_addr {
+        Some(addr) => Some(TcpListener::bind(addr).await.unwrap()),
+        None => None,
+    };
+    println!("Waiting...");
 
     loop {
-        if let Some(frame) = connection.read_frame().await.ok() {
-            println!("GOT: {:?}", frame);
-
-            // Respond with an error
-            let response = Frame::new(None, 0 as u32, 'd' as u8, Bytes::new());
-            let write_res = connection.write_frame(&response).await;
-            if !write_res.is_ok() {
-                println!("client closed socket, breaking out...");
+        tokio::select! {
+            accepted = accept_tcp(&listener) => {
+                // The second item contains the IP and port of the new connection.
+                let (socket, _) = accepted.unwrap();
+                let registry = registry.clone();
+                let options = connection_options.clone();
+                match tls.clone() {
+                    Some(acceptor) => {
+                        tokio::spawn(async move {
+                            match acceptor.accept(socket).await {
+                                Ok(stream) => spawn_connection(stream, registry, options),
+                                Err(e) => println!("TLS handshake failed: {:?}", e),
+                            }
+                        });
+                    },
+                    None => spawn_connection(socket, registry, options),
+                }
+            }
+            _ = tokio::signal::ctrl_c() => {
+                println!("shutting down, flushing user trees...");
+                if let Err(e) = registry.flush_all().await {
+                    println!("flush failed: {:?}", e);
+                }
+                if let Some(path) = &config.unix_socket {
+                    let _ = std::fs::remove_file(path);
+                }
                 break;
             }
         }
+    }
+}
 
+// Waits forever when there is no TCP listener.
+async fn accept_tcp(listener: &Option<TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
     }
+}
+
+fn spawn_connection<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(stream: S, registry: Arc<ProviderRegistry>, options: Arc<ConnectionOptions>) {
+    tokio::spawn(async move {
+        metrics::metrics().active_connections.inc();
+        process(stream, registry, options).await;
+        metrics::metrics().active_connections.dec();
+    });
+}
+
+async fn process<S: AsyncRead + AsyncWrite + Unpin>(socket: S, registry: Arc<ProviderRegistry>, options: Arc<ConnectionOptions>) {
+    // The `Connection` lets us read/write redis **frames** instead of
+    // byte streams. The `Connection` type is defined by mini-redis.
+    let connection = Connection::new(socket);
+    handler::serve_connection(connection, registry, options).await;
+}
 
+// Offline upgrade of every user's tree to the current schema. Returns the exit code.
+fn migrate(data_dir: &str, options: &ProviderOptions) -> i32 {
+    let reports = match schema::migrate_data_dir(Path::new(data_dir), TREE_FILE_NAME, |user_id| options.file_options(user_id)) {
+        Ok(reports) => reports,
+        Err(e) => {
+            println!("cannot read {}: {:?}", data_dir, e);
+            return 1;
+        }
+    };
+    let mut failed = 0;
+    for report in &reports {
+        match &report.outcome {
+            MigrationOutcome::Upgraded { from } => println!("{}: upgraded v{} -> v{}", report.user_id, from, CURRENT_SCHEMA_VERSION),
+            MigrationOutcome::AlreadyCurrent => println!("{}: already v{}", report.user_id, CURRENT_SCHEMA_VERSION),
+            MigrationOutcome::Failed(e) => {
+                failed += 1;
+                println!("{}: FAILED: {}", report.user_id, e);
+            }
+        }
+    }
+    println!("{} users, {} failed", reports.len(), failed);
+    if failed > 0 { 1 } else { 0 }
 }
@@ -1,16 +1,37 @@
 pub mod protocol {
+    pub mod auth;
+    pub mod compression;
+    pub mod ids;
     pub mod types;
     pub mod wire;
 }
 
 pub mod server {
+    pub mod changes;
+    pub mod config;
     pub mod connection;
+    pub mod handler;
+    pub mod metrics;
     pub mod sharding;
+    pub mod tls;
+    #[cfg(unix)]
+    pub mod unix;
     pub mod provider;
+    pub mod quota;
+    pub mod registry;
+    pub mod upload;
 }
 
 pub mod storage {
+    pub mod blobfile;
+    pub mod crypto;
+    pub mod changelog;
     pub mod format;
+    pub mod history;
+    pub mod schema;
+    pub mod search;
+    pub mod tags;
+    pub mod trash;
 }
 
 pub fn say_hello() {
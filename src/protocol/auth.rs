@@ -0,0 +1,62 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::*;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// Random bytes the server sends in its 'K' challenge.
+pub const CHALLENGE_BYTES: usize = 32;
+// Length of the HMAC-SHA256 a client answers with in 'k'.
+pub const RESPONSE_BYTES: usize = 32;
+// Shorter shared secrets are refused, so a typo'd file cannot weaken every connection.
+pub const MIN_SECRET_BYTES: usize = 16;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// The answer to `challenge` from a client that holds `secret`.
+pub fn respond(secret: &[u8], challenge: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(challenge);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Whether `response` answers `challenge` for `secret`, compared in constant time.
+pub fn verify(secret: &[u8], challenge: &[u8], response: &[u8]) -> bool {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(challenge);
+    mac.verify_slice(response).is_ok()
+}
+
+// Reads a shared secret: the file's contents without surrounding whitespace.
+pub fn load_secret(path: &Path) -> Result<Vec<u8>> {
+    let text = fs::read(path).with_context(|| format!("cannot read secret file {:?}", path))?;
+    let secret = text.trim_ascii().to_vec();
+    if secret.len() < MIN_SECRET_BYTES {
+        bail!("the secret in {:?} is shorter than {} bytes", path, MIN_SECRET_BYTES);
+    }
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_respond_and_verify() {
+        let challenge = [3_u8; CHALLENGE_BYTES];
+        let response = respond(b"correct horse battery staple", &challenge);
+        assert_eq!(response.len(), RESPONSE_BYTES);
+        assert!(verify(b"correct horse battery staple", &challenge, &response));
+        assert!(!verify(b"correct horse battery stapler", &challenge, &response));
+        assert!(!verify(b"correct horse battery staple", &[4_u8; CHALLENGE_BYTES], &response));
+        assert!(!verify(b"correct horse battery staple", &challenge, &response[..16]));
+
+        let path = std::env::temp_dir().join(format!("bearcub-secret-{}", std::process::id()));
+        fs::write(&path, "  correct horse battery staple\n").unwrap();
+        assert_eq!(load_secret(&path).unwrap(), b"correct horse battery staple");
+        fs::write(&path, "hunter2\n").unwrap();
+        assert!(load_secret(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+use anyhow::*;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use super::wire::{Frame, COMPRESSED_FLAG};
+
+// zstd level used for frames and blob files: fast, and most of the gain on JSON.
+const ZSTD_LEVEL: i32 = 3;
+
+// Frames shorter than this are sent as they are.
+pub const MIN_COMPRESSED_FRAME_BYTES: usize = 256;
+
+// Largest frame body accepted after decompression, so a small frame cannot expand into
+// an arbitrary amount of memory.
+pub const MAX_DECOMPRESSED_FRAME_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    None = 0,
+    Lz4 = 1,
+    Zstd = 2,
+}
+
+impl Codec {
+    pub fn from_u8(codec: u8) -> Option<Codec> {
+        match codec {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Lz4),
+            2 => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Lz4 => Ok(lz4_flex::block::compress(data)),
+            Codec::Zstd => Ok(zstd::bulk::compress(data, ZSTD_LEVEL)?),
+        }
+    }
+
+    // Inverse of `compress`, given the length of the original data.
+    pub fn decompress(self, data: &[u8], raw_len: usize) -> Result<Vec<u8>> {
+        let out = match self {
+            Codec::None => data.to_vec(),
+            Codec::Lz4 => lz4_flex::block::decompress(data, raw_len)?,
+            Codec::Zstd => zstd::bulk::decompress(data, raw_len)?,
+        };
+        if out.len() != raw_len {
+            bail!("{:?} data decompressed to {} bytes, expected {}", self, out.len(), raw_len);
+        }
+        Ok(out)
+    }
+}
+
+impl std::str::FromStr for Codec {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Codec> {
+        match name.trim() {
+            "none" => Ok(Codec::None),
+            "lz4" => Ok(Codec::Lz4),
+            "zstd" => Ok(Codec::Zstd),
+            other => Err(anyhow!("unknown codec {}", other)),
+        }
+    }
+}
+
+// Compresses the body of `frame` with `codec` if that makes it smaller. A compressed
+// frame has `COMPRESSED_FLAG` set in its type, and its body is the codec, the original
+// length (u32) and the compressed bytes.
+pub fn compress_frame(frame: &Frame, codec: Codec) -> Result<Option<Frame>> {
+    if codec == Codec::None || frame.data.len() < MIN_COMPRESSED_FRAME_BYTES {
+        return Ok(None);
+    }
+    let packed = codec.compress(&frame.data[..])?;
+    if packed.len() + 5 >= frame.data.len() {
+        return Ok(None);
+    }
+    let mut buf = BytesMut::with_capacity(packed.len() + 5);
+    buf.put_u8(codec as u8);
+    buf.put_u32(frame.data.len() as u32);
+    buf.put_slice(&packed);
+    let flag = frame.msg_type_flag | COMPRESSED_FLAG;
+    Ok(Some(Frame::new(frame.user_id.clone(), frame.n_remaining_frames, flag, buf.freeze())))
+}
+
+// Undoes `compress_frame`; frames without `COMPRESSED_FLAG` are returned as they are.
+pub fn decompress_frame(frame: Frame) -> Result<Frame> {
+    if frame.msg_type_flag & COMPRESSED_FLAG == 0 {
+        return Ok(frame);
+    }
+    let mut data = frame.data.clone();
+    if data.len() < 5 {
+        bail!("short compressed frame");
+    }
+    let codec = Codec::from_u8(data.get_u8()).ok_or_else(|| anyhow!("unknown frame codec"))?;
+    let raw_len = data.get_u32() as usize;
+    if raw_len > MAX_DECOMPRESSED_FRAME_BYTES {
+        bail!("compressed frame expands to {} bytes", raw_len);
+    }
+    let raw = codec.decompress(&data[..], raw_len)?;
+    Ok(Frame::new(frame.user_id, frame.n_remaining_frames, frame.msg_type_flag & !COMPRESSED_FLAG, Bytes::from(raw)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_compression_roundtrip() {
+        let json = Bytes::from("{\"note\":\"hello hello hello\"},".repeat(100));
+        let frame = Frame::new(Some("2ab3da63-e24f-47e2-9b56-f3d19fade0cf".to_string()), 3, b'p', json.clone());
+        for codec in [Codec::Lz4, Codec::Zstd] {
+            let packed = compress_frame(&frame, codec).unwrap().unwrap();
+            assert_eq!(packed.msg_type_flag, b'p' | COMPRESSED_FLAG);
+            assert!(packed.data.len() < json.len() / 4);
+            let restored = decompress_frame(packed).unwrap();
+            assert_eq!((restored.msg_type_flag, restored.n_remaining_frames), (b'p', 3));
+            assert_eq!(restored.user_id, frame.user_id);
+            assert_eq!(restored.data, json);
+        }
+        // Not worth it: sent as is.
+        let short = Frame::new(None, 1, b'd', Bytes::from_static(b"{}"));
+        assert!(compress_frame(&short, Codec::Zstd).unwrap().is_none());
+    }
+}
@@ -0,0 +1,123 @@
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+use anyhow::*;
+
+use super::types::{RequestError, ERR_INVALID_ID};
+
+// Length of a UUID in its canonical text form, e.g. e17ca57f-a8db-4a0d-b9a9-6ff9edc983fd.
+pub const UUID_LEN: usize = 36;
+
+// Whether `s` is a UUID in canonical form: lowercase hex digits in 8-4-4-4-12 groups.
+// Ids end up in file names, so nothing else is let through.
+pub fn is_canonical_uuid(s: &[u8]) -> bool {
+    s.len() == UUID_LEN
+        && s.iter().enumerate().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => *c == b'-',
+            _ => c.is_ascii_digit() || (b'a'..=b'f').contains(c),
+        })
+}
+
+// A string newtype that only holds canonical UUIDs. Compares equal to the plain string.
+macro_rules! uuid_newtype {
+    ($name:ident, $what:literal) => {
+        #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub struct $name(String);
+
+        impl $name {
+            // Fails with `ERR_INVALID_ID` unless `s` is a canonical UUID.
+            pub fn parse(s: &str) -> Result<$name> {
+                $name::from_bytes(s.as_bytes())
+            }
+
+            pub fn from_bytes(bytes: &[u8]) -> Result<$name> {
+                if !is_canonical_uuid(bytes) {
+                    let shown = String::from_utf8_lossy(&bytes[..bytes.len().min(64)]).to_string();
+                    return Err(RequestError::new(ERR_INVALID_ID, format!("{} {:?} is not a canonical UUID", $what, shown)).into());
+                }
+                // Checked above: ASCII only.
+                Ok($name(String::from_utf8(bytes.to_vec()).unwrap()))
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl Deref for $name {
+            type Target = str;
+
+            fn deref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = Error;
+
+            fn from_str(s: &str) -> Result<$name> {
+                $name::parse(s)
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(id: $name) -> String {
+                id.0
+            }
+        }
+
+        impl PartialEq<str> for $name {
+            fn eq(&self, other: &str) -> bool {
+                self.0 == other
+            }
+        }
+
+        impl PartialEq<&str> for $name {
+            fn eq(&self, other: &&str) -> bool {
+                self.0 == *other
+            }
+        }
+    };
+}
+
+uuid_newtype!(UserId, "user id");
+uuid_newtype!(BlobId, "blob id");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_canonical_uuids_parse() {
+        let user = UserId::parse("2ab3da63-e24f-47e2-9b56-f3d19fade0cf").unwrap();
+        assert_eq!(user, "2ab3da63-e24f-47e2-9b56-f3d19fade0cf");
+        assert_eq!(user.len(), UUID_LEN);
+        for bad in [
+            "",
+            "../../etc",
+            "2ab3da63-e24f-47e2-9b56-f3d19fade0c",
+            "2ab3da63-e24f-47e2-9b56-f3d19fade0cff",
+            "2AB3DA63-E24F-47E2-9B56-F3D19FADE0CF",
+            "2ab3da63e-24f-47e2-9b56-f3d19fade0cf",
+            "2ab3da63-e24f-47e2-9b56-f3d19fade0c/",
+            "{ab3da63-e24f-47e2-9b56-f3d19fade0c}",
+        ] {
+            let err = BlobId::parse(bad).unwrap_err();
+            assert_eq!(err.downcast_ref::<RequestError>().unwrap().code, ERR_INVALID_ID, "{:?}", bad);
+        }
+        assert!(BlobId::from_bytes(&[0xff; UUID_LEN]).is_err());
+    }
+}
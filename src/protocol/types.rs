@@ -1,34 +1,203 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use anyhow::*;
 use bytes::{BytesMut, Bytes, BufMut, Buf};
-use tokio::io::BufWriter;
+use serde::{Serialize, Deserialize};
 
+use super::ids::{BlobId, UserId};
 use super::wire::Frame;
-
-// TODO: implement protocol stuff for `user_id` field
+use crate::storage::changelog::ChangeKind;
+use crate::storage::history::RevisionInfo;
 
 #[derive(Debug)]
 pub enum RequestMessage {
     Get {
-        user_id: String,
-        id: Option<String>,
+        user_id: UserId,
+        id: Option<BlobId>,
         path: Option<String>,
     },
+    // Like a Get by id, but answered with the node's metadata ahead of its payload.
+    GetNode {
+        user_id: UserId,
+        id: BlobId,
+    },
+    // With `expected_revision`, a Put of an existing blob overwrites its payload only if
+    // the stored revision matches; without it, Put only creates new blobs.
     Put {
-        user_id: String,
-        id: String,
-        parent: Option<String>,
+        user_id: UserId,
+        id: BlobId,
+        parent: Option<BlobId>,
+        expected_revision: Option<u64>,
         data: Bytes,
     },
+    // With `expected_revision`, the write is rejected with `ERR_CONFLICT` unless the
+    // stored revision matches.
     Set {
-        user_id: String,
-        id: String,
+        user_id: UserId,
+        id: BlobId,
+        expected_revision: Option<u64>,
         data: Bytes,
     },
     Remove {
-        user_id: String,
-        id: String,
+        user_id: UserId,
+        id: BlobId,
+    },
+    Usage {
+        user_id: UserId,
+    },
+    // Lists the stored revisions of a blob, the current one included.
+    ListRevisions {
+        user_id: UserId,
+        id: BlobId,
+    },
+    GetRevision {
+        user_id: UserId,
+        id: BlobId,
+        revision: u64,
+    },
+    // Makes an old revision's payload current again, as a new revision.
+    RestoreRevision {
+        user_id: UserId,
+        id: BlobId,
+        revision: u64,
+    },
+    ListTrash {
+        user_id: UserId,
+    },
+    // Puts a trashed subtree back under its old parent, at its old position.
+    RestoreTrash {
+        user_id: UserId,
+        id: BlobId,
+    },
+    // Permanently deletes one trashed subtree, or the whole trash if `id` is `None`.
+    PurgeTrash {
+        user_id: UserId,
+        id: Option<BlobId>,
+    },
+    // Subscribes the connection to the user's mutations, only those inside the subtree
+    // rooted at `root` if given. Events then arrive as unsolicited `Change` responses.
+    Watch {
+        user_id: UserId,
+        root: Option<BlobId>,
+    },
+    Unwatch {
+        user_id: UserId,
+    },
+    // Blobs changed after sequence number `since`, at most `limit` (0 = the server's
+    // page size) of them.
+    ChangesSince {
+        user_id: UserId,
+        since: u64,
+        limit: u32,
+    },
+    // The subtree under `root` (the whole tree if `None`) without payloads, `depth` levels
+    // deep (0 = all), in pre-order. Paged like `ChangesSince`: skips the first `offset`
+    // nodes and returns at most `limit` (0 = the server's page size).
+    ListTree {
+        user_id: UserId,
+        root: Option<BlobId>,
+        depth: u32,
+        offset: u64,
+        limit: u32,
+    },
+    // Blobs whose title or payload text has words of `query`, best first, at most `limit`
+    // (0 = the server's maximum) of them.
+    Search {
+        user_id: UserId,
+        query: String,
+        limit: u32,
+    },
+    // Applies all of `ops` in order, or none of them if one fails.
+    Batch {
+        user_id: UserId,
+        ops: Vec<BatchOp>,
+    },
+    // Adds `tags` to a blob; tags it already has are left as they are.
+    AddTags {
+        user_id: UserId,
+        id: BlobId,
+        tags: Vec<String>,
+    },
+    // Removes `tags` from a blob; tags it does not have are ignored.
+    RemoveTags {
+        user_id: UserId,
+        id: BlobId,
+        tags: Vec<String>,
+    },
+    // Ids of all blobs tagged `tag`.
+    ListTagged {
+        user_id: UserId,
+        tag: String,
+    },
+    // The values at `pointers` (JSON pointers, like `/title`) in the payloads of `ids`.
+    Project {
+        user_id: UserId,
+        ids: Vec<BlobId>,
+        pointers: Vec<String>,
+    },
+}
+
+// One operation of a `Batch`. Sent as a BSON document tagged by `op`.
+#[derive(Debug, Clone)]
+pub enum BatchOp {
+    // Like a Put request, but a new blob goes in at `position` among its siblings (the end
+    // if `None`).
+    Put {
+        id: BlobId,
+        parent: Option<BlobId>,
+        position: Option<u32>,
+        expected_revision: Option<u64>,
+        // `None` keeps the blob's title, or leaves a new blob untitled.
+        title: Option<String>,
+        // The blob's MIME type; `None` keeps it, or uses the default for a new blob.
+        content_type: Option<String>,
+        data: Bytes,
+    },
+    Set {
+        id: BlobId,
+        expected_revision: Option<u64>,
+        content_type: Option<String>,
+        data: Bytes,
+    },
+    Remove {
+        id: BlobId,
+    },
+    // Changes the title of `id`, leaving its payload as it is.
+    Rename {
+        id: BlobId,
+        title: String,
+    },
+    // Moves `id` and its subtree under `parent` (the root if `None`), at `position` among
+    // its new siblings (the end if `None`).
+    Move {
+        id: BlobId,
+        parent: Option<BlobId>,
+        position: Option<u32>,
+    },
+    // Moves `id` and its subtree under the parent of `sibling`, right before it, or right
+    // after it if `after`.
+    MoveNextTo {
+        id: BlobId,
+        sibling: BlobId,
+        after: bool,
+    },
+    // Reorders the children of `parent` (the root if `None`) by `by`; ties keep their order.
+    Sort {
+        parent: Option<BlobId>,
+        by: SortKey,
+        descending: bool,
     },
 }
 
+// What `BatchOp::Sort` orders siblings by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    // Case-insensitively.
+    Title,
+    Modified,
+}
+
 #[derive(Debug)]
 pub enum ResponseMessage {
     Error {
@@ -37,40 +206,516 @@ pub enum ResponseMessage {
     },
     Data {
         data: Bytes,
+    },
+    // Answers a `GetNode`: the node's metadata, then its payload.
+    Node {
+        meta: NodeMeta,
+        data: Bytes,
+    },
+    // Acknowledges a Put or Set with the blob's new revision.
+    Written {
+        revision: u64,
+    },
+    // Current storage use of a user, next to the configured limits (`None` = unlimited).
+    Usage {
+        payload_bytes: u64,
+        nodes: u64,
+        depth: u32,
+        max_payload_bytes: Option<u64>,
+        max_nodes: Option<u64>,
+        max_depth: Option<u32>,
+        max_blob_bytes: Option<u64>,
+    },
+    // Revisions of a blob, oldest first.
+    Revisions {
+        revisions: Vec<RevisionInfo>,
+    },
+    Trash {
+        items: Vec<TrashItem>,
+    },
+    // A page of changes. Pass `next_seq` as `since` to continue, also once `has_more` is
+    // false, to pick up later changes.
+    Changes {
+        changes: Vec<ChangeItem>,
+        next_seq: u64,
+        has_more: bool,
+    },
+    // One result per operation of a `Batch`, in order.
+    Batch {
+        results: Vec<BatchResult>,
+    },
+    SearchResults {
+        results: Vec<SearchResult>,
+    },
+    // Answers a `ListTagged`, in id order.
+    Tagged {
+        ids: Vec<BlobId>,
+    },
+    // One result per id of a `Project`, in order.
+    Projection {
+        results: Vec<ProjectionResult>,
+    },
+    // A page of a `ListTree`. Pass `next_offset` as `offset` to continue while `has_more`.
+    Tree {
+        items: Vec<TreeItem>,
+        next_offset: u64,
+        has_more: bool,
+    },
+    // Pushed to watching connections, between responses, whenever a watched blob changes.
+    Change {
+        id: String,
+        op: ChangeOp,
+        revision: u64,
+    },
+}
+
+// Outcome of one operation of a batch. Sent BSON-encoded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchResult {
+    // 0 if the operation was applied, else an error code. When one operation fails, the
+    // others report `ERR_ABORTED`.
+    pub code: u32,
+    pub description: String,
+    // New revision of the blob written or moved.
+    pub revision: Option<u64>,
+}
+
+// Latest change of one blob in a `Changes` page. Sent BSON-encoded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChangeItem {
+    pub id: String,
+    pub seq: u64,
+    pub kind: ChangeKind,
+}
+
+// What happened to the blob named by a `Change` event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeOp {
+    // Events were dropped because the client fell behind; it should re-read what it
+    // watches. Sent with an empty id.
+    Resync = 0,
+    Created = 1,
+    Updated = 2,
+    // Moved to the trash, with its subtree.
+    Removed = 3,
+    // Back from the trash, with its subtree.
+    Restored = 4,
+    // Under a new parent or at a new position, with its subtree.
+    Moved = 5,
+}
+
+impl ChangeOp {
+    pub fn from_u8(op: u8) -> Option<ChangeOp> {
+        match op {
+            0 => Some(ChangeOp::Resync),
+            1 => Some(ChangeOp::Created),
+            2 => Some(ChangeOp::Updated),
+            3 => Some(ChangeOp::Removed),
+            4 => Some(ChangeOp::Restored),
+            5 => Some(ChangeOp::Moved),
+            _ => None,
+        }
     }
 }
+
+// One blob found by a `Search`. Sent BSON-encoded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub id: String,
+    pub title: String,
+    // Only meaningful relative to the other results of the same search.
+    pub score: f64,
+    // Text around the first match, from the payload or else the title.
+    pub snippet: String,
+}
+
+// The projected fields of one blob. Sent BSON-encoded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProjectionResult {
+    pub id: String,
+    // 0 if the payload could be read as JSON, else an error code.
+    pub code: u32,
+    pub description: String,
+    pub revision: Option<u64>,
+    // Pointer -> the JSON text of the value there. Pointers with no value are left out.
+    pub fields: BTreeMap<String, String>,
+}
+
+// One node in a tree listing. Sent BSON-encoded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TreeItem {
+    pub id: String,
+    pub title: String,
+    // `None` for nodes at the top of the tree.
+    pub parent_id: Option<String>,
+    // Levels below the listed root, 1 for its children.
+    pub depth: u32,
+    pub position: u32,
+    // ms since the Unix epoch.
+    pub created: i64,
+    pub modified: i64,
+    pub size: u64,
+    pub content_type: String,
+    pub revision: u64,
+    // All of the node's children, also those deeper than the listing goes.
+    pub children: u32,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+// One removed subtree in a trash listing. Sent BSON-encoded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrashItem {
+    pub id: String,
+    pub title: String,
+    pub parent_id: String,
+    pub position: u32,
+    // ms since the Unix epoch.
+    pub deleted_at: i64,
+    // Nodes in the subtree, its root included.
+    pub nodes: u64,
+}
+
+// Metadata of one node, sent ahead of its payload in a `Node` response. Binary-encoded as
+// documented in the README.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeMeta {
+    pub revision: u64,
+    // Length of the payload that follows.
+    pub size: u64,
+    // `None` for nodes at the top of the tree.
+    pub parent: Option<BlobId>,
+    pub title: String,
+    pub content_type: String,
+    // In sibling order.
+    pub children: Vec<BlobId>,
+}
+
+// Revision, size, parent id, title length and content type length.
+const NODE_META_FIXED_SZ: usize = 8 + 8 + 36 + 4 + 4;
+
+impl NodeMeta {
+    pub fn encode(&self) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(NODE_META_FIXED_SZ + self.title.len() + self.content_type.len() + 4 + self.children.len() * 36);
+        buf.put_u64(self.revision);
+        buf.put_u64(self.size);
+        match &self.parent {
+            Some(parent) => buf.put_slice(parent.as_bytes()),
+            None => buf.put_bytes(0, 36),
+        }
+        buf.put_u32(self.title.len() as u32);
+        buf.put_slice(self.title.as_bytes());
+        buf.put_u32(self.content_type.len() as u32);
+        buf.put_slice(self.content_type.as_bytes());
+        buf.put_u32(self.children.len() as u32);
+        for c in &self.children {
+            buf.put_slice(c.as_bytes());
+        }
+        buf
+    }
+
+    // Decodes the metadata at the start of `buf`, returning it and its encoded length, or
+    // `None` if `buf` does not hold all of it yet.
+    pub fn decode(buf: &[u8]) -> Result<Option<(NodeMeta, usize)>> {
+        if buf.len() < NODE_META_FIXED_SZ {
+            return Ok(None);
+        }
+        let mut data = buf;
+        let revision = data.get_u64();
+        let size = data.get_u64();
+        let parent_bytes = &data[..36];
+        let parent = if parent_bytes.iter().all(|b| *b == 0) { None } else { Some(BlobId::from_bytes(parent_bytes)?) };
+        data.advance(36);
+        let title_len = data.get_u32() as usize;
+        if data.len() < title_len + 4 {
+            return Ok(None);
+        }
+        let title = String::from_utf8(data[..title_len].to_vec()).map_err(|_| anyhow!("node title is not UTF-8"))?;
+        data.advance(title_len);
+        let content_type_len = data.get_u32() as usize;
+        if data.len() < content_type_len + 4 {
+            return Ok(None);
+        }
+        let content_type = String::from_utf8(data[..content_type_len].to_vec()).map_err(|_| anyhow!("node content type is not UTF-8"))?;
+        data.advance(content_type_len);
+        let n_children = data.get_u32() as usize;
+        if data.len() < n_children * 36 {
+            return Ok(None);
+        }
+        let children = data[..n_children * 36].chunks(36).map(BlobId::from_bytes).collect::<Result<Vec<_>>>()?;
+        let len = NODE_META_FIXED_SZ + title_len + content_type_len + 4 + n_children * 36;
+        Ok(Some((NodeMeta { revision, size, parent, title, content_type, children }, len)))
+    }
+}
+
+// Error codes carried by 'e' frames.
+pub const ERR_BAD_REQUEST: u32 = 1;
+pub const ERR_NOT_FOUND: u32 = 2;
+pub const ERR_ALREADY_EXISTS: u32 = 3;
+pub const ERR_QUOTA_EXCEEDED: u32 = 4;
+pub const ERR_CONFLICT: u32 = 5;
+// The changes asked for are older than the retained tombstones; the client must resync.
+pub const ERR_RESYNC_REQUIRED: u32 = 6;
+// Not applied, or undone, because another operation of the same batch failed.
+pub const ERR_ABORTED: u32 = 7;
+// The client did not prove it holds the shared secret; the connection is closed.
+pub const ERR_UNAUTHENTICATED: u32 = 8;
+// A user id or blob id that is not a canonical UUID.
+pub const ERR_INVALID_ID: u32 = 9;
+// A payload that is not JSON, written while JSON validation is on or projected.
+pub const ERR_INVALID_JSON: u32 = 10;
+pub const ERR_INTERNAL: u32 = 500;
+
+// An error that should reach the client with a specific code. Anything else that fails
+// while handling a request is reported as `ERR_INTERNAL`.
+#[derive(Debug)]
+pub struct RequestError {
+    pub code: u32,
+    pub description: String,
+}
+
+impl RequestError {
+    pub fn new(code: u32, description: impl Into<String>) -> RequestError {
+        RequestError { code, description: description.into() }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error {}: {}", self.code, self.description)
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl From<Error> for RequestError {
+    fn from(e: Error) -> RequestError {
+        match e.downcast::<RequestError>() {
+            Result::Ok(re) => re,
+            Err(e) => RequestError::new(ERR_INTERNAL, format!("{:#}", e)),
+        }
+    }
+}
+
+impl From<Error> for ResponseMessage {
+    fn from(e: Error) -> ResponseMessage {
+        let re = RequestError::from(e);
+        ResponseMessage::Error { code: re.code, description: re.description }
+    }
+}
+
 pub const BUF_CAP: usize = 4096;
 pub const BUF_CAP_HEADER_SZ_RES: usize = 128;
 pub const DATA_BYTES_PER_FRAME : usize = BUF_CAP - BUF_CAP_HEADER_SZ_RES;
+// Id, parent id and expected revision at the start of a Put/Set.
+pub const WRITE_HEADER_SZ: usize = 36 + 36 + 8;
+pub const FIRST_WRITE_FRAME_DATA_BYTES: usize = DATA_BYTES_PER_FRAME - WRITE_HEADER_SZ;
 
 impl ResponseMessage {
     pub fn to_frames(mut self) -> Vec<Frame> {
         match &mut self {
             ResponseMessage::Error{code, description} => {
-                vec![]
+                let mut buf = BytesMut::with_capacity(4 + description.len());
+                buf.put_u32(*code);
+                buf.put_slice(description.as_bytes());
+                vec![Frame::new(None, 1, b'e', buf.freeze())]
             },
-            Self::Data{data} => {
-                let bytes_per_frame = DATA_BYTES_PER_FRAME;
-                let mut n_frames = data.len() / bytes_per_frame;
-                if data.len() % bytes_per_frame != 0 {
-                    n_frames += 1;
+            ResponseMessage::Usage{payload_bytes, nodes, depth, max_payload_bytes, max_nodes, max_depth, max_blob_bytes} => {
+                // Limits are sent as 0 when unlimited.
+                let mut buf = BytesMut::with_capacity(48);
+                buf.put_u64(*payload_bytes);
+                buf.put_u64(*nodes);
+                buf.put_u32(*depth);
+                buf.put_u64(max_payload_bytes.unwrap_or(0));
+                buf.put_u64(max_nodes.unwrap_or(0));
+                buf.put_u32(max_depth.unwrap_or(0));
+                buf.put_u64(max_blob_bytes.unwrap_or(0));
+                vec![Frame::new(None, 1, b'Q', buf.freeze())]
+            },
+            ResponseMessage::Written{revision} => {
+                let mut buf = BytesMut::with_capacity(8);
+                buf.put_u64(*revision);
+                vec![Frame::new(None, 1, b'w', buf.freeze())]
+            },
+            ResponseMessage::Revisions{revisions} => {
+                let mut buf = BytesMut::with_capacity(4 + revisions.len() * 24);
+                buf.put_u32(revisions.len() as u32);
+                for r in revisions.iter() {
+                    buf.put_u64(r.revision);
+                    buf.put_i64(r.modified);
+                    buf.put_u64(r.size);
                 }
+                typed_data_frames(None, b'H', buf.freeze())
+            },
+            ResponseMessage::Trash{items} => {
+                let doc = bson::doc! { "items": bson::to_bson(items).unwrap_or_default() };
+                typed_data_frames(None, b'T', Bytes::from(bson::to_vec(&doc).unwrap_or_default()))
+            },
+            ResponseMessage::Changes{changes, next_seq, has_more} => {
+                let doc = bson::doc! {
+                    "changes": bson::to_bson(changes).unwrap_or_default(),
+                    "next_seq": *next_seq as i64,
+                    "has_more": *has_more,
+                };
+                typed_data_frames(None, b'L', Bytes::from(bson::to_vec(&doc).unwrap_or_default()))
+            },
+            ResponseMessage::Change{id, op, revision} => {
+                let mut buf = BytesMut::with_capacity(9 + id.len());
+                buf.put_u8(*op as u8);
+                buf.put_u64(*revision);
+                buf.put_slice(id.as_bytes());
+                vec![Frame::new(None, 1, b'C', buf.freeze())]
+            },
+            ResponseMessage::Batch{results} => {
+                let doc = bson::doc! { "results": bson::to_bson(results).unwrap_or_default() };
+                typed_data_frames(None, b'b', Bytes::from(bson::to_vec(&doc).unwrap_or_default()))
+            },
+            ResponseMessage::SearchResults{results} => {
+                let doc = bson::doc! { "results": bson::to_bson(results).unwrap_or_default() };
+                typed_data_frames(None, b'F', Bytes::from(bson::to_vec(&doc).unwrap_or_default()))
+            },
+            ResponseMessage::Tree{items, next_offset, has_more} => {
+                let doc = bson::doc! {
+                    "items": bson::to_bson(items).unwrap_or_default(),
+                    "next_offset": *next_offset as i64,
+                    "has_more": *has_more,
+                };
+                typed_data_frames(None, b'Y', Bytes::from(bson::to_vec(&doc).unwrap_or_default()))
+            },
+            ResponseMessage::Projection{results} => {
+                let doc = bson::doc! { "results": bson::to_bson(results).unwrap_or_default() };
+                typed_data_frames(None, b'J', Bytes::from(bson::to_vec(&doc).unwrap_or_default()))
+            },
+            ResponseMessage::Tagged{ids} => {
+                let mut buf = BytesMut::with_capacity(4 + ids.len() * 36);
+                buf.put_u32(ids.len() as u32);
+                for id in ids.iter() {
+                    buf.put_slice(id.as_bytes());
+                }
+                typed_data_frames(None, b'I', buf.freeze())
+            },
+            ResponseMessage::Node{meta, data} => {
+                let mut buf = meta.encode();
+                buf.put(std::mem::take(data));
+                typed_data_frames(None, b'M', buf.freeze())
+            },
+            Self::Data{data} => typed_data_frames(None, b'd', std::mem::take(data)),
+        }
+    }
 
-                let data_buf = BytesMut::with_capacity(BUF_CAP);
-                let mut frames:Vec<Frame> = vec![];
-                let mut bs_remaining = data.len();
-
-                let mut frame_idx = 0;
-                while bs_remaining > 0 {
-                    let bs_to_read = bs_remaining.min(bytes_per_frame);
-                    let fr_dat = data.split_to(bs_to_read);
-                    let f = Frame::new(None, (n_frames - frame_idx) as u32, 'd' as u8, fr_dat); 
-                    bs_remaining -= bs_to_read;
-                    frame_idx += 1;
-                    frames.push(f);
+    // Decodes the frames of one response, as produced by `to_frames`.
+    pub fn from_frames(frames: Vec<Frame>) -> Result<ResponseMessage> {
+        let first = frames.first().ok_or_else(|| anyhow!("empty response"))?;
+        match first.msg_type_flag {
+            b'e' => {
+                let mut data = first.data.clone();
+                if data.len() < 4 {
+                    bail!("short error frame");
                 }
-                frames
+                let code = data.get_u32();
+                let description = String::from_utf8_lossy(&data[..]).to_string();
+                Ok(ResponseMessage::Error { code, description })
+            },
+            b'Q' => {
+                let mut data = first.data.clone();
+                if data.len() < 48 {
+                    bail!("short usage frame");
+                }
+                let non_zero = |v: u64| if v == 0 { None } else { Some(v) };
+                Ok(ResponseMessage::Usage {
+                    payload_bytes: data.get_u64(),
+                    nodes: data.get_u64(),
+                    depth: data.get_u32(),
+                    max_payload_bytes: non_zero(data.get_u64()),
+                    max_nodes: non_zero(data.get_u64()),
+                    max_depth: non_zero(data.get_u32() as u64).map(|v| v as u32),
+                    max_blob_bytes: non_zero(data.get_u64()),
+                })
+            },
+            b'w' => {
+                let mut data = first.data.clone();
+                if data.len() < 8 {
+                    bail!("short write acknowledgement");
+                }
+                Ok(ResponseMessage::Written { revision: data.get_u64() })
+            },
+            b'H' => {
+                let mut data = concat_data(frames);
+                if data.len() < 4 {
+                    bail!("short revision list");
+                }
+                let n = data.get_u32() as usize;
+                if data.len() < n * 24 {
+                    bail!("short revision list");
+                }
+                let revisions = (0..n)
+                    .map(|_| RevisionInfo { revision: data.get_u64(), modified: data.get_i64(), size: data.get_u64() })
+                    .collect();
+                Ok(ResponseMessage::Revisions { revisions })
             },
+            b'T' => {
+                let doc: bson::Document = bson::from_slice(&concat_data(frames)[..])?;
+                let items = bson::from_bson(doc.get("items").cloned().unwrap_or(bson::Bson::Array(vec![])))?;
+                Ok(ResponseMessage::Trash { items })
+            },
+            b'L' => {
+                let doc: bson::Document = bson::from_slice(&concat_data(frames)[..])?;
+                let changes = bson::from_bson(doc.get("changes").cloned().unwrap_or(bson::Bson::Array(vec![])))?;
+                Ok(ResponseMessage::Changes { changes, next_seq: doc.get_i64("next_seq")? as u64, has_more: doc.get_bool("has_more")? })
+            },
+            b'b' => {
+                let doc: bson::Document = bson::from_slice(&concat_data(frames)[..])?;
+                let results = bson::from_bson(doc.get("results").cloned().unwrap_or(bson::Bson::Array(vec![])))?;
+                Ok(ResponseMessage::Batch { results })
+            },
+            b'F' => {
+                let doc: bson::Document = bson::from_slice(&concat_data(frames)[..])?;
+                let results = bson::from_bson(doc.get("results").cloned().unwrap_or(bson::Bson::Array(vec![])))?;
+                Ok(ResponseMessage::SearchResults { results })
+            },
+            b'J' => {
+                let doc: bson::Document = bson::from_slice(&concat_data(frames)[..])?;
+                let results = bson::from_bson(doc.get("results").cloned().unwrap_or(bson::Bson::Array(vec![])))?;
+                Ok(ResponseMessage::Projection { results })
+            },
+            b'I' => {
+                let mut data = concat_data(frames);
+                if data.len() < 4 {
+                    bail!("short id list");
+                }
+                let n = data.get_u32() as usize;
+                if data.len() != n * 36 {
+                    bail!("id list of {} bytes for {} ids", data.len(), n);
+                }
+                let ids = data.chunks(36).map(BlobId::from_bytes).collect::<Result<Vec<_>>>()?;
+                Ok(ResponseMessage::Tagged { ids })
+            },
+            b'Y' => {
+                let doc: bson::Document = bson::from_slice(&concat_data(frames)[..])?;
+                let items = bson::from_bson(doc.get("items").cloned().unwrap_or(bson::Bson::Array(vec![])))?;
+                Ok(ResponseMessage::Tree { items, next_offset: doc.get_i64("next_offset")? as u64, has_more: doc.get_bool("has_more")? })
+            },
+            b'C' => {
+                let mut data = first.data.clone();
+                if data.len() < 9 {
+                    bail!("short change event");
+                }
+                let op = ChangeOp::from_u8(data.get_u8()).ok_or_else(|| anyhow!("unknown change op"))?;
+                let revision = data.get_u64();
+                let id = String::from_utf8(data.to_vec())?;
+                Ok(ResponseMessage::Change { id, op, revision })
+            },
+            b'M' => {
+                let mut data = concat_data(frames);
+                let (meta, len) = NodeMeta::decode(&data[..])?.ok_or_else(|| anyhow!("short node metadata"))?;
+                data.advance(len);
+                if data.len() as u64 != meta.size {
+                    bail!("node payload is {} bytes, expected {}", data.len(), meta.size);
+                }
+                Ok(ResponseMessage::Node { meta, data })
+            },
+            b'd' => Ok(ResponseMessage::Data { data: concat_data(frames) }),
+            other => bail!("unexpected response type {}", other as char),
         }
     }
 }
@@ -81,59 +726,493 @@ impl RequestMessage {
             RequestMessage::Get{user_id, id, path} => {
                 let mut frames = vec![];
                 if let Some(id) = id {
-                    frames.push(Frame::new(Some(user_id), 1, 'G' as u8, Bytes::from(id.clone())));
+                    frames.push(Frame::new(Some(user_id.into()), 1, b'G', Bytes::from(String::from(id))));
                 } else {
                     if let Some(path) = path {
-                        frames.push(Frame::new(Some(user_id), 1, 'P' as u8, Bytes::from(path.clone())));
+                        frames.push(Frame::new(Some(user_id.into()), 1, b'P', Bytes::from(path)));
                     }
                 }
                 frames
             },
-            RequestMessage::Put{user_id, id, parent, data} => {
-                put_set_frames(user_id, 'p' as u8, id, parent, data)
+            RequestMessage::GetNode{user_id, id} => {
+                vec![Frame::new(Some(user_id.into()), 1, b'g', Bytes::from(String::from(id)))]
             },
-            RequestMessage::Set{user_id, id, data} => {
-                let frames = put_set_frames(user_id, 's' as u8, id, None, data);
-                // println!("set frames: {:?}\n", frames);
-                frames
+            RequestMessage::Put{user_id, id, parent, expected_revision, data} => {
+                put_set_frames(user_id, b'p', id, parent, expected_revision, data)
+            },
+            RequestMessage::Set{user_id, id, expected_revision, data} => {
+                put_set_frames(user_id, b's', id, None, expected_revision, data)
             },
             RequestMessage::Remove{user_id, id} => {
-                let mut frames = vec![];
-                frames
+                vec![Frame::new(Some(user_id.into()), 1, b'r', Bytes::from(String::from(id)))]
+            },
+            RequestMessage::Usage{user_id} => {
+                vec![Frame::new(Some(user_id.into()), 1, b'q', Bytes::new())]
+            },
+            RequestMessage::ListRevisions{user_id, id} => {
+                vec![Frame::new(Some(user_id.into()), 1, b'h', Bytes::from(String::from(id)))]
+            },
+            RequestMessage::GetRevision{user_id, id, revision} => {
+                vec![Frame::new(Some(user_id.into()), 1, b'v', id_and_revision(&id, revision))]
+            },
+            RequestMessage::RestoreRevision{user_id, id, revision} => {
+                vec![Frame::new(Some(user_id.into()), 1, b'R', id_and_revision(&id, revision))]
+            },
+            RequestMessage::ListTrash{user_id} => {
+                vec![Frame::new(Some(user_id.into()), 1, b't', Bytes::new())]
+            },
+            RequestMessage::RestoreTrash{user_id, id} => {
+                vec![Frame::new(Some(user_id.into()), 1, b'U', Bytes::from(String::from(id)))]
+            },
+            RequestMessage::PurgeTrash{user_id, id} => {
+                vec![Frame::new(Some(user_id.into()), 1, b'X', Bytes::from(id.map(String::from).unwrap_or_default()))]
+            },
+            RequestMessage::Watch{user_id, root} => {
+                vec![Frame::new(Some(user_id.into()), 1, b'W', Bytes::from(root.map(String::from).unwrap_or_default()))]
+            },
+            RequestMessage::Unwatch{user_id} => {
+                vec![Frame::new(Some(user_id.into()), 1, b'O', Bytes::new())]
+            },
+            RequestMessage::ChangesSince{user_id, since, limit} => {
+                let mut buf = BytesMut::with_capacity(12);
+                buf.put_u64(since);
+                buf.put_u32(limit);
+                vec![Frame::new(Some(user_id.into()), 1, b'c', buf.freeze())]
+            },
+            RequestMessage::ListTree{user_id, root, depth, offset, limit} => {
+                let mut buf = BytesMut::with_capacity(52);
+                match root {
+                    Some(root) => buf.put_slice(root.as_bytes()),
+                    None => buf.put_bytes(0, 36),
+                }
+                buf.put_u32(depth);
+                buf.put_u64(offset);
+                buf.put_u32(limit);
+                vec![Frame::new(Some(user_id.into()), 1, b'l', buf.freeze())]
             },
+            RequestMessage::Search{user_id, query, limit} => {
+                let mut buf = BytesMut::with_capacity(4 + query.len());
+                buf.put_u32(limit);
+                buf.put_slice(query.as_bytes());
+                vec![Frame::new(Some(user_id.into()), 1, b'S', buf.freeze())]
+            },
+            RequestMessage::Batch{user_id, ops} => {
+                let ops: Vec<bson::Bson> = ops.iter().map(|op| bson::Bson::Document(op.to_document())).collect();
+                let doc = bson::doc! { "ops": ops };
+                typed_data_frames(Some(user_id.into()), b'B', Bytes::from(bson::to_vec(&doc).unwrap_or_default()))
+            },
+            RequestMessage::AddTags{user_id, id, tags} => {
+                typed_data_frames(Some(user_id.into()), b'a', id_and_tags(&id, &tags))
+            },
+            RequestMessage::RemoveTags{user_id, id, tags} => {
+                typed_data_frames(Some(user_id.into()), b'x', id_and_tags(&id, &tags))
+            },
+            RequestMessage::ListTagged{user_id, tag} => {
+                vec![Frame::new(Some(user_id.into()), 1, b'f', Bytes::from(tag))]
+            },
+            RequestMessage::Project{user_id, ids, pointers} => {
+                let doc = bson::doc! { "ids": ids.iter().map(|id| id.as_str()).collect::<Vec<_>>(), "pointers": pointers };
+                typed_data_frames(Some(user_id.into()), b'j', Bytes::from(bson::to_vec(&doc).unwrap_or_default()))
+            },
+        }
+    }
+
+    pub fn user_id(&self) -> &UserId {
+        match self {
+            RequestMessage::Get{user_id, ..} => user_id,
+            RequestMessage::GetNode{user_id, ..} => user_id,
+            RequestMessage::Put{user_id, ..} => user_id,
+            RequestMessage::Set{user_id, ..} => user_id,
+            RequestMessage::Remove{user_id, ..} => user_id,
+            RequestMessage::Usage{user_id} => user_id,
+            RequestMessage::ListRevisions{user_id, ..} => user_id,
+            RequestMessage::GetRevision{user_id, ..} => user_id,
+            RequestMessage::RestoreRevision{user_id, ..} => user_id,
+            RequestMessage::ListTrash{user_id} => user_id,
+            RequestMessage::RestoreTrash{user_id, ..} => user_id,
+            RequestMessage::PurgeTrash{user_id, ..} => user_id,
+            RequestMessage::Watch{user_id, ..} => user_id,
+            RequestMessage::Unwatch{user_id} => user_id,
+            RequestMessage::ChangesSince{user_id, ..} => user_id,
+            RequestMessage::ListTree{user_id, ..} => user_id,
+            RequestMessage::Search{user_id, ..} => user_id,
+            RequestMessage::Batch{user_id, ..} => user_id,
+            RequestMessage::AddTags{user_id, ..} => user_id,
+            RequestMessage::RemoveTags{user_id, ..} => user_id,
+            RequestMessage::ListTagged{user_id, ..} => user_id,
+            RequestMessage::Project{user_id, ..} => user_id,
+        }
+    }
+
+    // Decodes the frames of one request: the typed first frame followed by any 'd'
+    // continuation frames, laid out as documented in the README.
+    pub fn from_frames(frames: Vec<Frame>) -> Result<RequestMessage> {
+        let mut frames = frames.into_iter();
+        let first = frames.next().ok_or_else(|| bad_request("empty message"))?;
+        let user_id = UserId::parse(first.user_id.as_deref().ok_or_else(|| bad_request("missing user_id"))?)?;
+        let text = |data: &Bytes| String::from_utf8(data.to_vec()).map_err(|_| bad_request("expected UTF-8"));
+        let blob_id = |data: &Bytes| BlobId::from_bytes(data);
+
+        match first.msg_type_flag {
+            b'G' => Ok(RequestMessage::Get { user_id, id: Some(blob_id(&first.data)?), path: None }),
+            b'P' => Ok(RequestMessage::Get { user_id, id: None, path: Some(text(&first.data)?) }),
+            b'g' => Ok(RequestMessage::GetNode { user_id, id: blob_id(&first.data)? }),
+            b'r' => Ok(RequestMessage::Remove { user_id, id: blob_id(&first.data)? }),
+            b'q' => Ok(RequestMessage::Usage { user_id }),
+            b'h' => Ok(RequestMessage::ListRevisions { user_id, id: blob_id(&first.data)? }),
+            b't' => Ok(RequestMessage::ListTrash { user_id }),
+            b'U' => Ok(RequestMessage::RestoreTrash { user_id, id: blob_id(&first.data)? }),
+            b'X' => {
+                let id = if first.data.is_empty() { None } else { Some(blob_id(&first.data)?) };
+                Ok(RequestMessage::PurgeTrash { user_id, id })
+            },
+            b'W' => {
+                let root = if first.data.is_empty() { None } else { Some(blob_id(&first.data)?) };
+                Ok(RequestMessage::Watch { user_id, root })
+            },
+            b'O' => Ok(RequestMessage::Unwatch { user_id }),
+            b'c' => {
+                if first.data.len() != 12 {
+                    return Err(bad_request("expected a sequence number and a limit"));
+                }
+                let mut data = first.data.clone();
+                Ok(RequestMessage::ChangesSince { user_id, since: data.get_u64(), limit: data.get_u32() })
+            },
+            b'l' => {
+                if first.data.len() != 52 {
+                    return Err(bad_request("expected a root, a depth, an offset and a limit"));
+                }
+                let root_bytes = first.data.slice(0..36);
+                let root = if root_bytes.iter().all(|b| *b == 0) { None } else { Some(blob_id(&root_bytes)?) };
+                let mut data = first.data.slice(36..);
+                Ok(RequestMessage::ListTree { user_id, root, depth: data.get_u32(), offset: data.get_u64(), limit: data.get_u32() })
+            },
+            b'S' => {
+                if first.data.len() < 4 {
+                    return Err(bad_request("expected a limit and a query"));
+                }
+                let limit = first.data.slice(0..4).get_u32();
+                Ok(RequestMessage::Search { user_id, query: text(&first.data.slice(4..))?, limit })
+            },
+            b'f' => Ok(RequestMessage::ListTagged { user_id, tag: text(&first.data)? }),
+            b'j' => {
+                let mut buf = BytesMut::from(&first.data[..]);
+                for f in frames {
+                    if f.msg_type_flag != b'd' {
+                        return Err(bad_request("expected continued data frame"));
+                    }
+                    buf.put(f.data);
+                }
+                let doc: bson::Document = bson::from_slice(&buf[..]).map_err(|e| bad_request(format!("bad projection: {}", e)))?;
+                let strings = |key: &str| -> Result<Vec<String>> {
+                    let values = doc.get_array(key).map_err(|_| bad_request(format!("projection without {}", key)))?;
+                    values.iter().map(|v| v.as_str().map(String::from).ok_or_else(|| bad_request(format!("{} must be strings", key)))).collect()
+                };
+                let ids = strings("ids")?.iter().map(|id| BlobId::parse(id)).collect::<Result<Vec<_>>>()?;
+                Ok(RequestMessage::Project { user_id, ids, pointers: strings("pointers")? })
+            },
+            b'a' | b'x' => {
+                let mut buf = BytesMut::from(&first.data[..]);
+                for f in frames {
+                    if f.msg_type_flag != b'd' {
+                        return Err(bad_request("expected continued data frame"));
+                    }
+                    buf.put(f.data);
+                }
+                if buf.len() < 36 {
+                    return Err(bad_request("expected a blob id and tags"));
+                }
+                let body = buf.freeze();
+                let id = blob_id(&body.slice(0..36))?;
+                let tags = text(&body.slice(36..))?;
+                let tags = if tags.is_empty() { vec![] } else { tags.split('\n').map(String::from).collect() };
+                if first.msg_type_flag == b'a' {
+                    Ok(RequestMessage::AddTags { user_id, id, tags })
+                } else {
+                    Ok(RequestMessage::RemoveTags { user_id, id, tags })
+                }
+            },
+            b'v' | b'R' => {
+                if first.data.len() != 44 {
+                    return Err(bad_request("expected a blob id and a revision"));
+                }
+                let id = blob_id(&first.data.slice(0..36))?;
+                let revision = first.data.slice(36..44).get_u64();
+                if first.msg_type_flag == b'v' {
+                    Ok(RequestMessage::GetRevision { user_id, id, revision })
+                } else {
+                    Ok(RequestMessage::RestoreRevision { user_id, id, revision })
+                }
+            },
+            b'B' => {
+                let mut buf = BytesMut::from(&first.data[..]);
+                for f in frames {
+                    if f.msg_type_flag != b'd' {
+                        return Err(bad_request("expected continued data frame"));
+                    }
+                    buf.put(f.data);
+                }
+                let doc: bson::Document = bson::from_slice(&buf[..]).map_err(|e| bad_request(format!("bad batch: {}", e)))?;
+                let mut ops = vec![];
+                for op in doc.get_array("ops").map_err(|_| bad_request("batch without ops"))? {
+                    let bson::Bson::Document(op) = op else {
+                        return Err(bad_request("batch operations must be documents"));
+                    };
+                    ops.push(BatchOp::from_document(op)?);
+                }
+                Ok(RequestMessage::Batch { user_id, ops })
+            },
+            b'p' | b's' => {
+                let mut body = first.data.clone();
+                let (id, parent, expected_revision) = decode_write_header(&mut body)?;
+                let mut buf = BytesMut::from(&body[..]);
+                for f in frames {
+                    if f.msg_type_flag != b'd' {
+                        return Err(bad_request("expected continued data frame"));
+                    }
+                    buf.put(f.data);
+                }
+                let data = buf.freeze();
+                if first.msg_type_flag == b'p' {
+                    Ok(RequestMessage::Put { user_id, id, parent, expected_revision, data })
+                } else {
+                    Ok(RequestMessage::Set { user_id, id, expected_revision, data })
+                }
+            },
+            other => Err(bad_request(format!("unknown message type {}", other as char))),
+        }
+    }
+}
+
+impl BatchOp {
+    // The blob the operation acts on; `None` for a sort, which acts on a parent's children.
+    pub fn id(&self) -> Option<&BlobId> {
+        match self {
+            BatchOp::Put{id, ..} | BatchOp::Set{id, ..} | BatchOp::Remove{id} | BatchOp::Rename{id, ..} | BatchOp::Move{id, ..} | BatchOp::MoveNextTo{id, ..} => Some(id),
+            BatchOp::Sort{..} => None,
         }
     }
+
+    fn to_document(&self) -> bson::Document {
+        let binary = |data: &Bytes| bson::Binary { subtype: bson::spec::BinarySubtype::Generic, bytes: data.to_vec() };
+        let mut doc = bson::Document::new();
+        match self {
+            BatchOp::Put{id, parent, position, expected_revision, title, content_type, data} => {
+                doc.insert("op", "put");
+                doc.insert("id", id.as_str());
+                if let Some(parent) = parent {
+                    doc.insert("parent", parent.as_str());
+                }
+                if let Some(position) = position {
+                    doc.insert("position", *position as i64);
+                }
+                if let Some(rev) = expected_revision {
+                    doc.insert("expected_revision", *rev as i64);
+                }
+                if let Some(title) = title {
+                    doc.insert("title", title.as_str());
+                }
+                if let Some(content_type) = content_type {
+                    doc.insert("content_type", content_type.as_str());
+                }
+                doc.insert("data", binary(data));
+            },
+            BatchOp::Set{id, expected_revision, content_type, data} => {
+                doc.insert("op", "set");
+                doc.insert("id", id.as_str());
+                if let Some(rev) = expected_revision {
+                    doc.insert("expected_revision", *rev as i64);
+                }
+                if let Some(content_type) = content_type {
+                    doc.insert("content_type", content_type.as_str());
+                }
+                doc.insert("data", binary(data));
+            },
+            BatchOp::Remove{id} => {
+                doc.insert("op", "remove");
+                doc.insert("id", id.as_str());
+            },
+            BatchOp::Rename{id, title} => {
+                doc.insert("op", "rename");
+                doc.insert("id", id.as_str());
+                doc.insert("title", title.as_str());
+            },
+            BatchOp::Move{id, parent, position} => {
+                doc.insert("op", "move");
+                doc.insert("id", id.as_str());
+                if let Some(parent) = parent {
+                    doc.insert("parent", parent.as_str());
+                }
+                if let Some(position) = position {
+                    doc.insert("position", *position as i64);
+                }
+            },
+            BatchOp::MoveNextTo{id, sibling, after} => {
+                doc.insert("op", if *after { "move_after" } else { "move_before" });
+                doc.insert("id", id.as_str());
+                doc.insert("sibling", sibling.as_str());
+            },
+            BatchOp::Sort{parent, by, descending} => {
+                doc.insert("op", "sort");
+                if let Some(parent) = parent {
+                    doc.insert("parent", parent.as_str());
+                }
+                doc.insert("by", match by { SortKey::Title => "title", SortKey::Modified => "modified" });
+                doc.insert("descending", *descending);
+            },
+        }
+        doc
+    }
+
+    fn from_document(doc: &bson::Document) -> Result<BatchOp> {
+        let op = doc.get_str("op").map_err(|_| bad_request("batch operation without op"))?;
+        let parent = doc.get_str("parent").ok().map(BlobId::parse).transpose()?;
+        if op == "sort" {
+            let by = match doc.get_str("by").map_err(|_| bad_request("sort without by"))? {
+                "title" => SortKey::Title,
+                "modified" => SortKey::Modified,
+                other => return Err(bad_request(format!("cannot sort by {}", other))),
+            };
+            return Ok(BatchOp::Sort { parent, by, descending: doc.get_bool("descending").unwrap_or(false) });
+        }
+        let id = BlobId::parse(doc.get_str("id").map_err(|_| bad_request("batch operation without id"))?)?;
+        let position = doc.get_i64("position").ok()
+            .map(|p| u32::try_from(p).map_err(|_| bad_request(format!("position {} is out of range", p))))
+            .transpose()?;
+        let expected_revision = doc.get_i64("expected_revision").ok()
+            .map(|r| u64::try_from(r).map_err(|_| bad_request(format!("expected revision {} is out of range", r))))
+            .transpose()?;
+        let title = doc.get_str("title").ok().map(String::from);
+        let content_type = doc.get_str("content_type").ok().map(String::from);
+        let data = || match doc.get_binary_generic("data") {
+            Result::Ok(data) => Ok(Bytes::from(data.clone())),
+            Err(_) => Err(bad_request(format!("batch write of {} without data", id))),
+        };
+        match op {
+            "put" => Ok(BatchOp::Put { id: id.clone(), parent, position, expected_revision, title, content_type, data: data()? }),
+            "set" => Ok(BatchOp::Set { id: id.clone(), expected_revision, content_type, data: data()? }),
+            "remove" => Ok(BatchOp::Remove { id }),
+            "rename" => match title {
+                Some(title) => Ok(BatchOp::Rename { id, title }),
+                None => Err(bad_request(format!("rename of {} without title", id))),
+            },
+            "move" => Ok(BatchOp::Move { id, parent, position }),
+            "move_before" | "move_after" => {
+                let sibling = BlobId::parse(doc.get_str("sibling").map_err(|_| bad_request(format!("{} without sibling", op)))?)?;
+                Ok(BatchOp::MoveNextTo { id, sibling, after: op == "move_after" })
+            },
+            other => Err(bad_request(format!("unknown batch operation {}", other))),
+        }
+    }
+}
+
+// Splits the id, parent and expected revision off the front of the first frame of a Put
+// or Set, leaving the start of the payload in `data`.
+pub fn decode_write_header(data: &mut Bytes) -> Result<(BlobId, Option<BlobId>, Option<u64>)> {
+    if data.len() < WRITE_HEADER_SZ {
+        return Err(bad_request("write header too short"));
+    }
+    let body = data.split_off(WRITE_HEADER_SZ);
+    let header = std::mem::replace(data, body);
+    let id = BlobId::from_bytes(&header[0..36])?;
+    let parent_bytes = &header[36..72];
+    let parent = if parent_bytes.iter().all(|b| *b == 0) { None } else { Some(BlobId::from_bytes(parent_bytes)?) };
+    // Revisions start at 1, so 0 means "unconditional".
+    let expected_revision = match header.slice(72..80).get_u64() {
+        0 => None,
+        rev => Some(rev),
+    };
+    Ok((id, parent, expected_revision))
 }
 
-fn put_set_frames(user_id: String, msg_typ_code: u8, id: String, parent: Option<String>, mut data: Bytes) -> Vec<Frame> {
-    let mut frames = vec![];
-    let fr_sz = DATA_BYTES_PER_FRAME;
-    let mut buf = BytesMut::with_capacity(fr_sz);
+pub fn encode_write_header(id: &BlobId, parent: Option<&BlobId>, expected_revision: Option<u64>) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(WRITE_HEADER_SZ);
     buf.put_slice(id.as_bytes());
-    if let Some(pid) = parent {
-        buf.put_slice(pid.as_bytes());
+    match parent {
+        Some(pid) => buf.put_slice(pid.as_bytes()),
+        None => buf.put_bytes(0, 36),
+    }
+    buf.put_u64(expected_revision.unwrap_or(0));
+    buf
+}
+
+// Frames taken by a Put or Set of a `len` byte payload. The first frame also carries the
+// write header, so it holds that much less payload; an empty payload still takes one.
+pub fn write_frame_count(len: u64) -> usize {
+    let first = FIRST_WRITE_FRAME_DATA_BYTES as u64;
+    if len <= first {
+        1
     } else {
-        // do nothing
-        for _ in 0..36 {
-            buf.put_u8(0 as u8);
-        }
-    }
-    let mut n_frames = data.len() / fr_sz;
-    if data.len() % fr_sz != 0 {
-        n_frames += 1;
-    }
-    let mut bytes_left = data.len();
-    let mut ctr = 0;
-    let mut uid_opt = Some(user_id.clone());
-    while bytes_left > 0 {
-        println!("ctr = {}, bytes left = {}", ctr, bytes_left);
-        let bytes_to_write = bytes_left.min(fr_sz);
-        let fr_dat = data.split_to(bytes_to_write);
-        let mtc = if(ctr == 0) { msg_typ_code } else { 'd' as u8 }; // Continued data frame
-        frames.push(Frame::new(uid_opt, (n_frames - ctr) as u32, mtc, fr_dat));
-        uid_opt = None;
-        ctr += 1;
-        bytes_left -= bytes_to_write;
+        1 + (len - first).div_ceil(DATA_BYTES_PER_FRAME as u64) as usize
+    }
+}
+
+fn bad_request(description: impl Into<String>) -> Error {
+    RequestError::new(ERR_BAD_REQUEST, description).into()
+}
+
+fn id_and_revision(id: &str, revision: u64) -> Bytes {
+    let mut buf = BytesMut::with_capacity(44);
+    buf.put_slice(id.as_bytes());
+    buf.put_u64(revision);
+    buf.freeze()
+}
+
+// A blob id followed by `tags`, one per line.
+fn id_and_tags(id: &str, tags: &[String]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(36 + tags.iter().map(|t| t.len() + 1).sum::<usize>());
+    buf.put_slice(id.as_bytes());
+    buf.put_slice(tags.join("\n").as_bytes());
+    buf.freeze()
+}
+
+// Splits a message body over as many frames as it needs: the first is typed
+// `msg_type_flag` and carries `user_id` if given, the rest are 'd' continuations.
+fn typed_data_frames(user_id: Option<String>, msg_type_flag: u8, mut data: Bytes) -> Vec<Frame> {
+    // An empty body still gets one (empty) frame, so every request is answered.
+    if data.is_empty() {
+        return vec![Frame::new(user_id, 1, msg_type_flag, Bytes::new())];
+    }
+    let bytes_per_frame = DATA_BYTES_PER_FRAME;
+    let n_frames = data.len().div_ceil(bytes_per_frame);
+
+    let mut frames:Vec<Frame> = vec![];
+    let mut bs_remaining = data.len();
+
+    let mut frame_idx = 0;
+    let mut uid_opt = user_id;
+    while bs_remaining > 0 {
+        let bs_to_read = bs_remaining.min(bytes_per_frame);
+        let fr_dat = data.split_to(bs_to_read);
+        let mtc = if frame_idx == 0 { msg_type_flag } else { b'd' };
+        let f = Frame::new(uid_opt.take(), (n_frames - frame_idx) as u32, mtc, fr_dat);
+        bs_remaining -= bs_to_read;
+        frame_idx += 1;
+        frames.push(f);
+    }
+    frames
+}
+
+fn concat_data(frames: Vec<Frame>) -> Bytes {
+    let mut buf = BytesMut::new();
+    for f in frames {
+        buf.put(f.data);
+    }
+    buf.freeze()
+}
+
+// Frames of a Put or Set: the first carries the user id, the write header and as much of
+// the payload as fits after it; the rest continue in 'd' frames. Laid out like
+// `Connection::write_blob_stream`, and an empty payload still takes one frame.
+fn put_set_frames(user_id: UserId, msg_typ_code: u8, id: BlobId, parent: Option<BlobId>, expected_revision: Option<u64>, mut data: Bytes) -> Vec<Frame> {
+    let n_frames = write_frame_count(data.len() as u64);
+    let mut frames = Vec::with_capacity(n_frames);
+    let mut first = encode_write_header(&id, parent.as_ref(), expected_revision);
+    first.put(data.split_to(data.len().min(FIRST_WRITE_FRAME_DATA_BYTES)));
+    frames.push(Frame::new(Some(user_id.into()), n_frames as u32, msg_typ_code, first.freeze()));
+    while !data.is_empty() {
+        let chunk = data.split_to(data.len().min(DATA_BYTES_PER_FRAME));
+        frames.push(Frame::new(None, (n_frames - frames.len()) as u32, b'd', chunk));
     }
     frames
 }
@@ -147,41 +1226,251 @@ mod tests {
     #[test]
     fn test_get_id_to_frames() {
         let id_str = String::from("2ab3da63-e24f-47e2-9b56-f3d19fade0cf");
-        let msg = RequestMessage::Get {user_id: "2ab3da63-e24f-47e2-9b56-f3d19fade0cf".to_string(), id: Some(id_str.clone()), path: None };
+        let msg = RequestMessage::Get {user_id: UserId::parse("2ab3da63-e24f-47e2-9b56-f3d19fade0cf").unwrap(), id: Some(BlobId::parse(&id_str).unwrap()), path: None };
         let frames = msg.to_frames();
         assert_eq!(frames.len(), 1);
         assert_eq!(frames[0].size(), 49+36);
-        assert_eq!(String::from_utf8(frames[0].data.to_vec()).unwrap().eq(&id_str), true);
+        assert!(String::from_utf8(frames[0].data.to_vec()).unwrap().eq(&id_str));
     }
 
     #[test]
     fn test_set_large_msg() {
         let id_str = String::from("2ab3da63-e24f-47e2-9b56-f3d19fade0cf");
         let mut data_buf = BytesMut::with_capacity(BUF_CAP*2);
-        for i in 0..(BUF_CAP*2) {
-            data_buf.put_u8(3 as u8);
+        for _ in 0..(BUF_CAP*2) {
+            data_buf.put_u8(3_u8);
         }
-        let msg = RequestMessage::Set {user_id: "2ab3da63-e24f-47e2-9b56-f3d19fade0cf".to_string(),  id: id_str.clone(), data: data_buf.freeze() };
+        let msg = RequestMessage::Set {user_id: UserId::parse("2ab3da63-e24f-47e2-9b56-f3d19fade0cf").unwrap(),  id: BlobId::parse(&id_str).unwrap(), expected_revision: None, data: data_buf.freeze() };
         let frames = msg.to_frames();
        
         assert_eq!(frames.len(), 3);
         
-        for i in 0..(frames.len() - 1) {
+        for (i, frame) in frames.iter().take(frames.len() - 1).enumerate() {
             if i == 0 {
-                assert_eq!(frames[i].size(), 13 + 36 + DATA_BYTES_PER_FRAME);
+                assert_eq!(frame.size(), 13 + 36 + DATA_BYTES_PER_FRAME);
             } else {
-                assert_eq!(frames[i].size(), 13 + DATA_BYTES_PER_FRAME);
+                assert_eq!(frame.size(), 13 + DATA_BYTES_PER_FRAME);
             }
         }
 
-        let mut new_buf = BytesMut::with_capacity(BUF_CAP*2);
-        for f in frames {
-            new_buf.put(f.data);
+        assert_eq!(&frames[0].data[..36], id_str.as_bytes());
+        assert_eq!(&frames[0].data[36..WRITE_HEADER_SZ], &[0_u8; WRITE_HEADER_SZ - 36][..]);
+        assert_eq!(frames.iter().map(|f| f.n_remaining_frames).collect::<Vec<_>>(), vec![3, 2, 1]);
+
+        match RequestMessage::from_frames(frames).unwrap() {
+            RequestMessage::Set{id, expected_revision, data, ..} => {
+                assert_eq!(id, id_str.as_str());
+                assert_eq!(expected_revision, None);
+                assert_eq!(data.len(), BUF_CAP*2);
+                assert!(data.iter().all(|b| *b == 3_u8));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_put_from_frames_reads_header() {
+        let user = "2ab3da63-e24f-47e2-9b56-f3d19fade0cf";
+        let id = "e17ca57f-a8db-4a0d-b9a9-6ff9edc983fd";
+        let mut first = BytesMut::new();
+        first.put_slice(id.as_bytes());
+        first.put_bytes(0, 36);
+        first.put_u64(7);
+        first.put_slice(b"ab");
+        let frames = vec![
+            Frame::new(Some(user.to_string()), 2, b'p', first.freeze()),
+            Frame::new(None, 1, b'd', Bytes::from_static(b"cd")),
+        ];
+        match RequestMessage::from_frames(frames).unwrap() {
+            RequestMessage::Put{user_id, id: got_id, parent, expected_revision, data} => {
+                assert_eq!(user_id, user);
+                assert_eq!(got_id, id);
+                assert!(parent.is_none());
+                assert_eq!(expected_revision, Some(7));
+                assert_eq!(&data[..], b"abcd");
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_ids_are_validated_on_decode() {
+        let user = "2ab3da63-e24f-47e2-9b56-f3d19fade0cf";
+        let id = "e17ca57f-a8db-4a0d-b9a9-6ff9edc983fd";
+        let code = |frames: Vec<Frame>| RequestMessage::from_frames(frames).unwrap_err().downcast::<RequestError>().unwrap().code;
+
+        let traversal = "../../../../../../../../../../etc/pw";
+        assert_eq!(code(vec![Frame::new(Some(traversal.to_string()), 1, b'q', Bytes::new())]), ERR_INVALID_ID);
+        assert_eq!(code(vec![Frame::new(Some(user.to_string()), 1, b'r', Bytes::from_static(b"e17ca57f"))]), ERR_INVALID_ID);
+        assert_eq!(code(vec![Frame::new(Some(user.to_string()), 1, b'W', Bytes::from(id.to_uppercase()))]), ERR_INVALID_ID);
+
+        let mut header = BytesMut::new();
+        header.put_slice(id.as_bytes());
+        header.put_slice(traversal.as_bytes());
+        header.put_u64(0);
+        assert_eq!(code(vec![Frame::new(Some(user.to_string()), 1, b'p', header.freeze())]), ERR_INVALID_ID);
+
+        let doc = bson::doc! { "ops": [ { "op": "remove", "id": "a" } ] };
+        assert_eq!(code(vec![Frame::new(Some(user.to_string()), 1, b'B', Bytes::from(bson::to_vec(&doc).unwrap()))]), ERR_INVALID_ID);
+    }
+
+    #[test]
+    fn test_error_and_usage_roundtrip() {
+        let frames = ResponseMessage::Error{code: ERR_QUOTA_EXCEEDED, description: "full".to_string()}.to_frames();
+        assert_eq!(frames.len(), 1);
+        match ResponseMessage::from_frames(frames).unwrap() {
+            ResponseMessage::Error{code, description} => {
+                assert_eq!(code, ERR_QUOTA_EXCEEDED);
+                assert_eq!(description, "full");
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let usage = ResponseMessage::Usage{payload_bytes: 7, nodes: 3, depth: 2, max_payload_bytes: Some(100), max_nodes: None, max_depth: Some(4), max_blob_bytes: None};
+        match ResponseMessage::from_frames(usage.to_frames()).unwrap() {
+            ResponseMessage::Usage{payload_bytes, nodes, depth, max_payload_bytes, max_nodes, max_depth, max_blob_bytes} => {
+                assert_eq!((payload_bytes, nodes, depth), (7, 3, 2));
+                assert_eq!((max_payload_bytes, max_nodes, max_depth, max_blob_bytes), (Some(100), None, Some(4), None));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_revision_list_spans_frames() {
+        let revisions: Vec<RevisionInfo> = (1..=400).map(|r| RevisionInfo { revision: r, modified: 1000 + r as i64, size: r * 2 }).collect();
+        let frames = ResponseMessage::Revisions{revisions: revisions.clone()}.to_frames();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].msg_type_flag, b'H');
+        assert_eq!(frames[1].msg_type_flag, b'd');
+        match ResponseMessage::from_frames(frames).unwrap() {
+            ResponseMessage::Revisions{revisions: got} => assert_eq!(got, revisions),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_batch_roundtrip() {
+        let user = "2ab3da63-e24f-47e2-9b56-f3d19fade0cf";
+        let [a, b, c] = ["e17ca57f-a8db-4a0d-b9a9-6ff9edc983fd", "8b0f5a1c-77d2-4c1e-9a5e-1f0b2c3d4e5f", "0c9d6e1a-3b2f-4a5c-8d7e-6f5a4b3c2d1e"].map(|id| BlobId::parse(id).unwrap());
+        let ops = vec![
+            BatchOp::Put { id: a.clone(), parent: None, position: Some(2), expected_revision: None, title: Some("Notes".to_string()), content_type: Some("text/plain".to_string()), data: Bytes::from(vec![7_u8; BUF_CAP]) },
+            BatchOp::Move { id: b.clone(), parent: Some(a.clone()), position: Some(0) },
+            BatchOp::Remove { id: c.clone() },
+            BatchOp::MoveNextTo { id: c.clone(), sibling: b, after: true },
+            BatchOp::Sort { parent: None, by: SortKey::Modified, descending: true },
+            BatchOp::Rename { id: c.clone(), title: "Archive".to_string() },
+        ];
+        let frames = RequestMessage::Batch { user_id: UserId::parse(user).unwrap(), ops }.to_frames();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].user_id.as_deref(), Some(user));
+        match RequestMessage::from_frames(frames).unwrap() {
+            RequestMessage::Batch { ops, .. } => {
+                assert!(matches!(&ops[0], BatchOp::Put { data, parent: None, position: Some(2), title: Some(n), content_type: Some(t), .. } if data.len() == BUF_CAP && n == "Notes" && t == "text/plain"));
+                assert!(matches!(&ops[1], BatchOp::Move { parent: Some(p), position: Some(0), .. } if *p == a));
+                assert_eq!(ops[2].id(), Some(&c));
+                assert!(matches!(&ops[3], BatchOp::MoveNextTo { after: true, .. }));
+                assert!(matches!(&ops[4], BatchOp::Sort { parent: None, by: SortKey::Modified, descending: true }));
+                assert_eq!(ops[4].id(), None);
+                assert!(matches!(&ops[5], BatchOp::Rename { id, title } if *id == c && title == "Archive"));
+            },
+            other => panic!("unexpected {:?}", other),
         }
+    }
 
-        let new_bytes = new_buf.to_vec();
-        for b in new_bytes {
-            assert_eq!(b, 3 as u8);
+    #[test]
+    fn test_batch_rejects_out_of_range_numbers() {
+        let user = "2ab3da63-e24f-47e2-9b56-f3d19fade0cf";
+        let id = "e17ca57f-a8db-4a0d-b9a9-6ff9edc983fd";
+        let data = bson::Binary { subtype: bson::spec::BinarySubtype::Generic, bytes: b"{}".to_vec() };
+        let code = |op: bson::Document| {
+            let doc = bson::doc! { "ops": [op] };
+            let frames = vec![Frame::new(Some(user.to_string()), 1, b'B', Bytes::from(bson::to_vec(&doc).unwrap()))];
+            RequestMessage::from_frames(frames).unwrap_err().downcast::<RequestError>().unwrap().code
+        };
+        assert_eq!(code(bson::doc! { "op": "set", "id": id, "expected_revision": -1_i64, "data": data.clone() }), ERR_BAD_REQUEST);
+        assert_eq!(code(bson::doc! { "op": "put", "id": id, "position": -1_i64, "data": data }), ERR_BAD_REQUEST);
+        assert_eq!(code(bson::doc! { "op": "move", "id": id, "position": u32::MAX as i64 + 1 }), ERR_BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_node_roundtrip() {
+        // Enough children for the metadata alone to need more than one frame.
+        let children = (0..150).map(|i| BlobId::parse(&format!("00000000-0000-4000-8000-{:012x}", i)).unwrap()).collect::<Vec<_>>();
+        let data = Bytes::from(vec![7_u8; 100]);
+        let meta = NodeMeta {
+            revision: 3,
+            size: data.len() as u64,
+            parent: Some(BlobId::parse("e17ca57f-a8db-4a0d-b9a9-6ff9edc983fd").unwrap()),
+            title: "Grocery list".to_string(),
+            content_type: "application/json".to_string(),
+            children,
+        };
+        let frames = (ResponseMessage::Node { meta: meta.clone(), data: data.clone() }).to_frames();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].msg_type_flag, b'M');
+        let encoded = meta.encode();
+        assert!(NodeMeta::decode(&encoded[..encoded.len() - 1]).unwrap().is_none());
+        match ResponseMessage::from_frames(frames).unwrap() {
+            ResponseMessage::Node { meta: got, data: got_data } => assert_eq!((got, got_data), (meta, data)),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_tag_messages_roundtrip() {
+        let user = UserId::parse("2ab3da63-e24f-47e2-9b56-f3d19fade0cf").unwrap();
+        let id = BlobId::parse("e17ca57f-a8db-4a0d-b9a9-6ff9edc983fd").unwrap();
+        // Long enough tags to continue in a second frame.
+        let tags: Vec<String> = (0..40).map(|i| format!("{:0100}", i)).collect();
+        let frames = RequestMessage::RemoveTags { user_id: user.clone(), id: id.clone(), tags: tags.clone() }.to_frames();
+        assert_eq!(frames.len(), 2);
+        match RequestMessage::from_frames(frames).unwrap() {
+            RequestMessage::RemoveTags { id: got, tags: got_tags, .. } => assert_eq!((got, got_tags), (id.clone(), tags)),
+            other => panic!("unexpected {:?}", other),
+        }
+        match RequestMessage::from_frames(RequestMessage::AddTags { user_id: user, id: id.clone(), tags: vec![] }.to_frames()).unwrap() {
+            RequestMessage::AddTags { tags, .. } => assert!(tags.is_empty()),
+            other => panic!("unexpected {:?}", other),
+        }
+        match ResponseMessage::from_frames(ResponseMessage::Tagged { ids: vec![id.clone(), id.clone()] }.to_frames()).unwrap() {
+            ResponseMessage::Tagged { ids } => assert_eq!(ids, vec![id.clone(), id]),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_projection_roundtrip() {
+        let user = UserId::parse("2ab3da63-e24f-47e2-9b56-f3d19fade0cf").unwrap();
+        let ids: Vec<BlobId> = (0..200).map(|i| BlobId::parse(&format!("00000000-0000-4000-8000-{:012x}", i)).unwrap()).collect();
+        let pointers = vec!["/title".to_string(), "/updated".to_string()];
+        let frames = RequestMessage::Project { user_id: user, ids: ids.clone(), pointers: pointers.clone() }.to_frames();
+        assert!(frames.len() > 1);
+        match RequestMessage::from_frames(frames).unwrap() {
+            RequestMessage::Project { ids: got_ids, pointers: got_pointers, .. } => assert_eq!((got_ids, got_pointers), (ids, pointers)),
+            other => panic!("unexpected {:?}", other),
+        }
+        let results = vec![ProjectionResult {
+            id: "e17ca57f-a8db-4a0d-b9a9-6ff9edc983fd".to_string(),
+            code: 0,
+            description: String::new(),
+            revision: Some(4),
+            fields: BTreeMap::from([("/title".to_string(), "\"Groceries\"".to_string())]),
+        }];
+        match ResponseMessage::from_frames(ResponseMessage::Projection { results: results.clone() }.to_frames()).unwrap() {
+            ResponseMessage::Projection { results: got } => assert_eq!(got, results),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_change_event_roundtrip() {
+        let id = "e17ca57f-a8db-4a0d-b9a9-6ff9edc983fd";
+        let frames = ResponseMessage::Change{id: id.to_string(), op: ChangeOp::Removed, revision: 9}.to_frames();
+        assert_eq!(frames[0].msg_type_flag, b'C');
+        match ResponseMessage::from_frames(frames).unwrap() {
+            ResponseMessage::Change{id: got, op, revision} => assert_eq!((got.as_str(), op, revision), (id, ChangeOp::Removed, 9)),
+            other => panic!("unexpected {:?}", other),
         }
     }
 }
@@ -1,6 +1,10 @@
-use bytes::{Bytes, BytesMut, BufMut, Buf};
+use anyhow::*;
+use bytes::{Bytes, BytesMut, BufMut};
 use std::io::{Cursor, Read};
 
+use crate::protocol::ids::{UserId, UUID_LEN};
+use crate::protocol::types::{RequestError, DATA_BYTES_PER_FRAME, ERR_BAD_REQUEST};
+
 #[derive(Debug)]
 pub struct Frame {
     pub user_id: Option<String>,
@@ -13,13 +17,12 @@ pub struct Frame {
 impl Frame {
 
     pub fn new(user_id: Option<String>, n_remaining_frames:u32, msg_type_flag:u8, data: Bytes) -> Frame {
-        let mut f = Frame{
+        Frame{
             user_id,
             n_remaining_frames, 
             msg_type_flag, 
             data, 
-        };
-        f
+        }
     }
 
     pub fn size(&self) -> usize {
@@ -37,128 +40,178 @@ impl Frame {
         bs.put_u32(total_sz as u32);
         bs.put_u32(self.n_remaining_frames);
         bs.put_u8(self.msg_type_flag);
-        if is_user_id_required_msgtype(self.msg_type_flag) {
-            if self.user_id.is_none() {
-                panic!("Get, Put, Set requests require user_id field");
-            }
+        if is_user_id_required_msgtype(self.msg_type_flag) && self.user_id.is_none() {
+            panic!("Get, Put, Set requests require user_id field");
         }
-        match &self.user_id {
-            Some(uid) => {
-                bs.put_slice(&uid[..].as_bytes());
-                ()
-            },
-            _ => (),
+        if let Some(uid) = &self.user_id {
+            bs.put_slice(uid[..].as_bytes());
         }
         bs.put(&self.data[..]);
-        println!("to_bytes: {:?}", bs);
         bs.freeze()
     }
 }
 
+// Set in the message type of a frame whose data is compressed (see `protocol::compression`).
+pub const COMPRESSED_FLAG: u8 = 0x80;
+
 pub fn is_user_id_required_msgtype(msg_type_flag:u8) -> bool {
-    let user_id_req:Vec<u8> = vec!['G', 'P', 'p', 's'].into_iter().map(|x| x as u8).collect();
+    let msg_type_flag = msg_type_flag & !COMPRESSED_FLAG;
+    let user_id_req:Vec<u8> = vec!['G', 'g', 'P', 'p', 's', 'r', 'q', 'h', 'v', 'R', 't', 'U', 'X', 'W', 'O', 'c', 'l', 'S', 'B', 'a', 'x', 'f', 'j'].into_iter().map(|x| x as u8).collect();
     user_id_req.contains(&msg_type_flag)
 }
 
-pub fn check_frame(buf:&mut Cursor<&[u8]>, buf_len: usize) -> Option<usize> {
-    if buf_len < (13 as usize) {
-        None
-    } else {
-        buf.set_position(4);
-        let mut sz4:[u8; 4] = [0; 4];
-        match buf.read_exact(&mut sz4) {
-            Ok(_) => {
-                let sz = u32::from_be_bytes(sz4);
-                if buf_len >= (sz as usize) {
-                    Some(sz as usize)
-                } else {
-                    None
-                }
-            },
-            _ => None
-        }
+// Human-readable name for a message type, used to label metrics.
+pub fn msg_type_name(msg_type_flag: u8) -> &'static str {
+    match msg_type_flag {
+        b'G' => "get",
+        b'g' => "get_node",
+        b'P' => "get_prefix",
+        b'p' => "put",
+        b's' => "set",
+        b'd' => "data",
+        b'r' => "remove",
+        b'q' => "usage",
+        b'e' => "error",
+        b'Q' => "usage_report",
+        b'w' => "written",
+        b'h' => "list_revisions",
+        b'v' => "get_revision",
+        b'R' => "restore_revision",
+        b'H' => "revisions",
+        b't' => "list_trash",
+        b'U' => "restore_trash",
+        b'X' => "purge_trash",
+        b'T' => "trash",
+        b'W' => "watch",
+        b'O' => "unwatch",
+        b'C' => "change",
+        b'c' => "changes_since",
+        b'L' => "changes",
+        b'l' => "list_tree",
+        b'Y' => "tree",
+        b'S' => "search",
+        b'F' => "search_results",
+        b'a' => "add_tags",
+        b'x' => "remove_tags",
+        b'f' => "list_tagged",
+        b'I' => "tagged",
+        b'j' => "project",
+        b'J' => "projection",
+        b'B' => "batch",
+        b'b' => "batch_results",
+        b'M' => "node",
+        b'N' => "negotiate",
+        b'n' => "negotiated",
+        b'K' => "challenge",
+        b'k' => "authenticate",
+        _ => "unknown",
+    }
+}
+
+// Size of the fixed frame header: version, size, remaining frames and message type.
+pub const FRAME_HEADER_SZ: usize = 4 + 4 + 4 + 1;
+
+// No frame sent by a client or server here comes near this, compressed or not; a larger
+// declared size is refused before any of it is buffered.
+pub const MAX_FRAME_BYTES: usize = 4 * DATA_BYTES_PER_FRAME;
+
+// Returns the size of the frame at the start of `buf` once all of it is there. Fails if
+// the frame declares a size over `MAX_FRAME_BYTES`.
+pub fn check_frame(buf:&mut Cursor<&[u8]>, buf_len: usize) -> Result<Option<usize>> {
+    if buf_len < FRAME_HEADER_SZ {
+        return Ok(None);
+    }
+    buf.set_position(4);
+    let mut sz4:[u8; 4] = [0; 4];
+    buf.read_exact(&mut sz4)?;
+    let sz = u32::from_be_bytes(sz4) as usize;
+    if sz > MAX_FRAME_BYTES {
+        return Err(protocol_error(format!("frame size {} is over the limit of {} bytes", sz, MAX_FRAME_BYTES)));
     }
+    Ok(if buf_len >= sz { Some(sz) } else { None })
 }
 
-pub fn try_parse_frame(buf: &mut Cursor<&[u8]>, buf_len: usize) -> Option<Frame> {
-    if buf_len < (13 as usize) {
+// Returns `Ok(None)` until `buf_len` bytes hold the whole frame. Fails on a frame whose
+// header cannot be right, before trusting its size or user id.
+pub fn try_parse_frame(buf: &mut Cursor<&[u8]>, buf_len: usize) -> Result<Option<Frame>> {
+    if buf_len < FRAME_HEADER_SZ {
         // header not yet received
-        None
-    } else {
-        let mut sz4:[u8; 4] = [0; 4];
-        buf.read_exact(&mut sz4).ok();
-        let version_string = String::from_utf8(sz4.to_vec());
-        if !version_string.unwrap_or_default().eq("c0.1") {
-            println!("bad v string");
-            return None
-        }
-        
-        buf.read_exact(&mut sz4).ok();
-        let sz = u32::from_be_bytes(sz4);
+        return Ok(None);
+    }
+    let mut sz4:[u8; 4] = [0; 4];
+    buf.read_exact(&mut sz4)?;
+    if &sz4 != b"c0.1" {
+        return Err(protocol_error("bad version string"));
+    }
 
-        if buf_len < ((sz as usize) - 0) {
-            println!("insufficient bytes: {} vs {}", buf_len, sz);
-            return None
-        }
-        
-        buf.read_exact(&mut sz4).ok();
-        let n_remaining_frames = u32::from_be_bytes(sz4);
-
-        let bytes_to_read = (sz - 13) as usize;
-        let mut rem_buf = vec![0u8; bytes_to_read + 1];
-        buf.read_exact(&mut rem_buf).ok()?;
-        let msg_type_flag = rem_buf[0];
-
-        let user_id: Option<String> = if is_user_id_required_msgtype(msg_type_flag){
-            Some(String::from_utf8(rem_buf[1..37].to_vec()).ok()?)
-        } else {
-            None
-        };
-        let data_start_idx: usize = if is_user_id_required_msgtype(msg_type_flag) { 37 } else { 1 };
-        let mut frame_bytes = BytesMut::with_capacity(rem_buf.len());
-        frame_bytes.put_slice(&rem_buf[data_start_idx..]);
-        let f = Frame::new(user_id, n_remaining_frames, msg_type_flag, frame_bytes.freeze());
-        Some(f)
+    buf.read_exact(&mut sz4)?;
+    let sz = u32::from_be_bytes(sz4) as usize;
+    if sz < FRAME_HEADER_SZ {
+        return Err(protocol_error(format!("frame size {} is smaller than its header", sz)));
+    }
+    if buf_len < sz {
+        return Ok(None);
     }
+
+    buf.read_exact(&mut sz4)?;
+    let n_remaining_frames = u32::from_be_bytes(sz4);
+
+    let mut rem_buf = vec![0u8; sz - FRAME_HEADER_SZ + 1];
+    buf.read_exact(&mut rem_buf)?;
+    let msg_type_flag = rem_buf[0];
+
+    let user_id = if is_user_id_required_msgtype(msg_type_flag) {
+        if sz < FRAME_HEADER_SZ + UUID_LEN {
+            return Err(protocol_error(format!("frame size {} leaves no room for a user id", sz)));
+        }
+        Some(String::from(UserId::from_bytes(&rem_buf[1..1 + UUID_LEN])?))
+    } else {
+        None
+    };
+    let data_start_idx = if user_id.is_some() { 1 + UUID_LEN } else { 1 };
+    let data = Bytes::copy_from_slice(&rem_buf[data_start_idx..]);
+    Ok(Some(Frame::new(user_id, n_remaining_frames, msg_type_flag, data)))
+}
+
+fn protocol_error(description: impl Into<String>) -> Error {
+    RequestError::new(ERR_BAD_REQUEST, description).into()
 }
 
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use bytes::Buf;
+    use crate::protocol::types::ERR_INVALID_ID;
 
     #[test]
     fn test_frame_deserialization() {
         let uuid = "e17ca57f-a8db-4a0d-b9a9-6ff9edc983fd";
         let data = Bytes::from("hello".as_bytes());
-        let f = Frame::new(Some(uuid.to_string()), 1, 'G' as u8, data);
+        let f = Frame::new(Some(uuid.to_string()), 1, b'G', data);
         let bs = f.to_bytes();
         let mut bs_buffer = BytesMut::with_capacity(bs.len());
         bs_buffer.put(bs);
         let mut buf = Cursor::new(&bs_buffer[..]);
-        let sz_opt = check_frame(&mut buf, bs_buffer.len());
-        assert_eq!(sz_opt.is_some(), true);
-        let frame_sz = sz_opt.unwrap();
+        let frame_sz = check_frame(&mut buf, bs_buffer.len()).unwrap().unwrap();
         buf.set_position(0);
-        let frame_opt = try_parse_frame(&mut buf, frame_sz);
-        assert_eq!(frame_opt.is_some(), true);
-        let frame = frame_opt.unwrap();
+        let frame = try_parse_frame(&mut buf, frame_sz).unwrap().unwrap();
         assert_eq!(frame.n_remaining_frames, 1);
         assert_eq!(frame.size(), 5 + 13 + 36);
-        assert_eq!(frame.msg_type_flag, 'G' as u8);
+        assert_eq!(frame.msg_type_flag, b'G');
     }
 
     #[test]
     fn test_frame_serialization() {
         let uuid = "e17ca57f-a8db-4a0d-b9a9-6ff9edc983fd";
         let data = Bytes::from("hello".as_bytes());
-        let f = Frame::new(Some(uuid.to_string()), 1, 'G' as u8, data);
+        let f = Frame::new(Some(uuid.to_string()), 1, b'G', data);
         assert_eq!(f.size(), 18+36);
         let mut bs = f.to_bytes();
         
         let mut v_bs = bs.split_to(4);
-        let mut v_str = String::from_utf8(v_bs.to_vec()).unwrap();
-        assert_eq!(v_str.eq("c0.1"), true);
+        let v_str = String::from_utf8(v_bs.to_vec()).unwrap();
+        assert!(v_str.eq("c0.1"));
 
         v_bs = bs.split_to(4);
         println!("v_bs = {:?}", &v_bs[..]);
@@ -172,17 +225,62 @@ mod tests {
         
         v_bs = bs.split_to(1);
         
-        assert_eq!(v_bs[0], 'G' as u8);
+        assert_eq!(v_bs[0], b'G');
         
         let _ = bs.split_to(36);
 
         let dat_str = String::from_utf8(bs.to_vec()).unwrap();
-        assert_eq!(dat_str.eq("hello"), true);
+        assert!(dat_str.eq("hello"));
+    }
+
+    // A frame header declaring `sz` bytes, followed by `rest`.
+    fn raw_frame(sz: u32, msg_type_flag: u8, rest: &[u8]) -> Vec<u8> {
+        let mut bs = b"c0.1".to_vec();
+        bs.extend_from_slice(&sz.to_be_bytes());
+        bs.extend_from_slice(&1u32.to_be_bytes());
+        bs.push(msg_type_flag);
+        bs.extend_from_slice(rest);
+        bs
+    }
+
+    fn parse(bs: &[u8]) -> Result<Option<Frame>> {
+        try_parse_frame(&mut Cursor::new(bs), bs.len())
+    }
+
+    #[test]
+    fn test_truncated_headers_are_rejected() {
+        let uuid = b"e17ca57f-a8db-4a0d-b9a9-6ff9edc983fd";
+        for sz in [0, 1, 12] {
+            assert!(parse(&raw_frame(sz, b'd', &[])).is_err());
+        }
+        assert!(parse(&raw_frame(13, b'd', &[])).unwrap().unwrap().data.is_empty());
+        // A user id type needs 13 + 36 bytes.
+        assert!(parse(&raw_frame(13, b'G', &[])).is_err());
+        assert!(parse(&raw_frame(48, b'G', &uuid[..35])).is_err());
+        assert!(parse(&raw_frame(49, b'G', uuid)).unwrap().unwrap().data.is_empty());
+        // Complete, but not a canonical user id.
+        let err = parse(&raw_frame(49, b'G', b"../../../../../../../../../../etc/pw")).unwrap_err();
+        assert_eq!(err.downcast_ref::<RequestError>().unwrap().code, ERR_INVALID_ID);
+        // Still incomplete rather than wrong.
+        assert!(parse(&raw_frame(60, b'G', uuid)).unwrap().is_none());
+        assert!(parse(b"c0.1").unwrap().is_none());
+        assert!(parse(&raw_frame(13, b'd', &[])[..12]).unwrap().is_none());
+        assert!(parse(b"c0.2\0\0\0\x0d\0\0\0\x01d").is_err());
+    }
+
+    #[test]
+    fn test_oversized_frames_are_rejected_from_the_header() {
+        let check = |bs: &[u8]| check_frame(&mut Cursor::new(bs), bs.len());
+        assert!(check(&raw_frame(u32::MAX, b'd', &[])).is_err());
+        assert!(check(&raw_frame(MAX_FRAME_BYTES as u32 + 1, b'd', &[])).is_err());
+        assert!(check(&raw_frame(MAX_FRAME_BYTES as u32, b'd', &[])).unwrap().is_none());
+        assert_eq!(check(&raw_frame(13, b'd', &[])).unwrap(), Some(13));
     }
 
     #[test]
     fn test_user_id_required_helper() {
-        assert_eq!(is_user_id_required_msgtype('G' as u8), true);
-        assert_eq!(is_user_id_required_msgtype('d' as u8), false);
+        assert!(is_user_id_required_msgtype(b'G'));
+        assert!(!is_user_id_required_msgtype(b'd'));
+        assert!(is_user_id_required_msgtype(b'p' | COMPRESSED_FLAG));
     }
 }
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use tokio::sync::broadcast;
+
+use crate::protocol::types::ChangeOp;
+
+// Events buffered per user before slow watchers start lagging.
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct ChangeEvent {
+    pub id: String,
+    pub op: ChangeOp,
+    pub revision: u64,
+    // Ancestors of `id` at the time of the change, root first. Lets watchers of a
+    // subtree filter events without access to the tree.
+    pub path: Vec<String>,
+}
+
+impl ChangeEvent {
+    // Whether this event is inside the subtree rooted at `root`.
+    pub fn is_under(&self, root: &str) -> bool {
+        self.id == root || self.path.iter().any(|a| a == root)
+    }
+}
+
+// Fans out every user's mutations to the connections watching that user.
+pub struct ChangeHub {
+    channels: Mutex<HashMap<String, broadcast::Sender<ChangeEvent>>>,
+    capacity: usize,
+}
+
+impl Default for ChangeHub {
+    fn default() -> ChangeHub {
+        ChangeHub::new(DEFAULT_CHANNEL_CAPACITY)
+    }
+}
+
+impl ChangeHub {
+    pub fn new(capacity: usize) -> ChangeHub {
+        ChangeHub { channels: Mutex::new(HashMap::new()), capacity }
+    }
+
+    pub fn subscribe(&self, user_id: &str) -> broadcast::Receiver<ChangeEvent> {
+        let mut channels = self.channels.lock().unwrap();
+        channels
+            .entry(user_id.to_string())
+            .or_insert_with(|| broadcast::channel(self.capacity).0)
+            .subscribe()
+    }
+
+    pub fn publish(&self, user_id: &str, event: ChangeEvent) {
+        let mut channels = self.channels.lock().unwrap();
+        let Some(tx) = channels.get(user_id) else {
+            return;
+        };
+        // Nobody is watching any more: drop the channel instead of buffering.
+        if tx.send(event).is_err() {
+            channels.remove(user_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish_reaches_subscribers_of_that_user() {
+        let hub = ChangeHub::new(4);
+        let mut alice = hub.subscribe("alice");
+        let mut bob = hub.subscribe("bob");
+        let event = ChangeEvent { id: "n2".to_string(), op: ChangeOp::Updated, revision: 3, path: vec!["alice".to_string(), "n1".to_string()] };
+        hub.publish("alice", event);
+
+        let got = alice.recv().await.unwrap();
+        assert_eq!((got.id.as_str(), got.revision), ("n2", 3));
+        assert!(got.is_under("n1"));
+        assert!(!got.is_under("n3"));
+        assert!(bob.try_recv().is_err());
+    }
+}
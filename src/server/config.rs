@@ -0,0 +1,228 @@
+use std::env;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+
+use crate::protocol::auth;
+use crate::protocol::compression::Codec;
+use crate::server::handler::ConnectionOptions;
+use crate::server::provider::ProviderOptions;
+use crate::server::quota::QuotaLimits;
+use crate::server::tls::ServerTlsFiles;
+use crate::storage::crypto::Keyring;
+use crate::storage::history::RetentionPolicy;
+
+pub const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:9444";
+// Owner and group may connect.
+pub const DEFAULT_UNIX_SOCKET_MODE: u32 = 0o660;
+pub const DEFAULT_METRICS_ADDR: &str = "127.0.0.1:9445";
+pub const DEFAULT_DATA_DIR: &str = "data";
+pub const DEFAULT_PROVIDER_SHARDS: usize = 16;
+pub const DEFAULT_PROVIDER_CACHE_BYTES: usize = 256 * 1024 * 1024;
+pub const DEFAULT_HISTORY_PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+pub const DEFAULT_TRASH_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 3600);
+pub const DEFAULT_TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+pub const DEFAULT_TOMBSTONE_MAX_AGE: Duration = Duration::from_secs(90 * 24 * 3600);
+pub const DEFAULT_KEY_ROTATION_INTERVAL: Duration = Duration::from_secs(3600);
+
+// Server settings, read from `BEARCUB_*` environment variables with local defaults.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    // TCP listener; unset (`BEARCUB_LISTEN_ADDR=""`) leaves only the Unix socket.
+    pub listen_addr: Option<String>,
+    // Unix socket listener, for a Cubby server on the same host.
+    pub unix_socket: Option<String>,
+    // Permissions of the socket file, which decide who may connect.
+    pub unix_socket_mode: u32,
+    // Prometheus scrape endpoint; unset (`BEARCUB_METRICS_ADDR=""`) disables it.
+    pub metrics_addr: Option<String>,
+    // Root of the per-user directories (`<data_dir>/<user id>/blobs.bson`).
+    pub data_dir: String,
+    pub provider_shards: usize,
+    // Memory budget for loaded user trees; idle users are evicted LRU beyond it.
+    pub provider_cache_bytes: usize,
+    pub quota: QuotaLimits,
+    pub history: RetentionPolicy,
+    // How often the background task applies `history` to every user.
+    pub history_prune_interval: Duration,
+    // Trashed subtrees are purged for good once they are older than this.
+    pub trash_max_age: Duration,
+    pub trash_purge_interval: Duration,
+    // How long deletions stay visible to `ChangesSince`. Clients that sync less often
+    // than this have to start over.
+    pub tombstone_max_age: Option<Duration>,
+    // Codec for newly written payload files.
+    pub storage_compression: Codec,
+    // Codecs clients may negotiate for their connection.
+    pub wire_compression: Vec<Codec>,
+    // Master keys for encryption at rest; unset stores everything in plaintext.
+    pub key_file: Option<String>,
+    // How often files still encrypted with an older key are re-encrypted.
+    pub key_rotation_interval: Duration,
+    // Shared secret every client must prove it holds. Required unless
+    // `allow_unauthenticated` is set.
+    pub secret_file: Option<String>,
+    pub allow_unauthenticated: bool,
+    // Certificate and key for TLS on the listener; plain TCP when both are unset.
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
+    // Requires clients to present a certificate signed by this CA.
+    pub tls_client_ca_file: Option<String>,
+    // Rejects payloads that are not JSON.
+    pub validate_json: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            listen_addr: Some(DEFAULT_LISTEN_ADDR.to_string()),
+            unix_socket: None,
+            unix_socket_mode: DEFAULT_UNIX_SOCKET_MODE,
+            metrics_addr: Some(DEFAULT_METRICS_ADDR.to_string()),
+            data_dir: DEFAULT_DATA_DIR.to_string(),
+            provider_shards: DEFAULT_PROVIDER_SHARDS,
+            provider_cache_bytes: DEFAULT_PROVIDER_CACHE_BYTES,
+            quota: QuotaLimits::default(),
+            history: RetentionPolicy::default(),
+            history_prune_interval: DEFAULT_HISTORY_PRUNE_INTERVAL,
+            trash_max_age: DEFAULT_TRASH_MAX_AGE,
+            trash_purge_interval: DEFAULT_TRASH_PURGE_INTERVAL,
+            tombstone_max_age: Some(DEFAULT_TOMBSTONE_MAX_AGE),
+            storage_compression: Codec::None,
+            wire_compression: ConnectionOptions::default().codecs,
+            key_file: None,
+            key_rotation_interval: DEFAULT_KEY_ROTATION_INTERVAL,
+            secret_file: None,
+            allow_unauthenticated: false,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_client_ca_file: None,
+            validate_json: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_env() -> ServerConfig {
+        let mut cfg = ServerConfig::default();
+        if let Ok(addr) = env::var("BEARCUB_LISTEN_ADDR") {
+            cfg.listen_addr = if addr.is_empty() { None } else { Some(addr) };
+        }
+        cfg.unix_socket = env_path("BEARCUB_UNIX_SOCKET");
+        if let Some(mode) = env::var("BEARCUB_UNIX_SOCKET_MODE").ok().and_then(|m| u32::from_str_radix(&m, 8).ok()) {
+            cfg.unix_socket_mode = mode;
+        }
+        if let Ok(addr) = env::var("BEARCUB_METRICS_ADDR") {
+            cfg.metrics_addr = if addr.is_empty() { None } else { Some(addr) };
+        }
+        if let Ok(dir) = env::var("BEARCUB_DATA_DIR") {
+            cfg.data_dir = dir;
+        }
+        if let Some(n) = env_parse("BEARCUB_PROVIDER_SHARDS") {
+            cfg.provider_shards = n;
+        }
+        if let Some(n) = env_parse("BEARCUB_PROVIDER_CACHE_BYTES") {
+            cfg.provider_cache_bytes = n;
+        }
+        cfg.quota = QuotaLimits {
+            max_payload_bytes: env_parse("BEARCUB_QUOTA_MAX_PAYLOAD_BYTES"),
+            max_nodes: env_parse("BEARCUB_QUOTA_MAX_NODES"),
+            max_depth: env_parse("BEARCUB_QUOTA_MAX_DEPTH"),
+            max_blob_bytes: env_parse("BEARCUB_QUOTA_MAX_BLOB_BYTES"),
+        };
+        if let Ok(n) = env::var("BEARCUB_HISTORY_MAX_REVISIONS") {
+            // An empty value lifts the per-blob limit.
+            cfg.history.max_revisions = n.parse().ok();
+        }
+        if let Some(secs) = env_parse("BEARCUB_HISTORY_MAX_AGE_SECS") {
+            cfg.history.max_age = Some(Duration::from_secs(secs));
+        }
+        if let Some(secs) = env_parse("BEARCUB_HISTORY_PRUNE_INTERVAL_SECS") {
+            cfg.history_prune_interval = Duration::from_secs(secs);
+        }
+        if let Some(secs) = env_parse("BEARCUB_TRASH_MAX_AGE_SECS") {
+            cfg.trash_max_age = Duration::from_secs(secs);
+        }
+        if let Some(secs) = env_parse("BEARCUB_TRASH_PURGE_INTERVAL_SECS") {
+            cfg.trash_purge_interval = Duration::from_secs(secs);
+        }
+        if let Ok(secs) = env::var("BEARCUB_TOMBSTONE_MAX_AGE_SECS") {
+            // An empty value keeps tombstones forever.
+            cfg.tombstone_max_age = secs.parse().ok().map(Duration::from_secs);
+        }
+        if let Some(codec) = env_parse("BEARCUB_STORAGE_COMPRESSION") {
+            cfg.storage_compression = codec;
+        }
+        if let Ok(names) = env::var("BEARCUB_WIRE_COMPRESSION") {
+            // Comma-separated; empty turns wire compression off.
+            cfg.wire_compression = names.split(',').filter_map(|n| n.parse().ok()).filter(|c| *c != Codec::None).collect();
+        }
+        if let Ok(path) = env::var("BEARCUB_KEY_FILE") {
+            cfg.key_file = if path.is_empty() { None } else { Some(path) };
+        }
+        if let Some(secs) = env_parse("BEARCUB_KEY_ROTATION_INTERVAL_SECS") {
+            cfg.key_rotation_interval = Duration::from_secs(secs);
+        }
+        if let Ok(path) = env::var("BEARCUB_SECRET_FILE") {
+            cfg.secret_file = if path.is_empty() { None } else { Some(path) };
+        }
+        cfg.allow_unauthenticated = env::var("BEARCUB_INSECURE_NO_AUTH").map(|v| v == "1").unwrap_or(false);
+        cfg.tls_cert_file = env_path("BEARCUB_TLS_CERT_FILE");
+        cfg.tls_key_file = env_path("BEARCUB_TLS_KEY_FILE");
+        cfg.tls_client_ca_file = env_path("BEARCUB_TLS_CLIENT_CA_FILE");
+        cfg.validate_json = env::var("BEARCUB_VALIDATE_JSON").map(|v| v == "1").unwrap_or(false);
+        cfg
+    }
+
+    // Fails if the key file cannot be loaded.
+    pub fn provider_options(&self) -> Result<ProviderOptions> {
+        let keyring = match &self.key_file {
+            Some(path) => Some(Arc::new(Keyring::load(Path::new(path))?)),
+            None => None,
+        };
+        Ok(ProviderOptions {
+            quota: self.quota.clone(),
+            history: self.history.clone(),
+            tombstone_max_age: self.tombstone_max_age,
+            compression: self.storage_compression,
+            keyring,
+            validate_json: self.validate_json,
+        })
+    }
+
+    // Fails if the secret file cannot be loaded, or if there is none and unauthenticated
+    // connections were not explicitly allowed.
+    pub fn connection_options(&self) -> Result<ConnectionOptions> {
+        let secret = match &self.secret_file {
+            Some(path) => Some(auth::load_secret(Path::new(path))?),
+            None if self.allow_unauthenticated => None,
+            None => bail!("set BEARCUB_SECRET_FILE, or BEARCUB_INSECURE_NO_AUTH=1 to accept unauthenticated clients"),
+        };
+        Ok(ConnectionOptions { codecs: self.wire_compression.clone(), secret })
+    }
+
+    // `None` if the listener should not use TLS. Fails on half a configuration.
+    pub fn tls_files(&self) -> Result<Option<ServerTlsFiles>> {
+        match (&self.tls_cert_file, &self.tls_key_file) {
+            (Some(cert_file), Some(key_file)) => Ok(Some(ServerTlsFiles {
+                cert_file: cert_file.clone(),
+                key_file: key_file.clone(),
+                client_ca_file: self.tls_client_ca_file.clone(),
+            })),
+            (None, None) if self.tls_client_ca_file.is_none() => Ok(None),
+            (None, None) => bail!("BEARCUB_TLS_CLIENT_CA_FILE needs BEARCUB_TLS_CERT_FILE and BEARCUB_TLS_KEY_FILE"),
+            _ => bail!("set both BEARCUB_TLS_CERT_FILE and BEARCUB_TLS_KEY_FILE, or neither"),
+        }
+    }
+}
+
+// Unset and empty both mean no file.
+fn env_path(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|p| !p.is_empty())
+}
+
+fn env_parse<T: std::str::FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok().and_then(|v| v.parse().ok())
+}
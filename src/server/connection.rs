@@ -1,44 +1,103 @@
 use std::io::Cursor;
-use std::fmt;
 
-use bytes::{BytesMut, Buf};
-use tokio::{net::TcpStream, io::{AsyncReadExt, AsyncWriteExt}};
+use bytes::{Bytes, BytesMut, Buf};
+use tokio::{net::TcpStream, io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}};
 use anyhow::*;
 
+use crate::protocol::auth;
+use crate::protocol::compression::{compress_frame, decompress_frame, Codec};
+use crate::protocol::ids::{BlobId, UserId};
 use crate::protocol::{types::*, wire::{Frame, check_frame, try_parse_frame}};
+use crate::server::metrics::metrics;
 
-pub struct Connection {
-    stream: TcpStream,
+// Frames over any byte stream: a plain `TcpStream`, or a TLS stream wrapping one (see
+// `server::tls`).
+pub struct Connection<S = TcpStream> {
+    stream: S,
     buffer: BytesMut,
+    // Codec outgoing frames are compressed with, once negotiated. Incoming frames say
+    // for themselves whether they are compressed.
+    codec: Codec,
 }
 
-impl Connection {
-    pub fn new(stream: TcpStream) -> Connection {
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub fn new(stream: S) -> Connection<S> {
         Connection {
             stream,
             // Allocate the buffer with enough capacity to hold 4 frames.
             buffer: BytesMut::with_capacity(BUF_CAP * 4),
+            codec: Codec::None,
         }
     }
 
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    // Asks the server to compress this connection's frames with the first of `codecs` it
+    // supports, and does the same from here on. Returns the codec agreed on, which is
+    // `Codec::None` if the server supports none of them.
+    pub async fn negotiate(&mut self, codecs: &[Codec]) -> Result<Codec> {
+        let offer: Vec<u8> = codecs.iter().map(|c| *c as u8).collect();
+        self.write_frame(&Frame::new(None, 1, b'N', Bytes::from(offer))).await?;
+        let frame = self.read_frame().await?.ok_or_else(|| anyhow!("connection closed"))?;
+        match frame.msg_type_flag {
+            b'n' => {
+                let codec = frame.data.first().and_then(|c| Codec::from_u8(*c)).ok_or_else(|| anyhow!("bad negotiation response"))?;
+                self.codec = codec;
+                Ok(codec)
+            },
+            b'e' => Err(error_response(frame)),
+            other => bail!("unexpected response type {}", other as char),
+        }
+    }
+
+    // Answers the server's challenge with proof of holding `secret`. Must come first on a
+    // connection to a server that requires authentication.
+    pub async fn authenticate(&mut self, secret: &[u8]) -> Result<()> {
+        let challenge = self.read_frame().await?.ok_or_else(|| anyhow!("connection closed"))?;
+        if challenge.msg_type_flag != b'K' {
+            bail!("expected a challenge, got message type {}", challenge.msg_type_flag as char);
+        }
+        let response = auth::respond(secret, &challenge.data[..]);
+        self.write_frame(&Frame::new(None, 1, b'k', Bytes::from(response))).await?;
+        let frame = self.read_frame().await?.ok_or_else(|| anyhow!("connection closed"))?;
+        match frame.msg_type_flag {
+            b'd' => Ok(()),
+            b'e' => Err(error_response(frame)),
+            other => bail!("unexpected response type {}", other as char),
+        }
+    }
+
+    // Returns `Ok(None)` if the buffer does not yet hold a complete frame, and an error
+    // if the frame in it cannot be parsed or declares a size over `MAX_FRAME_BYTES`.
     pub fn parse_frame(&mut self) -> Result<Option<Frame>> {
         let buf_len = self.buffer.len();
-        println!("parse_frame has {} bytes", buf_len);
         let mut buf = Cursor::new(&self.buffer[..]);
-        let fr_sz = check_frame(&mut buf, buf_len).with_context(|| "check_frame returned false")?;
+        let fr_sz = match check_frame(&mut buf, buf_len).inspect_err(|_| metrics().parse_errors.inc())? {
+            Some(sz) => sz,
+            None => return Ok(None),
+        };
         buf.set_position(0);
-        let fr = try_parse_frame(&mut buf, buf_len).with_context(|| "parse error")?;
+        let fr = try_parse_frame(&mut buf, buf_len)
+            .inspect_err(|_| metrics().parse_errors.inc())?
+            .ok_or_else(|| anyhow!("parse error"))?;
         self.buffer.advance(fr_sz);
+        let fr = decompress_frame(fr).inspect_err(|_| metrics().parse_errors.inc())?;
+        metrics().record_frame_in(fr.msg_type_flag, fr_sz);
         Ok(Some(fr))
     }
 
     pub async fn read_frame(&mut self) -> Result<Option<Frame>> {
         loop {
-            if let Some(frame) = self.parse_frame().ok() {
-                return Ok(frame)
+            if let Some(frame) = self.parse_frame()? {
+                return Ok(Some(frame))
             }
 
-            println!("Insufficient data in buffer: {}", String::from_utf8_lossy(&self.buffer.to_vec()[..]));
             if 0 == self.stream.read_buf(&mut self.buffer).await? {
                 if self.buffer.is_empty() {
                     return Ok(None);
@@ -49,16 +108,232 @@ impl Connection {
         }
     }
 
+    // Closes the write side, which for TLS also tells the peer the stream ended cleanly.
+    pub async fn shutdown(&mut self) -> Result<()> {
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+
     pub async fn write_frame(&mut self, frame: &Frame) -> Result<usize> {
-        let bs = frame.to_bytes();
-        // self.stream.writable().await?;
-        // self.stream.try_write(&bs[..]).with_context(|| "write error")
-        let n = self.stream.write(&bs[..]).await;
-        if n.is_ok() {
-            Ok(n.unwrap())
-        } else {
-            Err(anyhow!("stream write err"))
+        let bs = match compress_frame(frame, self.codec)? {
+            Some(compressed) => compressed.to_bytes(),
+            None => frame.to_bytes(),
+        };
+        self.stream.write_all(&bs[..]).await.with_context(|| "stream write err")?;
+        // TLS streams hold on to written bytes until flushed.
+        self.stream.flush().await.with_context(|| "stream write err")?;
+        metrics().record_frame_out(frame.msg_type_flag, bs.len());
+        Ok(bs.len())
+    }
+
+    // Sends a Put (`b'p'`) or Set (`b's'`) whose `len` byte payload is read from `reader`
+    // as it goes, so only one frame is in memory at a time. If `reader` ends early the
+    // message is left incomplete and the connection should be dropped.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn write_blob_stream<R: AsyncRead + Unpin>(
+        &mut self,
+        user_id: &UserId,
+        msg_type_flag: u8,
+        id: &BlobId,
+        parent: Option<&BlobId>,
+        expected_revision: Option<u64>,
+        reader: &mut R,
+        len: u64,
+    ) -> Result<()> {
+        let n_frames = write_frame_count(len);
+        let mut remaining = len;
+        for idx in 0..n_frames {
+            let (user, flag, mut buf, room) = if idx == 0 {
+                (Some(user_id.to_string()), msg_type_flag, encode_write_header(id, parent, expected_revision), FIRST_WRITE_FRAME_DATA_BYTES)
+            } else {
+                (None, b'd', BytesMut::with_capacity(DATA_BYTES_PER_FRAME), DATA_BYTES_PER_FRAME)
+            };
+            let chunk = remaining.min(room as u64) as usize;
+            read_chunk(reader, &mut buf, chunk).await?;
+            remaining -= chunk as u64;
+            self.write_frame(&Frame::new(user, (n_frames - idx) as u32, flag, buf.freeze())).await?;
         }
+        Ok(())
+    }
+
+    // Sends the `len` bytes read from `reader` as a data response, one frame at a time.
+    pub async fn write_data_stream<R: AsyncRead + Unpin>(&mut self, reader: &mut R, len: u64) -> Result<()> {
+        self.write_typed_stream(b'd', Bytes::new(), reader, len).await
+    }
+
+    // Sends a `Node` response: `meta`, then the `meta.size` byte payload read from `reader`.
+    pub async fn write_node_stream<R: AsyncRead + Unpin>(&mut self, meta: &NodeMeta, reader: &mut R) -> Result<()> {
+        self.write_typed_stream(b'M', meta.encode().freeze(), reader, meta.size).await
+    }
+
+    // Sends `header` followed by `len` bytes read from `reader` as one response typed
+    // `msg_type_flag`, framed like `ResponseMessage::to_frames` would.
+    async fn write_typed_stream<R: AsyncRead + Unpin>(&mut self, msg_type_flag: u8, mut header: Bytes, reader: &mut R, len: u64) -> Result<()> {
+        let total = header.len() as u64 + len;
+        let n_frames = total.div_ceil(DATA_BYTES_PER_FRAME as u64).max(1) as usize;
+        let mut remaining = len;
+        for idx in 0..n_frames {
+            let mut buf = BytesMut::with_capacity(DATA_BYTES_PER_FRAME);
+            buf.extend_from_slice(&header.split_to(header.len().min(DATA_BYTES_PER_FRAME)));
+            let chunk = remaining.min((DATA_BYTES_PER_FRAME - buf.len()) as u64) as usize;
+            read_chunk(reader, &mut buf, chunk).await?;
+            remaining -= chunk as u64;
+            let flag = if idx == 0 { msg_type_flag } else { b'd' };
+            self.write_frame(&Frame::new(None, (n_frames - idx) as u32, flag, buf.freeze())).await?;
+        }
+        Ok(())
+    }
+
+    // Reads a data response into `writer` as its frames arrive and returns its length.
+    // An error response is returned as a `RequestError`. Change events that arrive
+    // before the response are skipped.
+    pub async fn read_data_stream<W: AsyncWrite + Unpin>(&mut self, writer: &mut W) -> Result<u64> {
+        let mut len = 0;
+        loop {
+            let frame = self.read_frame().await?.ok_or_else(|| anyhow!("connection closed"))?;
+            let last = frame.n_remaining_frames <= 1;
+            match frame.msg_type_flag {
+                b'd' => {
+                    writer.write_all(&frame.data[..]).await?;
+                    len += frame.data.len() as u64;
+                },
+                b'C' if len == 0 => continue,
+                b'e' => return Err(error_response(frame)),
+                other => bail!("unexpected response type {}", other as char),
+            }
+            if last {
+                break;
+            }
+        }
+        writer.flush().await?;
+        Ok(len)
+    }
+
+    // Reads a `Node` response, writing its payload into `writer` as its frames arrive, and
+    // returns the node's metadata. Errors and change events are handled like in
+    // `read_data_stream`.
+    pub async fn read_node_stream<W: AsyncWrite + Unpin>(&mut self, writer: &mut W) -> Result<NodeMeta> {
+        // Holds the start of the response until the metadata is complete.
+        let mut header = BytesMut::new();
+        let mut meta = None;
+        let mut len = 0;
+        let mut first = true;
+        loop {
+            let frame = self.read_frame().await?.ok_or_else(|| anyhow!("connection closed"))?;
+            let last = frame.n_remaining_frames <= 1;
+            match frame.msg_type_flag {
+                b'C' if first => continue,
+                b'e' if first => return Err(error_response(frame)),
+                b'M' if first => header.extend_from_slice(&frame.data[..]),
+                b'd' if !first => header.extend_from_slice(&frame.data[..]),
+                other => bail!("unexpected response type {}", other as char),
+            }
+            first = false;
+            if meta.is_none() {
+                if let Some((decoded, header_len)) = NodeMeta::decode(&header[..])? {
+                    header.advance(header_len);
+                    meta = Some(decoded);
+                }
+            }
+            if meta.is_some() {
+                writer.write_all(&header[..]).await?;
+                len += header.len() as u64;
+                header.clear();
+            }
+            if last {
+                break;
+            }
+        }
+        writer.flush().await?;
+        let meta = meta.ok_or_else(|| anyhow!("short node metadata"))?;
+        if len != meta.size {
+            bail!("node payload is {} bytes, expected {}", len, meta.size);
+        }
+        Ok(meta)
     }
 }
 
+// Appends exactly `n` bytes from `reader` to `buf`.
+async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut BytesMut, n: usize) -> Result<()> {
+    let start = buf.len();
+    buf.resize(start + n, 0);
+    reader.read_exact(&mut buf[start..]).await.with_context(|| "payload source ended early")?;
+    Ok(())
+}
+
+// The error carried by an 'e' frame.
+fn error_response(frame: Frame) -> Error {
+    match ResponseMessage::from_frames(vec![frame]) {
+        Result::Ok(ResponseMessage::Error { code, description }) => RequestError::new(code, description).into(),
+        _ => anyhow!("bad error response"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::wire::MAX_FRAME_BYTES;
+
+    // Reads the frames of one message.
+    async fn read_message<S: AsyncRead + AsyncWrite + Unpin>(conn: &mut Connection<S>) -> Vec<Frame> {
+        let mut frames = vec![];
+        loop {
+            let frame = conn.read_frame().await.unwrap().unwrap();
+            let last = frame.n_remaining_frames <= 1;
+            frames.push(frame);
+            if last {
+                return frames;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put_and_set_roundtrip_at_frame_boundaries() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let (mut client, mut server) = (Connection::new(client), Connection::new(server));
+        let user = UserId::parse("2ab3da63-e24f-47e2-9b56-f3d19fade0cf").unwrap();
+        let id = BlobId::parse("e17ca57f-a8db-4a0d-b9a9-6ff9edc983fd").unwrap();
+        let parent = BlobId::parse("8b0f5a1c-77d2-4c1e-9a5e-1f0b2c3d4e5f").unwrap();
+
+        for len in [0, 1, FIRST_WRITE_FRAME_DATA_BYTES, FIRST_WRITE_FRAME_DATA_BYTES + 1, DATA_BYTES_PER_FRAME, DATA_BYTES_PER_FRAME * 5 + 17] {
+            let data = Bytes::from((0..len).map(|i| i as u8).collect::<Vec<u8>>());
+            let put = RequestMessage::Put { user_id: user.clone(), id: id.clone(), parent: Some(parent.clone()), expected_revision: Some(4), data: data.clone() };
+            let frames = put.to_frames();
+            assert_eq!(frames.len(), write_frame_count(len as u64), "{} bytes", len);
+            for f in frames {
+                client.write_frame(&f).await.unwrap();
+            }
+            match RequestMessage::from_frames(read_message(&mut server).await).unwrap() {
+                RequestMessage::Put { user_id, id: got_id, parent: got_parent, expected_revision, data: got } => {
+                    assert_eq!((user_id, got_id, got_parent, expected_revision), (user.clone(), id.clone(), Some(parent.clone()), Some(4)));
+                    assert_eq!(got, data, "{} bytes", len);
+                },
+                other => panic!("unexpected {:?}", other),
+            }
+
+            // A set, and the same payload streamed, arrive the same way.
+            for f in (RequestMessage::Set { user_id: user.clone(), id: id.clone(), expected_revision: None, data: data.clone() }).to_frames() {
+                client.write_frame(&f).await.unwrap();
+            }
+            client.write_blob_stream(&user, b's', &id, None, None, &mut &data[..], len as u64).await.unwrap();
+            for _ in 0..2 {
+                match RequestMessage::from_frames(read_message(&mut server).await).unwrap() {
+                    RequestMessage::Set { id: got_id, expected_revision: None, data: got, .. } => {
+                        assert_eq!(got_id, id);
+                        assert_eq!(got, data, "{} bytes", len);
+                    },
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_is_refused() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let (mut client, mut server) = (Connection::new(client), Connection::new(server));
+        client.write_frame(&Frame::new(None, 1, b'd', Bytes::from(vec![0u8; MAX_FRAME_BYTES]))).await.unwrap();
+        let err = server.read_frame().await.unwrap_err();
+        assert_eq!(err.downcast_ref::<RequestError>().unwrap().code, ERR_BAD_REQUEST);
+    }
+}
@@ -0,0 +1,1311 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::*;
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tokio::task::JoinHandle;
+
+use crate::protocol::auth::{self, CHALLENGE_BYTES};
+use crate::protocol::compression::Codec;
+use crate::protocol::ids::{BlobId, UserId};
+use crate::protocol::types::{
+    BatchResult, ChangeItem, ChangeOp, NodeMeta, ProjectionResult, RequestError, RequestMessage, ResponseMessage, TrashItem, ERR_ABORTED,
+    ERR_BAD_REQUEST, ERR_NOT_FOUND, ERR_UNAUTHENTICATED,
+};
+use crate::protocol::wire::{msg_type_name, Frame};
+use crate::server::connection::Connection;
+use crate::server::metrics::metrics;
+use crate::server::registry::ProviderRegistry;
+use crate::server::upload::Upload;
+use crate::storage::blobfile::BlobReader;
+use crate::storage::crypto::random_bytes;
+
+// Most operations accepted in one batch.
+const MAX_BATCH_OPS: usize = 1000;
+
+// Largest page of changes returned by `ChangesSince`, and the default.
+const MAX_CHANGES_PAGE: usize = 1000;
+
+// Largest page of nodes returned by `ListTree`, and the default.
+const MAX_TREE_PAGE: usize = 1000;
+
+// Most results returned by `Search`, and the default.
+const MAX_SEARCH_RESULTS: usize = 100;
+
+// Most blobs, and most pointers, in one `Project`.
+const MAX_PROJECTION_BLOBS: usize = 1000;
+const MAX_PROJECTION_POINTERS: usize = 64;
+// Most payload bytes one `Project` reads and parses, over all of its blobs.
+const MAX_PROJECTION_BYTES: u64 = 8 << 20;
+
+// Most bytes of one message buffered in memory; only streamed writes may be larger.
+const MAX_MESSAGE_BYTES: usize = 16 << 20;
+
+// Change events waiting to be written to one connection. A watch whose events back up
+// past this falls behind on the user's channel and gets a `Resync`.
+const EVENT_QUEUE_LEN: usize = 64;
+
+// How long a client has to answer the authentication challenge.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Per-connection settings, shared by every connection of a server.
+#[derive(Clone)]
+pub struct ConnectionOptions {
+    // Codecs a client may ask for. The client's order of preference decides between them.
+    pub codecs: Vec<Codec>,
+    // Shared secret clients must prove they hold before sending requests; `None` accepts
+    // every connection.
+    pub secret: Option<Vec<u8>>,
+}
+
+impl std::fmt::Debug for ConnectionOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionOptions").field("codecs", &self.codecs).field("authenticated", &self.secret.is_some()).finish()
+    }
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> ConnectionOptions {
+        ConnectionOptions { codecs: vec![Codec::Zstd, Codec::Lz4], secret: None }
+    }
+}
+
+// Reads requests off `connection` until the client goes away, answering each one in turn.
+// Change events of the connection's watches are written between responses.
+pub async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin>(mut connection: Connection<S>, registry: Arc<ProviderRegistry>, options: Arc<ConnectionOptions>) {
+    // Frames of the message currently being received, and when its first frame arrived.
+    let mut pending: Vec<Frame> = vec![];
+    let mut pending_bytes = 0;
+    let mut started = Instant::now();
+    let mut op = "";
+    // Multi-frame writes go to disk as they arrive instead of into `pending`.
+    let mut upload: Option<Upload> = None;
+    // One forwarding task per watched user, all feeding `events_rx`.
+    let mut watches: HashMap<UserId, JoinHandle<()>> = HashMap::new();
+    let (events_tx, mut events_rx) = mpsc::channel::<ResponseMessage>(EVENT_QUEUE_LEN);
+
+    if let Some(secret) = &options.secret {
+        if let Err(e) = authenticate(&mut connection, secret).await {
+            metrics().auth_failures.inc();
+            println!("authentication failed, closing connection: {:?}", e);
+            let _ = write_response(&mut connection, e.into()).await;
+            let _ = connection.shutdown().await;
+            return;
+        }
+    }
+
+    loop {
+        let read = tokio::select! {
+            read = connection.read_frame() => read,
+            Some(event) = events_rx.recv() => {
+                if write_response(&mut connection, event).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+        let frame = match read {
+            Result::Ok(Some(frame)) => frame,
+            Result::Ok(None) => break,
+            Err(e) => {
+                println!("bad frame, closing connection: {:?}", e);
+                // A malformed frame is answered before closing; a failed read is not.
+                if e.downcast_ref::<RequestError>().is_some() {
+                    let _ = write_response(&mut connection, e.into()).await;
+                }
+                break;
+            }
+        };
+        let last = frame.n_remaining_frames <= 1;
+        if frame.msg_type_flag == b'N' && pending.is_empty() && upload.is_none() {
+            // Answered uncompressed; frames after the answer use the chosen codec.
+            let codec = choose_codec(&options, &frame.data[..]);
+            if connection.write_frame(&Frame::new(None, 1, b'n', Bytes::from(vec![codec as u8]))).await.is_err() {
+                break;
+            }
+            connection.set_codec(codec);
+            continue;
+        }
+        if let Some(upload) = &mut upload {
+            upload.append(frame.msg_type_flag, frame.data).await;
+        } else {
+            if pending.is_empty() {
+                started = Instant::now();
+                op = msg_type_name(frame.msg_type_flag);
+            }
+            if pending.is_empty() && !last && matches!(frame.msg_type_flag, b'p' | b's') {
+                upload = Some(Upload::start(&registry, frame).await);
+            } else {
+                pending_bytes += frame.data.len();
+                if pending_bytes > MAX_MESSAGE_BYTES {
+                    println!("message over {} bytes, closing connection", MAX_MESSAGE_BYTES);
+                    let error: Error = RequestError::new(ERR_BAD_REQUEST, format!("message larger than {} bytes", MAX_MESSAGE_BYTES)).into();
+                    let _ = write_response(&mut connection, error.into()).await;
+                    break;
+                }
+                pending.push(frame);
+            }
+        }
+        if !last {
+            continue;
+        }
+
+        pending_bytes = 0;
+        let reply = match upload.take() {
+            Some(upload) => Reply::Message(upload.finish(&registry).await),
+            None => match RequestMessage::from_frames(std::mem::take(&mut pending)) {
+                Result::Ok(RequestMessage::Get { user_id, id: Some(id), .. }) => match open_blob(&registry, &user_id, &id).await {
+                    Result::Ok(reply) => reply,
+                    Err(e) => Reply::Message(e.into()),
+                },
+                Result::Ok(RequestMessage::GetNode { user_id, id }) => match open_node(&registry, &user_id, &id).await {
+                    Result::Ok(reply) => reply,
+                    Err(e) => Reply::Message(e.into()),
+                },
+                Result::Ok(RequestMessage::Watch { user_id, root }) => match watch(&registry, &user_id, root, events_tx.clone()).await {
+                    Result::Ok(task) => {
+                        if let Some(old) = watches.insert(user_id, task) {
+                            old.abort();
+                        }
+                        Reply::Message(ResponseMessage::Data { data: Bytes::new() })
+                    },
+                    Err(e) => Reply::Message(e.into()),
+                },
+                Result::Ok(RequestMessage::Unwatch { user_id }) => {
+                    if let Some(task) = watches.remove(&user_id) {
+                        task.abort();
+                    }
+                    Reply::Message(ResponseMessage::Data { data: Bytes::new() })
+                },
+                Result::Ok(request) => Reply::Message(handle(&registry, request).await),
+                Err(e) => Reply::Message(e.into()),
+            },
+        };
+        let written = match reply {
+            Reply::Message(response) => write_response(&mut connection, response).await,
+            Reply::Blob { file: Some(mut file), len } => connection.write_data_stream(&mut file, len).await,
+            Reply::Blob { file: None, .. } => connection.write_data_stream(&mut tokio::io::empty(), 0).await,
+            Reply::Node { meta, file: Some(mut file) } => connection.write_node_stream(&meta, &mut file).await,
+            Reply::Node { meta, file: None } => connection.write_node_stream(&meta, &mut tokio::io::empty()).await,
+        };
+        if written.is_err() {
+            println!("client closed socket, breaking out...");
+            break;
+        }
+        metrics().request_latency.observe(op, started.elapsed());
+    }
+
+    for task in watches.into_values() {
+        task.abort();
+    }
+    let _ = connection.shutdown().await;
+}
+
+// Challenges the client to prove it holds `secret`, and acknowledges with an empty 'd'
+// frame if it does. Any other first frame, or none in time, fails with `ERR_UNAUTHENTICATED`.
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(connection: &mut Connection<S>, secret: &[u8]) -> Result<()> {
+    let challenge = random_bytes::<CHALLENGE_BYTES>();
+    connection.write_frame(&Frame::new(None, 1, b'K', Bytes::copy_from_slice(&challenge))).await?;
+    let read = tokio::time::timeout(AUTH_TIMEOUT, connection.read_frame()).await;
+    let frame = match read {
+        Result::Ok(read) => read?.ok_or_else(|| anyhow!("connection closed before authenticating"))?,
+        Err(_) => return Err(RequestError::new(ERR_UNAUTHENTICATED, "authentication timed out").into()),
+    };
+    if frame.msg_type_flag != b'k' {
+        return Err(RequestError::new(ERR_UNAUTHENTICATED, "authenticate before sending requests").into());
+    }
+    if !auth::verify(secret, &challenge, &frame.data[..]) {
+        return Err(RequestError::new(ERR_UNAUTHENTICATED, "wrong challenge response").into());
+    }
+    write_response(connection, ResponseMessage::Data { data: Bytes::new() }).await
+}
+
+// The first codec offered by the client that the server allows, or none.
+fn choose_codec(options: &ConnectionOptions, offer: &[u8]) -> Codec {
+    offer.iter().filter_map(|c| Codec::from_u8(*c)).find(|c| options.codecs.contains(c)).unwrap_or(Codec::None)
+}
+
+// What a request is answered with: a message, or a payload streamed from its file, on
+// its own or after the node's metadata.
+enum Reply {
+    Message(ResponseMessage),
+    Blob { file: Option<BlobReader>, len: u64 },
+    Node { meta: NodeMeta, file: Option<BlobReader> },
+}
+
+async fn write_response<S: AsyncRead + AsyncWrite + Unpin>(connection: &mut Connection<S>, response: ResponseMessage) -> Result<()> {
+    for f in response.to_frames() {
+        connection.write_frame(&f).await?;
+    }
+    Ok(())
+}
+
+// Opens the payload of `id` for streaming. The user's lock is only held while opening, and
+// the open file keeps its contents even if a write replaces it before it is sent.
+async fn open_blob(registry: &ProviderRegistry, user_id: &str, id: &str) -> Result<Reply> {
+    let handle = registry.get(user_id).await;
+    let file = handle.lock().await.open_blob(id)?;
+    match file {
+        Some(file) => {
+            let len = file.len();
+            Ok(Reply::Blob { file: Some(file), len })
+        },
+        None => Ok(Reply::Blob { file: None, len: 0 }),
+    }
+}
+
+// Like `open_blob`, with the node's metadata read under the same lock.
+async fn open_node(registry: &ProviderRegistry, user_id: &str, id: &str) -> Result<Reply> {
+    let handle = registry.get(user_id).await;
+    let provider = handle.lock().await;
+    let mut meta = provider.node_meta(id)?;
+    let file = provider.open_blob(id)?;
+    // What is streamed is what the file holds.
+    meta.size = file.as_ref().map(|f| f.len()).unwrap_or(0);
+    Ok(Reply::Node { meta, file })
+}
+
+// Starts forwarding the changes of `user_id` under `root` (everything if `None`) to
+// `events`, until the returned task is aborted or the connection stops reading events.
+async fn watch(registry: &ProviderRegistry, user_id: &str, root: Option<BlobId>, events: mpsc::Sender<ResponseMessage>) -> Result<JoinHandle<()>> {
+    // Subscribe before looking at the tree, so no change after the check is missed.
+    let mut rx = registry.changes().subscribe(user_id);
+    if let Some(root) = &root {
+        let handle = registry.get(user_id).await;
+        if !handle.lock().await.contains(root) {
+            return Err(RequestError::new(ERR_NOT_FOUND, format!("blob {} not found", root)).into());
+        }
+    }
+    Ok(tokio::spawn(async move {
+        loop {
+            let event = match rx.recv().await {
+                Result::Ok(e) if root.as_deref().map(|r| e.is_under(r)).unwrap_or(true) => {
+                    ResponseMessage::Change { id: e.id, op: e.op, revision: e.revision }
+                },
+                Result::Ok(_) => continue,
+                Err(RecvError::Lagged(_)) => ResponseMessage::Change { id: String::new(), op: ChangeOp::Resync, revision: 0 },
+                Err(RecvError::Closed) => return,
+            };
+            if events.send(event).await.is_err() {
+                return;
+            }
+        }
+    }))
+}
+
+pub async fn handle(registry: &ProviderRegistry, request: RequestMessage) -> ResponseMessage {
+    match execute(registry, request).await {
+        Result::Ok(response) => response,
+        Err(e) => e.into(),
+    }
+}
+
+async fn execute(registry: &ProviderRegistry, request: RequestMessage) -> Result<ResponseMessage> {
+    let handle = registry.get(request.user_id()).await;
+    let mut provider = handle.lock().await;
+
+    match request {
+        RequestMessage::Get { id: Some(id), .. } => Ok(ResponseMessage::Data { data: provider.read_blob(&id)? }),
+        RequestMessage::Get { .. } => Err(RequestError::new(ERR_BAD_REQUEST, "get by path is not supported").into()),
+        RequestMessage::GetNode { id, .. } => {
+            let mut meta = provider.node_meta(&id)?;
+            let data = provider.read_blob(&id)?;
+            meta.size = data.len() as u64;
+            Ok(ResponseMessage::Node { meta, data })
+        },
+        RequestMessage::Put { id, parent, expected_revision, data, .. } => {
+            let revision = provider.put(&id, parent.as_deref(), expected_revision, data)?;
+            Ok(ResponseMessage::Written { revision })
+        },
+        RequestMessage::Set { id, expected_revision, data, .. } => {
+            let revision = provider.set(&id, expected_revision, data)?;
+            Ok(ResponseMessage::Written { revision })
+        },
+        RequestMessage::Remove { id, .. } => {
+            provider.remove(&id)?;
+            Ok(ResponseMessage::Data { data: Bytes::new() })
+        },
+        RequestMessage::ListRevisions { id, .. } => Ok(ResponseMessage::Revisions { revisions: provider.list_revisions(&id)? }),
+        RequestMessage::GetRevision { id, revision, .. } => Ok(ResponseMessage::Data { data: provider.read_revision(&id, revision)? }),
+        RequestMessage::RestoreRevision { id, revision, .. } => {
+            let revision = provider.restore_revision(&id, revision)?;
+            Ok(ResponseMessage::Written { revision })
+        },
+        RequestMessage::ListTrash { .. } => {
+            let items = provider
+                .trash()
+                .iter()
+                .map(|e| TrashItem {
+                    id: e.subtree.id().to_string(),
+                    title: e.subtree.title().to_string(),
+                    parent_id: e.parent_id.clone(),
+                    position: e.position,
+                    deleted_at: e.deleted_at,
+                    nodes: e.subtree.node_count(),
+                })
+                .collect();
+            Ok(ResponseMessage::Trash { items })
+        },
+        RequestMessage::RestoreTrash { id, .. } => {
+            provider.restore_from_trash(&id)?;
+            Ok(ResponseMessage::Data { data: Bytes::new() })
+        },
+        RequestMessage::PurgeTrash { id, .. } => {
+            provider.purge_trash(id.as_deref())?;
+            Ok(ResponseMessage::Data { data: Bytes::new() })
+        },
+        RequestMessage::ChangesSince { since, limit, .. } => {
+            let limit = if limit == 0 { MAX_CHANGES_PAGE } else { (limit as usize).min(MAX_CHANGES_PAGE) };
+            let (page, has_more, next_seq) = provider.changes_since(since, limit)?;
+            let changes = page.into_iter().map(|r| ChangeItem { id: r.id, seq: r.seq, kind: r.kind }).collect();
+            Ok(ResponseMessage::Changes { changes, next_seq, has_more })
+        },
+        RequestMessage::ListTree { root, depth, offset, limit, .. } => {
+            let limit = if limit == 0 { MAX_TREE_PAGE } else { (limit as usize).min(MAX_TREE_PAGE) };
+            let depth = if depth == 0 { None } else { Some(depth) };
+            let offset = usize::try_from(offset).unwrap_or(usize::MAX);
+            let (items, has_more) = provider.list_tree(root.as_deref(), depth, offset, limit)?;
+            let next_offset = offset.saturating_add(items.len()) as u64;
+            Ok(ResponseMessage::Tree { items, next_offset, has_more })
+        },
+        RequestMessage::Search { query, limit, .. } => {
+            let limit = if limit == 0 { MAX_SEARCH_RESULTS } else { (limit as usize).min(MAX_SEARCH_RESULTS) };
+            Ok(ResponseMessage::SearchResults { results: provider.search(&query, limit)? })
+        },
+        RequestMessage::AddTags { id, tags, .. } => Ok(ResponseMessage::Written { revision: provider.update_tags(&id, &tags, &[])? }),
+        RequestMessage::RemoveTags { id, tags, .. } => Ok(ResponseMessage::Written { revision: provider.update_tags(&id, &[], &tags)? }),
+        RequestMessage::ListTagged { tag, .. } => {
+            let ids = provider.tagged(&tag)?.iter().map(|id| BlobId::parse(id)).collect::<Result<Vec<_>>>()?;
+            Ok(ResponseMessage::Tagged { ids })
+        },
+        RequestMessage::Project { ids, pointers, .. } => {
+            if ids.len() > MAX_PROJECTION_BLOBS || pointers.len() > MAX_PROJECTION_POINTERS {
+                let msg = format!("projections are limited to {} blobs and {} pointers", MAX_PROJECTION_BLOBS, MAX_PROJECTION_POINTERS);
+                return Err(RequestError::new(ERR_BAD_REQUEST, msg).into());
+            }
+            if let Some(pointer) = pointers.iter().find(|p| !p.is_empty() && !p.starts_with('/')) {
+                return Err(RequestError::new(ERR_BAD_REQUEST, format!("{:?} is not a JSON pointer", pointer)).into());
+            }
+            // One blob that cannot be read does not fail the others.
+            let mut budget = MAX_PROJECTION_BYTES;
+            let results = ids
+                .iter()
+                .map(|id| match provider.project(id, &pointers, &mut budget) {
+                    Result::Ok((revision, fields)) => ProjectionResult { id: id.to_string(), code: 0, description: String::new(), revision: Some(revision), fields },
+                    Err(e) => {
+                        let e = RequestError::from(e);
+                        ProjectionResult { id: id.to_string(), code: e.code, description: e.description, revision: None, fields: Default::default() }
+                    },
+                })
+                .collect();
+            Ok(ResponseMessage::Projection { results })
+        },
+        RequestMessage::Batch { ops, .. } => {
+            if ops.len() > MAX_BATCH_OPS {
+                return Err(RequestError::new(ERR_BAD_REQUEST, format!("batches are limited to {} operations", MAX_BATCH_OPS)).into());
+            }
+            let n = ops.len();
+            let results = match provider.apply_batch(ops) {
+                Result::Ok(revisions) => revisions
+                    .into_iter()
+                    .map(|revision| BatchResult { code: 0, description: String::new(), revision })
+                    .collect(),
+                Err((failed, e)) => {
+                    let e = RequestError::from(e);
+                    (0..n)
+                        .map(|idx| match idx.cmp(&failed) {
+                            Ordering::Equal => BatchResult { code: e.code, description: e.description.clone(), revision: None },
+                            Ordering::Less => BatchResult { code: ERR_ABORTED, description: "undone".to_string(), revision: None },
+                            Ordering::Greater => BatchResult { code: ERR_ABORTED, description: "not applied".to_string(), revision: None },
+                        })
+                        .collect()
+                },
+            };
+            Ok(ResponseMessage::Batch { results })
+        },
+        RequestMessage::Watch { .. } | RequestMessage::Unwatch { .. } => {
+            Err(RequestError::new(ERR_BAD_REQUEST, "watches are only available on a connection").into())
+        },
+        RequestMessage::Usage { .. } => {
+            let usage = provider.usage()?;
+            let limits = &provider.options().quota;
+            Ok(ResponseMessage::Usage {
+                payload_bytes: usage.payload_bytes,
+                nodes: usage.nodes,
+                depth: usage.depth,
+                max_payload_bytes: limits.max_payload_bytes,
+                max_nodes: limits.max_nodes,
+                max_depth: limits.max_depth,
+                max_blob_bytes: limits.max_blob_bytes,
+            })
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::types::{encode_write_header, BatchOp, SearchResult, SortKey, TreeItem, DATA_BYTES_PER_FRAME, ERR_CONFLICT, ERR_INVALID_ID, ERR_INVALID_JSON, ERR_QUOTA_EXCEEDED, WRITE_HEADER_SZ};
+    use bytes::BufMut;
+    use crate::server::provider::ProviderOptions;
+    use crate::server::quota::QuotaLimits;
+    use crate::storage::changelog::ChangeKind;
+    use crate::storage::crypto::Keyring;
+    use crate::storage::format::{DEFAULT_CONTENT_TYPE, MAX_CONTENT_TYPE_BYTES};
+
+    const USER: &str = "2ab3da63-e24f-47e2-9b56-f3d19fade0cf";
+    const ID1: &str = "e17ca57f-a8db-4a0d-b9a9-6ff9edc983fd";
+    const ID2: &str = "8b0f5a1c-77d2-4c1e-9a5e-1f0b2c3d4e5f";
+
+    fn test_registry(name: &str, quota: QuotaLimits) -> ProviderRegistry {
+        let dir = std::env::temp_dir().join(format!("bearcub-handler-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        ProviderRegistry::new(dir.to_string_lossy().to_string(), 4, usize::MAX, ProviderOptions { quota, ..Default::default() })
+    }
+
+    // A client connection to a server task answering from `registry`.
+    async fn connect(registry: Arc<ProviderRegistry>) -> Connection {
+        connect_with(registry, ConnectionOptions::default()).await
+    }
+
+    async fn connect_with(registry: Arc<ProviderRegistry>, options: ConnectionOptions) -> Connection {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            serve_connection(Connection::new(socket), registry, Arc::new(options)).await;
+        });
+        Connection::new(tokio::net::TcpStream::connect(addr).await.unwrap())
+    }
+
+    fn user() -> UserId {
+        UserId::parse(USER).unwrap()
+    }
+
+    fn blob(id: &str) -> BlobId {
+        BlobId::parse(id).unwrap()
+    }
+
+    fn error_code(response: ResponseMessage) -> u32 {
+        match response {
+            ResponseMessage::Error { code, .. } => code,
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    fn put(id: &str, parent: Option<&str>, data: &'static [u8]) -> RequestMessage {
+        RequestMessage::Put { user_id: user(), id: blob(id), parent: parent.map(blob), expected_revision: None, data: Bytes::from_static(data) }
+    }
+
+    #[tokio::test]
+    async fn test_watch_pushes_changes_under_root() {
+        let registry = Arc::new(test_registry("watch", QuotaLimits::default()));
+        handle(&registry, put(ID1, None, b"")).await;
+
+        let mut client = connect(registry.clone()).await;
+        let watch = RequestMessage::Watch { user_id: user(), root: Some(blob(ID1)) };
+        for f in watch.to_frames() {
+            client.write_frame(&f).await.unwrap();
+        }
+        let ack = client.read_frame().await.unwrap().unwrap();
+        assert!(matches!(ResponseMessage::from_frames(vec![ack]).unwrap(), ResponseMessage::Data { data } if data.is_empty()));
+
+        // Outside the watched subtree: not pushed.
+        handle(&registry, put("5c1d7f9e-0a2b-4c3d-8e4f-5a6b7c8d9e0f", None, b"")).await;
+        handle(&registry, put(ID2, Some(ID1), b"")).await;
+        handle(&registry, RequestMessage::Remove { user_id: user(), id: blob(ID2) }).await;
+
+        let mut events = vec![];
+        for _ in 0..2 {
+            match ResponseMessage::from_frames(vec![client.read_frame().await.unwrap().unwrap()]).unwrap() {
+                ResponseMessage::Change { id, op, revision } => events.push((id, op, revision)),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!(events, vec![(ID2.to_string(), ChangeOp::Created, 1), (ID2.to_string(), ChangeOp::Removed, 1)]);
+
+        let missing = RequestMessage::Watch { user_id: user(), root: Some(blob(ID2)) };
+        for f in missing.to_frames() {
+            client.write_frame(&f).await.unwrap();
+        }
+        let err = ResponseMessage::from_frames(vec![client.read_frame().await.unwrap().unwrap()]).unwrap();
+        assert_eq!(error_code(err), ERR_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_changes_since() {
+        let registry = test_registry("changes", QuotaLimits::default());
+        let changes = |since, limit| {
+            let registry = &registry;
+            async move {
+                match handle(registry, RequestMessage::ChangesSince { user_id: user(), since, limit }).await {
+                    ResponseMessage::Changes { changes, next_seq, has_more } => {
+                        (changes.into_iter().map(|c| (c.id, c.kind)).collect::<Vec<_>>(), next_seq, has_more)
+                    },
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+        };
+        handle(&registry, put(ID1, None, b"")).await;
+        handle(&registry, put(ID2, Some(ID1), b"")).await;
+        let (all, synced, has_more) = changes(0, 0).await;
+        assert_eq!(all, vec![(ID1.to_string(), ChangeKind::Added), (ID2.to_string(), ChangeKind::Added)]);
+        assert!(!has_more);
+
+        let set = RequestMessage::Set { user_id: user(), id: blob(ID2), expected_revision: None, data: Bytes::from_static(b"x") };
+        handle(&registry, set).await;
+        let (modified, next, _) = changes(synced, 0).await;
+        assert_eq!(modified, vec![(ID2.to_string(), ChangeKind::Modified)]);
+
+        // Removing the parent leaves a tombstone for both; one per page.
+        handle(&registry, RequestMessage::Remove { user_id: user(), id: blob(ID1) }).await;
+        let (first, next, has_more) = changes(next, 1).await;
+        assert!(has_more);
+        let (second, _, has_more) = changes(next, 1).await;
+        assert!(!has_more);
+        let mut deleted: Vec<(String, ChangeKind)> = first.into_iter().chain(second).collect();
+        deleted.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(deleted, vec![(ID2.to_string(), ChangeKind::Deleted), (ID1.to_string(), ChangeKind::Deleted)]);
+    }
+
+    #[tokio::test]
+    async fn test_batch_is_all_or_nothing() {
+        let registry = test_registry("batch", QuotaLimits::default());
+        let get = |id: &str| RequestMessage::Get { user_id: user(), id: Some(blob(id)), path: None };
+        let batch = |ops| RequestMessage::Batch { user_id: user(), ops };
+        handle(&registry, put(ID1, None, b"v1")).await;
+        let user_dir = std::path::Path::new(registry.data_dir()).join(USER);
+        let files = || {
+            let mut files: Vec<(String, std::time::SystemTime)> = std::fs::read_dir(&user_dir).unwrap()
+                .map(|e| e.unwrap())
+                .filter(|e| e.path().is_file())
+                .map(|e| (e.file_name().to_string_lossy().to_string(), e.metadata().unwrap().modified().unwrap()))
+                .collect();
+            files.sort();
+            files
+        };
+        let before = files();
+
+        // The move fails (no such parent), so the set before it is undone.
+        let failing = batch(vec![
+            BatchOp::Set { id: blob(ID1), expected_revision: None, content_type: None, data: Bytes::from_static(b"v2") },
+            BatchOp::Move { id: blob(ID1), parent: Some(blob(ID2)), position: None },
+            BatchOp::Remove { id: blob(ID1) },
+        ]);
+        match handle(&registry, failing).await {
+            ResponseMessage::Batch { results } => {
+                let codes: Vec<u32> = results.iter().map(|r| r.code).collect();
+                assert_eq!(codes, vec![ERR_ABORTED, ERR_NOT_FOUND, ERR_ABORTED]);
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(handle(&registry, get(ID1)).await, ResponseMessage::Data { data } if &data[..] == b"v1"));
+        let revisions = handle(&registry, RequestMessage::ListRevisions { user_id: user(), id: blob(ID1) }).await;
+        assert!(matches!(revisions, ResponseMessage::Revisions { revisions } if revisions.len() == 1));
+        // Nothing is written before a batch commits, so a failed one leaves every file as it was.
+        assert_eq!(files(), before);
+
+        let ok = batch(vec![
+            BatchOp::Put { id: blob(ID2), parent: None, position: None, expected_revision: None, title: None, content_type: None, data: Bytes::from_static(b"folder") },
+            BatchOp::Move { id: blob(ID1), parent: Some(blob(ID2)), position: None },
+            BatchOp::Set { id: blob(ID1), expected_revision: Some(2), content_type: None, data: Bytes::from_static(b"v2") },
+        ]);
+        match handle(&registry, ok).await {
+            ResponseMessage::Batch { results } => {
+                let revisions: Vec<Option<u64>> = results.iter().map(|r| r.revision).collect();
+                assert_eq!(revisions, vec![Some(1), Some(2), Some(3)]);
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        // A payload written twice in one batch keeps both older revisions.
+        let twice = batch(vec![
+            BatchOp::Set { id: blob(ID1), expected_revision: None, content_type: None, data: Bytes::from_static(b"v3") },
+            BatchOp::Set { id: blob(ID1), expected_revision: None, content_type: None, data: Bytes::from_static(b"v4") },
+        ]);
+        assert!(matches!(handle(&registry, twice).await, ResponseMessage::Batch { results } if results.iter().all(|r| r.code == 0)));
+        match handle(&registry, RequestMessage::ListRevisions { user_id: user(), id: blob(ID1) }).await {
+            ResponseMessage::Revisions { revisions } => assert_eq!(revisions.iter().map(|r| r.revision).collect::<Vec<_>>(), vec![2, 3, 4, 5]),
+            other => panic!("unexpected {:?}", other),
+        }
+        let revision = RequestMessage::GetRevision { user_id: user(), id: blob(ID1), revision: 4 };
+        assert!(matches!(handle(&registry, revision).await, ResponseMessage::Data { data } if &data[..] == b"v3"));
+
+        let user_handle = registry.get(USER).await;
+        let provider = user_handle.lock().await;
+        assert_eq!(provider.get_blob(ID2).unwrap().children()[0].id(), ID1);
+        assert_eq!(&provider.read_blob(ID1).unwrap()[..], b"v4");
+        assert!(files().iter().all(|(name, _)| !name.contains(".batch")));
+        drop(provider);
+
+        // Payloads staged by a batch that did not commit are dropped on the next load.
+        let leftover = user_dir.join(format!("{}.json.batch0", ID1));
+        std::fs::write(&leftover, b"v3").unwrap();
+        let reopened = ProviderRegistry::new(registry.data_dir().to_string(), 4, usize::MAX, ProviderOptions::default());
+        assert!(matches!(handle(&reopened, get(ID1)).await, ResponseMessage::Data { data } if &data[..] == b"v4"));
+        assert!(!leftover.exists());
+    }
+
+    #[tokio::test]
+    async fn test_sibling_order() {
+        const ID3: &str = "5c3e1d2a-0b4f-4e6a-8c7d-9e0f1a2b3c4d";
+        const ID4: &str = "9d8c7b6a-5f4e-4d3c-8b2a-1f0e9d8c7b6a";
+        let registry = test_registry("order", QuotaLimits::default());
+        let titled = |id: &str, title: &str| BatchOp::Put {
+            id: blob(id), parent: None, position: None, expected_revision: None, title: Some(title.to_string()), content_type: None, data: Bytes::from_static(b"{}"),
+        };
+        async fn order(registry: &ProviderRegistry) -> Vec<String> {
+            match handle(registry, RequestMessage::ListTree { user_id: user(), root: None, depth: 1, offset: 0, limit: 0 }).await {
+                ResponseMessage::Tree { items, .. } => items.into_iter().map(|i| i.id).collect(),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        let apply = |ops| RequestMessage::Batch { user_id: user(), ops };
+        let ok = |response| match response {
+            ResponseMessage::Batch { results } => assert!(results.iter().all(|r| r.code == 0), "{:?}", results),
+            other => panic!("unexpected {:?}", other),
+        };
+
+        ok(handle(&registry, apply(vec![titled(ID1, "beta"), titled(ID2, "Alpha"), titled(ID3, "delta")])).await);
+        ok(handle(&registry, apply(vec![
+            BatchOp::Rename { id: blob(ID3), title: "gamma".to_string() },
+            BatchOp::Sort { parent: None, by: SortKey::Title, descending: false },
+        ])).await);
+        assert_eq!(order(&registry).await, vec![ID2, ID1, ID3]);
+        ok(handle(&registry, apply(vec![
+            BatchOp::Rename { id: blob(ID2), title: "zeta".to_string() },
+            BatchOp::Sort { parent: None, by: SortKey::Title, descending: true },
+        ])).await);
+        assert_eq!(order(&registry).await, vec![ID2, ID3, ID1]);
+        ok(handle(&registry, apply(vec![
+            BatchOp::MoveNextTo { id: blob(ID3), sibling: blob(ID2), after: false },
+            BatchOp::MoveNextTo { id: blob(ID1), sibling: blob(ID3), after: true },
+        ])).await);
+        assert_eq!(order(&registry).await, vec![ID3, ID1, ID2]);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        ok(handle(&registry, apply(vec![BatchOp::Put { id: blob(ID4), parent: None, position: Some(1), expected_revision: None, title: None, content_type: None, data: Bytes::from_static(b"{}") }])).await);
+        assert_eq!(order(&registry).await, vec![ID3, ID4, ID1, ID2]);
+
+        // The order is kept in blobs.bson.
+        let reopened = ProviderRegistry::new(registry.data_dir().to_string(), 4, usize::MAX, ProviderOptions::default());
+        assert_eq!(order(&reopened).await, vec![ID3, ID4, ID1, ID2]);
+
+        ok(handle(&registry, apply(vec![BatchOp::Sort { parent: None, by: SortKey::Modified, descending: true }])).await);
+        assert_eq!(order(&registry).await[0], ID4);
+        let next_to_itself = handle(&registry, apply(vec![BatchOp::MoveNextTo { id: blob(ID1), sibling: blob(ID1), after: true }])).await;
+        assert!(matches!(next_to_itself, ResponseMessage::Batch { results } if results[0].code == ERR_BAD_REQUEST));
+        let bad_title = handle(&registry, apply(vec![BatchOp::Rename { id: blob(ID1), title: "two\nlines".to_string() }])).await;
+        assert!(matches!(bad_title, ResponseMessage::Batch { results } if results[0].code == ERR_BAD_REQUEST));
+    }
+
+    #[tokio::test]
+    async fn test_search_follows_writes() {
+        const ID3: &str = "5c3e1d2a-0b4f-4e6a-8c7d-9e0f1a2b3c4d";
+        const MISSING: &str = "9d8c7b6a-5f4e-4d3c-8b2a-1f0e9d8c7b6a";
+        let registry = test_registry("search", QuotaLimits::default());
+        handle(&registry, put(ID1, None, br#"{"title": "Groceries", "items": ["milk", "eggs"]}"#)).await;
+        handle(&registry, put(ID2, Some(ID1), br#"{"text": "Whisk the eggs with a little milk, then more milk."}"#)).await;
+        handle(&registry, put(ID3, None, br#"{"text": "bank pin"}"#)).await;
+        async fn search(registry: &ProviderRegistry, query: &str) -> Vec<SearchResult> {
+            match handle(registry, RequestMessage::Search { user_id: user(), query: query.to_string(), limit: 0 }).await {
+                ResponseMessage::SearchResults { results } => results,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        let ids = |results: Vec<SearchResult>| results.into_iter().map(|r| r.id).collect::<Vec<_>>();
+
+        let results = search(&registry, "milk").await;
+        assert_eq!(ids(results.clone()), vec![ID2, ID1]);
+        assert_eq!(results[0].snippet, "Whisk the eggs with a little milk, then more milk.");
+        handle(&registry, RequestMessage::Set { user_id: user(), id: blob(ID2), expected_revision: None, data: Bytes::from_static(br#"{"text": "pancakes"}"#) }).await;
+        assert_eq!(ids(search(&registry, "milk whisk").await), vec![ID1]);
+        assert_eq!(ids(search(&registry, "Pancakes").await), vec![ID2]);
+
+        // Trashed blobs are not found until restored.
+        handle(&registry, RequestMessage::Remove { user_id: user(), id: blob(ID1) }).await;
+        assert!(search(&registry, "milk pancakes").await.is_empty());
+        handle(&registry, RequestMessage::RestoreTrash { user_id: user(), id: blob(ID1) }).await;
+        assert_eq!(search(&registry, "milk pancakes").await.len(), 2);
+
+        // A failed batch leaves the index as it was.
+        let failing = RequestMessage::Batch { user_id: user(), ops: vec![
+            BatchOp::Set { id: blob(ID3), expected_revision: None, content_type: None, data: Bytes::from_static(b"zucchini") },
+            BatchOp::Remove { id: blob(MISSING) },
+        ] };
+        handle(&registry, failing).await;
+        assert!(search(&registry, "zucchini").await.is_empty());
+        assert_eq!(ids(search(&registry, "bank").await), vec![ID3]);
+
+        // Found by title alone, with the title as the snippet.
+        let titled = RequestMessage::Batch { user_id: user(), ops: vec![
+            BatchOp::Put { id: blob(MISSING), parent: None, position: None, expected_revision: None, title: Some("Quarterly taxes".to_string()), content_type: None, data: Bytes::from_static(b"{}") },
+        ] };
+        handle(&registry, titled).await;
+        let results = search(&registry, "taxes").await;
+        assert_eq!(ids(results.clone()), vec![MISSING]);
+        assert_eq!((results[0].title.as_str(), results[0].snippet.as_str()), ("Quarterly taxes", "Quarterly taxes"));
+        let rename = RequestMessage::Batch { user_id: user(), ops: vec![BatchOp::Rename { id: blob(MISSING), title: "Receipts".to_string() }] };
+        handle(&registry, rename).await;
+        assert!(search(&registry, "taxes").await.is_empty());
+        assert_eq!(ids(search(&registry, "receipts").await), vec![MISSING]);
+
+        // The index is only saved on flush; writes after that leave the file stale, as a
+        // crash would, and a stale file is rebuilt.
+        let path = registry.get(USER).await.lock().await.search_index_path();
+        assert!(!path.exists());
+        registry.flush_all().await.unwrap();
+        let saved = std::fs::read(&path).unwrap();
+        handle(&registry, RequestMessage::Set { user_id: user(), id: blob(ID3), expected_revision: None, data: Bytes::from_static(b"vault code") }).await;
+        assert_eq!(std::fs::read(&path).unwrap(), saved);
+        let reopened = ProviderRegistry::new(registry.data_dir().to_string(), 4, usize::MAX, ProviderOptions::default());
+        assert_eq!(ids(search(&reopened, "vault").await), vec![ID3]);
+        assert!(search(&reopened, "pin").await.is_empty());
+
+        // Rebuilt from scratch when the file is gone.
+        std::fs::remove_file(&path).unwrap();
+        let reopened = ProviderRegistry::new(registry.data_dir().to_string(), 4, usize::MAX, ProviderOptions::default());
+        assert_eq!(ids(search(&reopened, "vault").await), vec![ID3]);
+        assert!(path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_tags_and_listing_by_tag() {
+        const ID3: &str = "5c3e1d2a-0b4f-4e6a-8c7d-9e0f1a2b3c4d";
+        let registry = test_registry("tags", QuotaLimits::default());
+        handle(&registry, put(ID1, None, b"{}")).await;
+        handle(&registry, put(ID2, Some(ID1), b"{}")).await;
+        handle(&registry, put(ID3, None, b"{}")).await;
+        let tag = |id: &str, tags: &[&str]| RequestMessage::AddTags { user_id: user(), id: blob(id), tags: tags.iter().map(|t| t.to_string()).collect() };
+        let untag = |id: &str, tags: &[&str]| RequestMessage::RemoveTags { user_id: user(), id: blob(id), tags: tags.iter().map(|t| t.to_string()).collect() };
+        async fn tagged(registry: &ProviderRegistry, tag: &str) -> Vec<String> {
+            match handle(registry, RequestMessage::ListTagged { user_id: user(), tag: tag.to_string() }).await {
+                ResponseMessage::Tagged { ids } => ids.into_iter().map(String::from).collect(),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+
+        assert!(matches!(handle(&registry, tag(ID2, &["work", "to read"])).await, ResponseMessage::Written { revision: 2 }));
+        handle(&registry, tag(ID1, &["work"])).await;
+        // Nothing new: the revision stays.
+        assert!(matches!(handle(&registry, tag(ID1, &["work"])).await, ResponseMessage::Written { revision: 2 }));
+        assert_eq!(tagged(&registry, "work").await, vec![ID2, ID1]);
+        assert_eq!(tagged(&registry, "to read").await, vec![ID2]);
+        assert!(tagged(&registry, "Work").await.is_empty());
+
+        assert!(matches!(handle(&registry, untag(ID2, &["work", "unknown"])).await, ResponseMessage::Written { revision: 3 }));
+        assert_eq!(tagged(&registry, "work").await, vec![ID1]);
+        assert_eq!(error_code(handle(&registry, tag(ID3, &["a\nb"])).await), ERR_BAD_REQUEST);
+        assert_eq!(error_code(handle(&registry, tag(USER, &["x"])).await), ERR_BAD_REQUEST);
+        assert_eq!(error_code(handle(&registry, RequestMessage::AddTags { user_id: user(), id: blob(ID3), tags: (0..65).map(|i| i.to_string()).collect() }).await), ERR_BAD_REQUEST);
+
+        // Trashed blobs are left out until restored, and tags are kept in the tree file.
+        handle(&registry, RequestMessage::Remove { user_id: user(), id: blob(ID1) }).await;
+        assert!(tagged(&registry, "to read").await.is_empty());
+        handle(&registry, RequestMessage::RestoreTrash { user_id: user(), id: blob(ID1) }).await;
+        let reopened = ProviderRegistry::new(registry.data_dir().to_string(), 4, usize::MAX, ProviderOptions::default());
+        assert_eq!(tagged(&reopened, "to read").await, vec![ID2]);
+        let (items, _) = reopened.get(USER).await.lock().await.list_tree(None, None, 0, 10).unwrap();
+        assert_eq!(items[1].tags, vec!["to read"]);
+    }
+
+    #[tokio::test]
+    async fn test_json_validation_and_projection() {
+        const ID3: &str = "5c3e1d2a-0b4f-4e6a-8c7d-9e0f1a2b3c4d";
+        const MISSING: &str = "9d8c7b6a-5f4e-4d3c-8b2a-1f0e9d8c7b6a";
+        let dir = std::env::temp_dir().join(format!("bearcub-handler-json-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let options = ProviderOptions { validate_json: true, ..Default::default() };
+        let registry = Arc::new(ProviderRegistry::new(dir.to_string_lossy().to_string(), 4, usize::MAX, options));
+
+        assert!(matches!(handle(&registry, put(ID1, None, br#"{"title": "Groceries", "updated": 1700000000, "items": [{"name": "milk"}]}"#)).await, ResponseMessage::Written { .. }));
+        assert!(matches!(handle(&registry, put(ID2, None, b"")).await, ResponseMessage::Written { .. }));
+        assert_eq!(error_code(handle(&registry, put(ID3, None, b"{\"title\": ")).await), ERR_INVALID_JSON);
+        let set = RequestMessage::Set { user_id: user(), id: blob(ID1), expected_revision: None, data: Bytes::from_static(b"{} trailing") };
+        assert_eq!(error_code(handle(&registry, set).await), ERR_INVALID_JSON);
+        // Streamed payloads are checked too.
+        let mut client = connect(registry.clone()).await;
+        let payload = vec![b'['; 10_000];
+        client.write_blob_stream(&user(), b'p', &blob(ID3), None, None, &mut &payload[..], payload.len() as u64).await.unwrap();
+        assert_eq!(error_code(ResponseMessage::from_frames(vec![client.read_frame().await.unwrap().unwrap()]).unwrap()), ERR_INVALID_JSON);
+        assert_eq!(error_code(handle(&registry, RequestMessage::Get { user_id: user(), id: Some(blob(ID3)), path: None }).await), ERR_NOT_FOUND);
+
+        let pointers = ["/title", "/updated", "/items/0/name", "/missing"].map(String::from).to_vec();
+        let project = RequestMessage::Project { user_id: user(), ids: vec![blob(ID1), blob(ID2), blob(MISSING)], pointers };
+        let results = match handle(&registry, project).await {
+            ResponseMessage::Projection { results } => results,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(results[0].revision, Some(1));
+        let fields: Vec<(&str, &str)> = results[0].fields.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(fields, vec![("/items/0/name", "\"milk\""), ("/title", "\"Groceries\""), ("/updated", "1700000000")]);
+        assert_eq!((results[1].code, results[1].fields.len()), (0, 0));
+        assert_eq!((results[2].code, results[2].revision), (ERR_NOT_FOUND, None));
+
+        let bad = RequestMessage::Project { user_id: user(), ids: vec![blob(ID1)], pointers: vec!["title".to_string()] };
+        assert_eq!(error_code(handle(&registry, bad).await), ERR_BAD_REQUEST);
+
+        // Payloads written without validation are reported per blob.
+        let unchecked = ProviderRegistry::new(registry.data_dir().to_string(), 4, usize::MAX, ProviderOptions::default());
+        handle(&unchecked, put(ID3, None, b"plain text")).await;
+        match handle(&unchecked, RequestMessage::Project { user_id: user(), ids: vec![blob(ID3)], pointers: vec![String::new()] }).await {
+            ResponseMessage::Projection { results } => assert_eq!(results[0].code, ERR_INVALID_JSON),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Payloads past the byte budget of the request are not read.
+        let half = MAX_PROJECTION_BYTES as usize / 2 + 1;
+        let mut big = vec![b' '; half];
+        big[..2].copy_from_slice(b"{}");
+        for id in [ID1, ID2] {
+            let set = RequestMessage::Set { user_id: user(), id: blob(id), expected_revision: None, data: Bytes::from(big.clone()) };
+            assert!(matches!(handle(&unchecked, set).await, ResponseMessage::Written { .. }));
+        }
+        match handle(&unchecked, RequestMessage::Project { user_id: user(), ids: vec![blob(ID1), blob(ID2)], pointers: vec![String::new()] }).await {
+            ResponseMessage::Projection { results } => {
+                assert_eq!((results[0].code, results[0].fields.len()), (0, 1));
+                assert_eq!((results[1].code, results[1].revision), (ERR_BAD_REQUEST, None));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streamed_put_and_get() {
+        let quota = QuotaLimits { max_blob_bytes: Some(200_000), ..Default::default() };
+        let registry = Arc::new(test_registry("stream", quota));
+        let mut client = connect(registry.clone()).await;
+        let payload: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+
+        client.write_blob_stream(&user(), b'p', &blob(ID1), None, None, &mut &payload[..], payload.len() as u64).await.unwrap();
+        let ack = ResponseMessage::from_frames(vec![client.read_frame().await.unwrap().unwrap()]).unwrap();
+        assert!(matches!(ack, ResponseMessage::Written { revision: 1 }));
+        client.write_blob_stream(&user(), b'p', &blob(ID2), None, None, &mut &b""[..], 0).await.unwrap();
+        client.read_frame().await.unwrap().unwrap();
+
+        let mut got = vec![];
+        for f in (RequestMessage::Get { user_id: user(), id: Some(blob(ID1)), path: None }).to_frames() {
+            client.write_frame(&f).await.unwrap();
+        }
+        assert_eq!(client.read_data_stream(&mut got).await.unwrap(), payload.len() as u64);
+        assert_eq!(got, payload);
+        for f in (RequestMessage::Get { user_id: user(), id: Some(blob(ID2)), path: None }).to_frames() {
+            client.write_frame(&f).await.unwrap();
+        }
+        assert_eq!(client.read_data_stream(&mut tokio::io::sink()).await.unwrap(), 0);
+
+        // Over the blob limit: rejected once the whole message is in, and nothing is left behind.
+        let big = vec![0_u8; 300_000];
+        client.write_blob_stream(&user(), b's', &blob(ID1), None, None, &mut &big[..], big.len() as u64).await.unwrap();
+        let err = ResponseMessage::from_frames(vec![client.read_frame().await.unwrap().unwrap()]).unwrap();
+        assert_eq!(error_code(err), ERR_QUOTA_EXCEEDED);
+        let user_dir = std::path::Path::new(registry.data_dir()).join(USER);
+        let staged = std::fs::read_dir(user_dir).unwrap().filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().ends_with(".tmp"));
+        assert_eq!(staged.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_content_type_set_by_writes() {
+        let registry = test_registry("content-type", QuotaLimits::default());
+        let batch = |ops| RequestMessage::Batch { user_id: user(), ops };
+        let codes = |response| match response {
+            ResponseMessage::Batch { results } => results.iter().map(|r| r.code).collect::<Vec<u32>>(),
+            other => panic!("unexpected {:?}", other),
+        };
+        async fn content_types(registry: &ProviderRegistry) -> Vec<String> {
+            match handle(registry, RequestMessage::ListTree { user_id: user(), root: None, depth: 0, offset: 0, limit: 0 }).await {
+                ResponseMessage::Tree { items, .. } => items.into_iter().map(|i| i.content_type).collect(),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+
+        handle(&registry, put(ID1, None, b"{}")).await;
+        let text = Some("text/plain; charset=utf-8".to_string());
+        assert_eq!(codes(handle(&registry, batch(vec![
+            BatchOp::Put { id: blob(ID2), parent: None, position: None, expected_revision: None, title: None, content_type: text.clone(), data: Bytes::from_static(b"hello") },
+        ])).await), vec![0]);
+        assert_eq!(content_types(&registry).await, vec![DEFAULT_CONTENT_TYPE, "text/plain; charset=utf-8"]);
+
+        // A set without one keeps it; with one, replaces it.
+        handle(&registry, RequestMessage::Set { user_id: user(), id: blob(ID2), expected_revision: None, data: Bytes::from_static(b"bye") }).await;
+        assert_eq!(content_types(&registry).await[1], "text/plain; charset=utf-8");
+        assert_eq!(codes(handle(&registry, batch(vec![
+            BatchOp::Set { id: blob(ID1), expected_revision: None, content_type: Some("application/geo+json".to_string()), data: Bytes::from_static(b"{}") },
+        ])).await), vec![0]);
+        match handle(&registry, RequestMessage::GetNode { user_id: user(), id: blob(ID1) }).await {
+            ResponseMessage::Node { meta, .. } => assert_eq!(meta.content_type, "application/geo+json"),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        for bad in ["json", "text/\n", &format!("text/{}", "x".repeat(MAX_CONTENT_TYPE_BYTES))] {
+            let set = BatchOp::Set { id: blob(ID2), expected_revision: None, content_type: Some(bad.to_string()), data: Bytes::from_static(b"x") };
+            assert_eq!(codes(handle(&registry, batch(vec![set])).await), vec![ERR_BAD_REQUEST]);
+        }
+        assert_eq!(content_types(&registry).await, vec!["application/geo+json", "text/plain; charset=utf-8"]);
+
+        // Kept in blobs.bson.
+        let reopened = ProviderRegistry::new(registry.data_dir().to_string(), 4, usize::MAX, ProviderOptions::default());
+        assert_eq!(content_types(&reopened).await, vec!["application/geo+json", "text/plain; charset=utf-8"]);
+    }
+
+    #[tokio::test]
+    async fn test_get_node_with_metadata() {
+        const ID3: &str = "5c3e1d2a-0b4f-4e6a-8c7d-9e0f1a2b3c4d";
+        let registry = Arc::new(test_registry("node", QuotaLimits::default()));
+        let mut client = connect(registry.clone()).await;
+        let payload = (0..DATA_BYTES_PER_FRAME * 2 + 5).map(|i| i as u8).collect::<Vec<u8>>();
+        client.write_blob_stream(&user(), b'p', &blob(ID1), None, None, &mut &payload[..], payload.len() as u64).await.unwrap();
+        client.read_frame().await.unwrap().unwrap();
+        for id in [ID2, ID3] {
+            for f in put(id, Some(ID1), b"").to_frames() {
+                client.write_frame(&f).await.unwrap();
+            }
+            client.read_frame().await.unwrap().unwrap();
+        }
+        let rename = RequestMessage::Batch { user_id: user(), ops: vec![BatchOp::Rename { id: blob(ID1), title: "Grocery list".to_string() }] };
+        assert!(matches!(handle(&registry, rename).await, ResponseMessage::Batch { results } if results[0].code == 0));
+
+        for f in (RequestMessage::GetNode { user_id: user(), id: blob(ID1) }).to_frames() {
+            client.write_frame(&f).await.unwrap();
+        }
+        let mut got = vec![];
+        let meta = client.read_node_stream(&mut got).await.unwrap();
+        let title = "Grocery list".to_string();
+        assert_eq!(meta, NodeMeta { revision: 2, size: payload.len() as u64, parent: None, title, content_type: DEFAULT_CONTENT_TYPE.to_string(), children: vec![blob(ID2), blob(ID3)] });
+        assert_eq!(got, payload);
+        match handle(&registry, RequestMessage::ListTree { user_id: user(), root: None, depth: 1, offset: 0, limit: 0 }).await {
+            ResponseMessage::Tree { items, .. } => assert_eq!(items[0].title, "Grocery list"),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // An empty payload, and the same answer without streaming.
+        for f in (RequestMessage::GetNode { user_id: user(), id: blob(ID3) }).to_frames() {
+            client.write_frame(&f).await.unwrap();
+        }
+        let meta = client.read_node_stream(&mut tokio::io::sink()).await.unwrap();
+        assert_eq!((meta.parent, meta.size, meta.children.len()), (Some(blob(ID1)), 0, 0));
+        match handle(&registry, RequestMessage::GetNode { user_id: user(), id: blob(ID1) }).await {
+            ResponseMessage::Node { meta, data } => {
+                assert_eq!(meta.children, vec![blob(ID2), blob(ID3)]);
+                assert_eq!(&data[..], &payload[..]);
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+
+        handle(&registry, RequestMessage::Remove { user_id: user(), id: blob(ID2) }).await;
+        for f in (RequestMessage::GetNode { user_id: user(), id: blob(ID2) }).to_frames() {
+            client.write_frame(&f).await.unwrap();
+        }
+        let err = client.read_node_stream(&mut tokio::io::sink()).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<RequestError>().unwrap().code, ERR_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_list_tree_pages_and_depth() {
+        const ID3: &str = "5c3e1d2a-0b4f-4e6a-8c7d-9e0f1a2b3c4d";
+        const ID4: &str = "9d8c7b6a-5f4e-4d3c-8b2a-1f0e9d8c7b6a";
+        let registry = Arc::new(test_registry("tree", QuotaLimits::default()));
+        for (id, parent) in [(ID1, None), (ID2, Some(ID1)), (ID3, Some(ID2)), (ID4, None)] {
+            handle(&registry, put(id, parent, b"{}")).await;
+        }
+        let list = |root: Option<&str>, depth, offset, limit| RequestMessage::ListTree { user_id: user(), root: root.map(blob), depth, offset, limit };
+        let page = |response| match response {
+            ResponseMessage::Tree { items, next_offset, has_more } => (items.into_iter().map(|i: TreeItem| i.id).collect::<Vec<_>>(), next_offset, has_more),
+            other => panic!("unexpected {:?}", other),
+        };
+
+        // Over the wire, then the rest of the pages.
+        let mut client = connect(registry.clone()).await;
+        for f in list(None, 0, 0, 2).to_frames() {
+            client.write_frame(&f).await.unwrap();
+        }
+        let first = ResponseMessage::from_frames(vec![client.read_frame().await.unwrap().unwrap()]).unwrap();
+        assert_eq!(page(first), (vec![ID1.to_string(), ID2.to_string()], 2, true));
+        assert_eq!(page(handle(&registry, list(None, 0, 2, 2)).await), (vec![ID3.to_string(), ID4.to_string()], 4, false));
+
+        match handle(&registry, list(None, 1, 0, 0)).await {
+            ResponseMessage::Tree { items, has_more: false, .. } => {
+                assert_eq!(items.iter().map(|i| (i.id.as_str(), i.position, i.children)).collect::<Vec<_>>(), vec![(ID1, 0, 1), (ID4, 1, 0)]);
+                assert!(items.iter().all(|i| i.parent_id.is_none() && i.depth == 1 && i.revision == 1 && i.size == 2));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        match handle(&registry, list(Some(ID1), 2, 0, 0)).await {
+            ResponseMessage::Tree { items, .. } => {
+                assert_eq!(items.iter().map(|i| (i.id.as_str(), i.parent_id.as_deref(), i.depth)).collect::<Vec<_>>(), vec![(ID2, Some(ID1), 1), (ID3, Some(ID2), 2)]);
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(page(handle(&registry, list(Some(ID3), 0, 0, 0)).await), (vec![], 0, false));
+        handle(&registry, RequestMessage::Remove { user_id: user(), id: blob(ID2) }).await;
+        assert_eq!(error_code(handle(&registry, list(Some(ID2), 0, 0, 0)).await), ERR_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_buffered_message_is_refused() {
+        let registry = Arc::new(test_registry("oversized", QuotaLimits::default()));
+        let mut client = connect(registry).await;
+        // Announces far more frames than it sends; the server gives up past the byte limit.
+        let frames = (MAX_MESSAGE_BYTES / DATA_BYTES_PER_FRAME + 2) as u32;
+        let chunk = Bytes::from(vec![0_u8; DATA_BYTES_PER_FRAME]);
+        client.write_frame(&Frame::new(Some(USER.to_string()), u32::MAX, b'B', chunk.clone())).await.unwrap();
+        for n in 1..frames {
+            if client.write_frame(&Frame::new(None, u32::MAX - n, b'd', chunk.clone())).await.is_err() {
+                break;
+            }
+        }
+        let err = ResponseMessage::from_frames(vec![client.read_frame().await.unwrap().unwrap()]).unwrap();
+        assert_eq!(error_code(err), ERR_BAD_REQUEST);
+        assert!(client.read_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_streamed_write_with_bad_user_id_touches_nothing() {
+        let registry = Arc::new(test_registry("bad-id", QuotaLimits::default()));
+        let mut client = connect(registry.clone()).await;
+        let mut first = encode_write_header(&blob(ID1), None, None);
+        first.put_bytes(1, DATA_BYTES_PER_FRAME - WRITE_HEADER_SZ);
+        client.write_frame(&Frame::new(Some("../../../../../../../../../../etc/pw".to_string()), 2, b'p', first.freeze())).await.unwrap();
+        client.write_frame(&Frame::new(None, 1, b'd', Bytes::from_static(b"more"))).await.unwrap();
+        let err = ResponseMessage::from_frames(vec![client.read_frame().await.unwrap().unwrap()]).unwrap();
+        assert_eq!(error_code(err), ERR_INVALID_ID);
+        assert!(!std::path::Path::new(registry.data_dir()).exists());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_connection_and_encrypted_storage() {
+        let dir = std::env::temp_dir().join(format!("bearcub-handler-compress-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let keyring = Keyring::new([(1, [7; 32])]).unwrap();
+        let options = ProviderOptions { compression: Codec::Zstd, keyring: Some(Arc::new(keyring)), ..Default::default() };
+        let registry = Arc::new(ProviderRegistry::new(dir.to_string_lossy().to_string(), 4, usize::MAX, options));
+        let mut client = connect(registry.clone()).await;
+        assert_eq!(client.negotiate(&[Codec::Lz4, Codec::Zstd]).await.unwrap(), Codec::Lz4);
+        let payload = "{\"title\":\"groceries\",\"items\":[\"milk\",\"eggs\"]},".repeat(2000).into_bytes();
+
+        client.write_blob_stream(&user(), b'p', &blob(ID1), None, None, &mut &payload[..], payload.len() as u64).await.unwrap();
+        let ack = ResponseMessage::from_frames(vec![client.read_frame().await.unwrap().unwrap()]).unwrap();
+        assert!(matches!(ack, ResponseMessage::Written { revision: 1 }));
+        for f in (RequestMessage::Get { user_id: user(), id: Some(blob(ID1)), path: None }).to_frames() {
+            client.write_frame(&f).await.unwrap();
+        }
+        let mut got = vec![];
+        assert_eq!(client.read_data_stream(&mut got).await.unwrap(), payload.len() as u64);
+        assert_eq!(got, payload);
+
+        // Stored compressed and encrypted, but sized and quota-counted by its payload.
+        let path = dir.join(USER).join(format!("{}.json", ID1));
+        assert!(std::fs::metadata(&path).unwrap().len() < payload.len() as u64 / 4);
+        let usage = registry.get(USER).await.lock().await.usage().unwrap();
+        assert_eq!(usage.payload_bytes, payload.len() as u64);
+
+        assert_eq!(choose_codec(&ConnectionOptions { codecs: vec![Codec::Zstd], ..Default::default() }, &[Codec::Lz4 as u8, 9]), Codec::None);
+    }
+
+    #[tokio::test]
+    async fn test_connections_must_authenticate() {
+        let registry = Arc::new(test_registry("auth", QuotaLimits::default()));
+        let options = ConnectionOptions { secret: Some(b"0123456789abcdef".to_vec()), ..Default::default() };
+
+        let mut client = connect_with(registry.clone(), options.clone()).await;
+        client.authenticate(b"0123456789abcdef").await.unwrap();
+        client.write_blob_stream(&user(), b'p', &blob(ID1), None, None, &mut &b"{}"[..], 2).await.unwrap();
+        let ack = ResponseMessage::from_frames(vec![client.read_frame().await.unwrap().unwrap()]).unwrap();
+        assert!(matches!(ack, ResponseMessage::Written { revision: 1 }));
+
+        let mut client = connect_with(registry.clone(), options.clone()).await;
+        let err = client.authenticate(b"0123456789abcdeF").await.unwrap_err();
+        assert_eq!(err.downcast_ref::<RequestError>().unwrap().code, ERR_UNAUTHENTICATED);
+        assert!(client.read_frame().await.unwrap().is_none());
+
+        // A request instead of the challenge response is refused and never handled.
+        let before = metrics().auth_failures.get();
+        let mut client = connect_with(registry.clone(), options).await;
+        assert_eq!(client.read_frame().await.unwrap().unwrap().msg_type_flag, b'K');
+        for f in (RequestMessage::Remove { user_id: user(), id: blob(ID1) }).to_frames() {
+            client.write_frame(&f).await.unwrap();
+        }
+        let err = ResponseMessage::from_frames(vec![client.read_frame().await.unwrap().unwrap()]).unwrap();
+        assert_eq!(error_code(err), ERR_UNAUTHENTICATED);
+        assert!(client.read_frame().await.unwrap().is_none());
+        assert!(metrics().auth_failures.get() > before);
+        assert!(registry.get(USER).await.lock().await.contains(ID1));
+    }
+
+    #[tokio::test]
+    async fn test_put_get_remove() {
+        let registry = test_registry("crud", QuotaLimits::default());
+        handle(&registry, put(ID1, None, b"{\"a\":1}")).await;
+        let got = handle(&registry, RequestMessage::Get { user_id: user(), id: Some(blob(ID1)), path: None }).await;
+        match got {
+            ResponseMessage::Data { data } => assert_eq!(&data[..], b"{\"a\":1}"),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        handle(&registry, RequestMessage::Remove { user_id: user(), id: blob(ID1) }).await;
+        let got = handle(&registry, RequestMessage::Get { user_id: user(), id: Some(blob(ID1)), path: None }).await;
+        assert_eq!(error_code(got), ERR_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_quota_enforced_and_reported() {
+        let quota = QuotaLimits { max_payload_bytes: Some(10), max_nodes: Some(3), max_depth: Some(1), max_blob_bytes: Some(8) };
+        let registry = test_registry("quota", quota);
+
+        assert!(matches!(handle(&registry, put(ID1, None, b"12345")).await, ResponseMessage::Written { revision: 1 }));
+        // Depth 2 is over the limit of 1.
+        assert_eq!(error_code(handle(&registry, put(ID2, Some(ID1), b"")).await), ERR_QUOTA_EXCEEDED);
+        // A single blob over 8 bytes.
+        assert_eq!(error_code(handle(&registry, put(ID2, None, b"123456789")).await), ERR_QUOTA_EXCEEDED);
+        // 5 + 6 bytes is over the 10 byte total.
+        assert_eq!(error_code(handle(&registry, put(ID2, None, b"123456")).await), ERR_QUOTA_EXCEEDED);
+
+        let set = RequestMessage::Set { user_id: user(), id: blob(ID1), expected_revision: None, data: Bytes::from_static(b"12345678") };
+        assert!(matches!(handle(&registry, set).await, ResponseMessage::Written { revision: 2 }));
+
+        match handle(&registry, RequestMessage::Usage { user_id: user() }).await {
+            ResponseMessage::Usage { payload_bytes, nodes, depth, max_nodes, .. } => {
+                assert_eq!((payload_bytes, nodes, depth, max_nodes), (8, 2, 1, Some(3)));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_payload_total_tolerates_files_changed_on_disk() {
+        let registry = test_registry("drift", QuotaLimits::default());
+        handle(&registry, put(ID1, None, b"1")).await;
+        // The payload grows behind the server's back, so it is larger than what was counted.
+        let path = registry.get(USER).await.lock().await.blob_path(ID1);
+        std::fs::write(&path, [b'x'; 100]).unwrap();
+        let set = RequestMessage::Set { user_id: user(), id: blob(ID1), expected_revision: None, data: Bytes::from_static(b"12") };
+        assert!(matches!(handle(&registry, set).await, ResponseMessage::Written { .. }));
+
+        std::fs::write(&path, [b'x'; 100]).unwrap();
+        handle(&registry, RequestMessage::Remove { user_id: user(), id: blob(ID1) }).await;
+        handle(&registry, RequestMessage::PurgeTrash { user_id: user(), id: None }).await;
+        match handle(&registry, RequestMessage::Usage { user_id: user() }).await {
+            ResponseMessage::Usage { payload_bytes, .. } => assert_eq!(payload_bytes, 0),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restore_from_trash_is_checked_against_quota() {
+        const ID3: &str = "5c3e1d2a-0b4f-4e6a-8c7d-9e0f1a2b3c4d";
+        let registry = test_registry("restore-nodes", QuotaLimits { max_nodes: Some(3), ..QuotaLimits::default() });
+        let trashed = |items| match items {
+            ResponseMessage::Trash { items } => items.len(),
+            other => panic!("unexpected {:?}", other),
+        };
+
+        // Two nodes go to the trash, and the space they left is filled again.
+        handle(&registry, put(ID1, None, b"")).await;
+        handle(&registry, put(ID2, Some(ID1), b"")).await;
+        handle(&registry, RequestMessage::Remove { user_id: user(), id: blob(ID1) }).await;
+        handle(&registry, put(ID3, None, b"")).await;
+        let restore = handle(&registry, RequestMessage::RestoreTrash { user_id: user(), id: blob(ID1) }).await;
+        assert_eq!(error_code(restore), ERR_QUOTA_EXCEEDED);
+        assert_eq!(trashed(handle(&registry, RequestMessage::ListTrash { user_id: user() }).await), 1);
+
+        // The original parent moved a level down while its child was in the trash.
+        let registry = test_registry("restore-depth", QuotaLimits { max_depth: Some(2), ..QuotaLimits::default() });
+        handle(&registry, put(ID3, None, b"")).await;
+        handle(&registry, put(ID1, None, b"")).await;
+        handle(&registry, put(ID2, Some(ID1), b"")).await;
+        handle(&registry, RequestMessage::Remove { user_id: user(), id: blob(ID2) }).await;
+        registry.get(USER).await.lock().await.move_node(ID1, Some(ID3), None).unwrap();
+        let restore = handle(&registry, RequestMessage::RestoreTrash { user_id: user(), id: blob(ID2) }).await;
+        assert_eq!(error_code(restore), ERR_QUOTA_EXCEEDED);
+        assert_eq!(trashed(handle(&registry, RequestMessage::ListTrash { user_id: user() }).await), 1);
+    }
+
+    #[tokio::test]
+    async fn test_conditional_writes() {
+        let registry = test_registry("conditional", QuotaLimits::default());
+        let set = |expected_revision, data: &'static [u8]| RequestMessage::Set {
+            user_id: user(),
+            id: blob(ID1),
+            expected_revision,
+            data: Bytes::from_static(data),
+        };
+        handle(&registry, put(ID1, None, b"v1")).await;
+
+        assert!(matches!(handle(&registry, set(Some(1), b"v2")).await, ResponseMessage::Written { revision: 2 }));
+        // A second writer still holding revision 1 loses.
+        assert_eq!(error_code(handle(&registry, set(Some(1), b"v2'")).await), ERR_CONFLICT);
+
+        // Put with an expected revision overwrites in place.
+        let mut overwrite = put(ID1, None, b"v3");
+        if let RequestMessage::Put { expected_revision, .. } = &mut overwrite {
+            *expected_revision = Some(2);
+        }
+        assert!(matches!(handle(&registry, overwrite).await, ResponseMessage::Written { revision: 3 }));
+
+        let got = handle(&registry, RequestMessage::Get { user_id: user(), id: Some(blob(ID1)), path: None }).await;
+        assert!(matches!(got, ResponseMessage::Data { data } if &data[..] == b"v3"));
+    }
+
+    #[tokio::test]
+    async fn test_revision_history_and_restore() {
+        let registry = test_registry("history", QuotaLimits::default());
+        handle(&registry, put(ID1, None, b"first")).await;
+        for data in [&b"second"[..], b"third"] {
+            let set = RequestMessage::Set { user_id: user(), id: blob(ID1), expected_revision: None, data: Bytes::from_static(data) };
+            handle(&registry, set).await;
+        }
+
+        match handle(&registry, RequestMessage::ListRevisions { user_id: user(), id: blob(ID1) }).await {
+            ResponseMessage::Revisions { revisions } => {
+                let revs: Vec<u64> = revisions.iter().map(|r| r.revision).collect();
+                assert_eq!(revs, vec![1, 2, 3]);
+                assert_eq!(revisions[0].size, 5);
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        let old = handle(&registry, RequestMessage::GetRevision { user_id: user(), id: blob(ID1), revision: 1 }).await;
+        assert!(matches!(old, ResponseMessage::Data { data } if &data[..] == b"first"));
+
+        let restored = handle(&registry, RequestMessage::RestoreRevision { user_id: user(), id: blob(ID1), revision: 1 }).await;
+        assert!(matches!(restored, ResponseMessage::Written { revision: 4 }));
+        let got = handle(&registry, RequestMessage::Get { user_id: user(), id: Some(blob(ID1)), path: None }).await;
+        assert!(matches!(got, ResponseMessage::Data { data } if &data[..] == b"first"));
+
+        let missing = handle(&registry, RequestMessage::GetRevision { user_id: user(), id: blob(ID1), revision: 99 }).await;
+        assert_eq!(error_code(missing), ERR_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_trash_restore_and_purge() {
+        let registry = test_registry("trash", QuotaLimits::default());
+        let get = |id: &str| RequestMessage::Get { user_id: user(), id: Some(blob(id)), path: None };
+        handle(&registry, put(ID1, None, b"parent")).await;
+        handle(&registry, put(ID2, Some(ID1), b"child")).await;
+
+        handle(&registry, RequestMessage::Remove { user_id: user(), id: blob(ID1) }).await;
+        assert_eq!(error_code(handle(&registry, get(ID2)).await), ERR_NOT_FOUND);
+        match handle(&registry, RequestMessage::ListTrash { user_id: user() }).await {
+            ResponseMessage::Trash { items } => {
+                assert_eq!(items.len(), 1);
+                assert_eq!((items[0].id.as_str(), items[0].parent_id.as_str(), items[0].nodes), (ID1, USER, 2));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        // The id is still taken while trashed.
+        assert!(matches!(handle(&registry, put(ID1, None, b"")).await, ResponseMessage::Error { .. }));
+
+        handle(&registry, RequestMessage::RestoreTrash { user_id: user(), id: blob(ID1) }).await;
+        assert!(matches!(handle(&registry, get(ID2)).await, ResponseMessage::Data { data } if &data[..] == b"child"));
+
+        handle(&registry, RequestMessage::Remove { user_id: user(), id: blob(ID2) }).await;
+        handle(&registry, RequestMessage::PurgeTrash { user_id: user(), id: None }).await;
+        match handle(&registry, RequestMessage::Usage { user_id: user() }).await {
+            ResponseMessage::Usage { payload_bytes, nodes, .. } => assert_eq!((payload_bytes, nodes), (6, 2)),
+            other => panic!("unexpected {:?}", other),
+        }
+        let restore = handle(&registry, RequestMessage::RestoreTrash { user_id: user(), id: blob(ID2) }).await;
+        assert_eq!(error_code(restore), ERR_NOT_FOUND);
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use anyhow::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::protocol::wire::msg_type_name;
+
+// Bucket upper bounds in seconds, shared by all histograms.
+const LATENCY_BUCKETS: [f64; 12] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+
+#[derive(Default)]
+pub struct Counter {
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, n: u64) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge {
+    value: AtomicI64,
+}
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default, Clone)]
+struct HistogramData {
+    // One count per bucket in LATENCY_BUCKETS (non-cumulative), plus the +Inf bucket.
+    counts: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl HistogramData {
+    fn observe(&mut self, secs: f64) {
+        let idx = LATENCY_BUCKETS.iter().position(|b| secs <= *b).unwrap_or(LATENCY_BUCKETS.len());
+        self.counts[idx] += 1;
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            cumulative += self.counts[i];
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, bound, cumulative);
+        }
+        cumulative += self.counts[LATENCY_BUCKETS.len()];
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, sep, cumulative);
+        let braces = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let _ = writeln!(out, "{}_sum{} {}", name, braces, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braces, self.count);
+    }
+}
+
+#[derive(Default)]
+pub struct Histogram {
+    data: Mutex<HistogramData>,
+}
+
+impl Histogram {
+    pub fn observe(&self, d: Duration) {
+        self.data.lock().unwrap().observe(d.as_secs_f64());
+    }
+
+    pub fn count(&self) -> u64 {
+        self.data.lock().unwrap().count
+    }
+}
+
+// A counter family with a single label.
+#[derive(Default)]
+pub struct CounterVec {
+    values: Mutex<BTreeMap<String, u64>>,
+}
+
+impl CounterVec {
+    pub fn inc(&self, label: &str) {
+        self.inc_by(label, 1);
+    }
+
+    pub fn inc_by(&self, label: &str, n: u64) {
+        let mut values = self.values.lock().unwrap();
+        *values.entry(label.to_string()).or_insert(0) += n;
+    }
+
+    pub fn get(&self, label: &str) -> u64 {
+        self.values.lock().unwrap().get(label).copied().unwrap_or(0)
+    }
+}
+
+// A histogram family with a single label.
+#[derive(Default)]
+pub struct HistogramVec {
+    values: Mutex<BTreeMap<String, HistogramData>>,
+}
+
+impl HistogramVec {
+    pub fn observe(&self, label: &str, d: Duration) {
+        let mut values = self.values.lock().unwrap();
+        values.entry(label.to_string()).or_default().observe(d.as_secs_f64());
+    }
+
+    pub fn count(&self, label: &str) -> u64 {
+        self.values.lock().unwrap().get(label).map(|h| h.count).unwrap_or(0)
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    pub frames_in: CounterVec,
+    pub frames_out: CounterVec,
+    pub bytes_in: Counter,
+    pub bytes_out: Counter,
+    pub request_latency: HistogramVec,
+    pub parse_errors: Counter,
+    pub auth_failures: Counter,
+    pub active_connections: Gauge,
+    pub provider_cache_hits: Counter,
+    pub provider_cache_misses: Counter,
+    pub flush_duration: Histogram,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+// Process-wide metrics, shared by every connection and provider.
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::default)
+}
+
+impl Metrics {
+    pub fn record_frame_in(&self, msg_type_flag: u8, n_bytes: usize) {
+        self.frames_in.inc(msg_type_name(msg_type_flag));
+        self.bytes_in.inc_by(n_bytes as u64);
+    }
+
+    pub fn record_frame_out(&self, msg_type_flag: u8, n_bytes: usize) {
+        self.frames_out.inc(msg_type_name(msg_type_flag));
+        self.bytes_out.inc_by(n_bytes as u64);
+    }
+
+    // Renders all metrics in the Prometheus text exposition format (version 0.0.4).
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        render_counter_vec(&mut out, "bearcub_frames_in_total", "Frames received, by message type.", "msg_type", &self.frames_in);
+        render_counter_vec(&mut out, "bearcub_frames_out_total", "Frames sent, by message type.", "msg_type", &self.frames_out);
+        render_counter(&mut out, "bearcub_bytes_in_total", "Bytes received over the wire protocol.", self.bytes_in.get());
+        render_counter(&mut out, "bearcub_bytes_out_total", "Bytes sent over the wire protocol.", self.bytes_out.get());
+
+        let _ = writeln!(out, "# HELP bearcub_request_duration_seconds Request latency, by operation.");
+        let _ = writeln!(out, "# TYPE bearcub_request_duration_seconds histogram");
+        for (op, h) in self.request_latency.values.lock().unwrap().iter() {
+            h.render(&mut out, "bearcub_request_duration_seconds", &format!("op=\"{}\"", op));
+        }
+
+        render_counter(&mut out, "bearcub_parse_errors_total", "Frames that could not be parsed.", self.parse_errors.get());
+        render_counter(&mut out, "bearcub_auth_failures_total", "Connections closed for failing authentication.", self.auth_failures.get());
+
+        let _ = writeln!(out, "# HELP bearcub_active_connections Currently open client connections.");
+        let _ = writeln!(out, "# TYPE bearcub_active_connections gauge");
+        let _ = writeln!(out, "bearcub_active_connections {}", self.active_connections.get());
+
+        render_counter(&mut out, "bearcub_provider_cache_hits_total", "Provider lookups served from a loaded tree.", self.provider_cache_hits.get());
+        render_counter(&mut out, "bearcub_provider_cache_misses_total", "Provider lookups that had to load the tree from disk.", self.provider_cache_misses.get());
+
+        let _ = writeln!(out, "# HELP bearcub_flush_duration_seconds Time spent flushing blob trees to disk.");
+        let _ = writeln!(out, "# TYPE bearcub_flush_duration_seconds histogram");
+        let flush = self.flush_duration.data.lock().unwrap().clone();
+        flush.render(&mut out, "bearcub_flush_duration_seconds", "");
+        out
+    }
+}
+
+fn render_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn render_counter_vec(out: &mut String, name: &str, help: &str, label: &str, cv: &CounterVec) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (k, v) in cv.values.lock().unwrap().iter() {
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, k, v);
+    }
+}
+
+// Serves `GET /metrics` over plain HTTP/1.1. This is deliberately minimal: it is meant
+// to be bound to a local port and scraped by Prometheus, nothing else.
+pub async fn serve(addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    loop {
+        let (socket, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle_scrape(socket).await {
+                println!("metrics scrape failed: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn handle_scrape(mut socket: TcpStream) -> Result<()> {
+    let mut buf = vec![0u8; 1024];
+    let n = socket.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    let (status, body) = if path == "/metrics" {
+        ("200 OK", metrics().render())
+    } else {
+        ("404 Not Found", String::new())
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let mut h = HistogramData::default();
+        h.observe(0.0002);
+        h.observe(0.003);
+        h.observe(5.0);
+        let mut out = String::new();
+        h.render(&mut out, "x", "op=\"get\"");
+        assert!(out.contains("x_bucket{op=\"get\",le=\"0.00025\"} 1\n"));
+        assert!(out.contains("x_bucket{op=\"get\",le=\"0.005\"} 2\n"));
+        assert!(out.contains("x_bucket{op=\"get\",le=\"1\"} 2\n"));
+        assert!(out.contains("x_bucket{op=\"get\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("x_count{op=\"get\"} 3\n"));
+    }
+
+    #[test]
+    fn test_render_labels_frames_by_type() {
+        let m = Metrics::default();
+        m.record_frame_in(b'G', 85);
+        m.record_frame_in(b'G', 85);
+        m.record_frame_out(b'd', 20);
+        let out = m.render();
+        assert!(out.contains("bearcub_frames_in_total{msg_type=\"get\"} 2\n"));
+        assert!(out.contains("bearcub_frames_out_total{msg_type=\"data\"} 1\n"));
+        assert!(out.contains("bearcub_bytes_in_total 170\n"));
+        assert!(out.contains("bearcub_flush_duration_seconds_count 0\n"));
+    }
+}
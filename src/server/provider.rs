@@ -1,37 +1,1166 @@
-use crate::storage::format::BlobNode;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
+use anyhow::*;
+use bytes::Bytes;
+use serde::de::IgnoredAny;
 
+use crate::protocol::compression::Codec;
+use crate::protocol::ids::BlobId;
+use crate::protocol::types::{BatchOp, ChangeOp, NodeMeta, RequestError, SearchResult, SortKey, TreeItem, ERR_ALREADY_EXISTS, ERR_BAD_REQUEST, ERR_CONFLICT, ERR_INVALID_JSON, ERR_NOT_FOUND, ERR_RESYNC_REQUIRED};
+use crate::server::changes::{ChangeEvent, ChangeHub};
+use crate::server::metrics::metrics;
+use crate::server::quota::{QuotaLimits, StorageUsage};
+use crate::storage::blobfile::{self, BlobReader, FileOptions};
+use crate::storage::changelog::{self, ChangeKind, ChangeLog, ChangeRecord, CHANGELOG_FILE_NAME};
+use crate::storage::crypto::{Keyring, UserKeys};
+use crate::storage::format::{check_content_type, check_title, now_millis, BlobNode};
+use crate::storage::history::{self, RetentionPolicy, RevisionInfo};
+use crate::storage::search::{self, SearchIndex, MAX_INDEXED_PAYLOAD_BYTES, SEARCH_INDEX_FILE_NAME};
+use crate::storage::tags::{self, TagIndex, MAX_TAGS_PER_BLOB};
+use crate::storage::trash::{self, TrashEntry, TRASH_FILE_NAME};
+
+pub const TREE_FILE_NAME: &str = "blobs.bson";
+
+// Payloads written by a batch are staged as `<blob id>.json.batch<n>` until it is committed.
+const BATCH_STAGING_INFIX: &str = ".json.batch";
+
+// Payload of a Put or Set: in memory, or already written to a staging file in the user's
+// directory (see `staging_path`) in the format of payload files, which is moved into place.
+#[derive(Debug)]
+pub enum Payload {
+    Bytes(Bytes),
+    Staged { path: PathBuf, len: u64 },
+}
+
+impl Payload {
+    pub fn len(&self) -> u64 {
+        match self {
+            Payload::Bytes(data) => data.len() as u64,
+            Payload::Staged { len, .. } => *len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl From<Bytes> for Payload {
+    fn from(data: Bytes) -> Payload {
+        Payload::Bytes(data)
+    }
+}
+
+// Node metadata a Put or Set can change along with the payload. `None` keeps the current
+// value, or the default for a new node.
+#[derive(Debug, Clone, Default)]
+pub struct NodeAttrs {
+    pub title: Option<String>,
+    pub content_type: Option<String>,
+}
+
+// A fresh path for staging an incoming payload of `user_id`. It is on the same file system
+// as the user's payloads, so installing it is a rename.
+pub fn staging_path(data_dir: &str, user_id: &str) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    Path::new(data_dir).join(user_id).join(format!(".upload-{}-{}.tmp", std::process::id(), n))
+}
+
+// Settings shared by every provider of a server.
+#[derive(Debug, Clone, Default)]
+pub struct ProviderOptions {
+    pub quota: QuotaLimits,
+    pub history: RetentionPolicy,
+    // Deletions are remembered for `ChangesSince` this long; `None` keeps them forever.
+    pub tombstone_max_age: Option<Duration>,
+    // Codec for newly written payload files. Files record their own codec, so changing
+    // this leaves existing payloads readable.
+    pub compression: Codec,
+    // Encrypts everything written for a user with a key derived from the current master
+    // key. Without it files are written in plaintext.
+    pub keyring: Option<Arc<Keyring>>,
+    // Rejects Put and Set payloads that are not JSON with `ERR_INVALID_JSON`. Empty
+    // payloads are always accepted.
+    pub validate_json: bool,
+}
+
+impl ProviderOptions {
+    // How the files of `user_id` are written.
+    pub fn file_options(&self, user_id: &str) -> FileOptions {
+        FileOptions { codec: self.compression, keys: self.keyring.as_ref().map(|k| UserKeys::new(k.clone(), user_id)) }
+    }
+}
 
 pub struct Provider {
     data_dir: String,
     user_id: String,
+    options: Arc<ProviderOptions>,
+    files: FileOptions,
     blob_root: Option<BlobNode>,
+    // Set when `blob_root` has changes that are not yet in `blobs.bson`.
+    dirty: bool,
+    // Loaded together with `blob_root`; saved to `trash.bson` after every change.
+    trash: Vec<TrashEntry>,
+    trash_dirty: bool,
+    // Sum of the sizes of all payload files, computed on load and kept up to date by writes.
+    payload_bytes: u64,
+    // Sequence numbers of mutations, loaded with `blob_root` and saved after every change.
+    changelog: ChangeLog,
+    changelog_dirty: bool,
+    // Loaded with `blob_root` and saved after every change; `None` if there is no index
+    // file yet, or the index has to be rebuilt, which the next search does.
+    search: Option<SearchIndex>,
+    search_dirty: bool,
+    // Built from `blob_root` when it is loaded or replaced, and kept up to date by changes.
+    tags: TagIndex,
+    // Where mutations are announced to watching connections.
+    changes: Arc<ChangeHub>,
+    // Set while a batch is being applied, to undo it if one of its operations fails.
+    batch: Option<BatchJournal>,
+}
+
+// A batch being applied. Its changes stay in memory, and the payloads it writes in staging
+// files, until it is committed; nothing on disk changes before then. Also holds what is
+// needed to put the user's store back as it was if one of its operations fails.
+struct BatchJournal {
+    root: Option<BlobNode>,
+    trash: Vec<TrashEntry>,
+    changelog: ChangeLog,
+    search: Option<SearchIndex>,
+    payload_bytes: u64,
+    // Id -> the staging file with its latest payload, moved into place on commit.
+    staged: BTreeMap<String, PathBuf>,
+    // Payloads to move into the history on commit, in order, as id, revision and the
+    // file holding it.
+    archived: Vec<(String, u64, PathBuf)>,
+    // Every staging file written so far.
+    written: Vec<PathBuf>,
+    // Held back until the batch is committed.
+    events: Vec<ChangeEvent>,
+    prune: Vec<String>,
 }
 
 impl Provider {
     pub fn new(data_dir: String, user_id: String) -> Provider {
-        Provider { data_dir, user_id, blob_root: None }
+        Provider::with_options(data_dir, user_id, Arc::new(ProviderOptions::default()), Arc::new(ChangeHub::default()))
+    }
+
+    pub fn with_options(data_dir: String, user_id: String, options: Arc<ProviderOptions>, changes: Arc<ChangeHub>) -> Provider {
+        let files = options.file_options(&user_id);
+        Provider {
+            data_dir,
+            user_id,
+            options,
+            files,
+            blob_root: None,
+            dirty: false,
+            trash: vec![],
+            trash_dirty: false,
+            payload_bytes: 0,
+            changelog: ChangeLog::default(),
+            changelog_dirty: false,
+            search: None,
+            search_dirty: false,
+            tags: TagIndex::default(),
+            changes,
+            batch: None,
+        }
+    }
+
+    pub fn user_id(&self) -> &str {
+        &self.user_id[..]
+    }
+
+    pub fn options(&self) -> &ProviderOptions {
+        &self.options
+    }
+
+    pub fn user_dir(&self) -> PathBuf {
+        Path::new(&self.data_dir).join(&self.user_id)
+    }
+
+    pub fn tree_path(&self) -> PathBuf {
+        self.user_dir().join(TREE_FILE_NAME)
+    }
+
+    pub fn trash_path(&self) -> PathBuf {
+        self.user_dir().join(TRASH_FILE_NAME)
+    }
+
+    pub fn changelog_path(&self) -> PathBuf {
+        self.user_dir().join(CHANGELOG_FILE_NAME)
+    }
+
+    pub fn search_index_path(&self) -> PathBuf {
+        self.user_dir().join(SEARCH_INDEX_FILE_NAME)
+    }
+
+    pub fn blob_path(&self, id: &str) -> PathBuf {
+        self.user_dir().join(format!("{}.json", id))
     }
 
     pub fn cheeck_root_structure(&mut self) {
         if self.blob_root.is_some() {
+            metrics().provider_cache_hits.inc();
             return;
         }
+        metrics().provider_cache_misses.inc();
+        // Payloads staged by a batch that never got to commit.
+        if let Result::Ok(entries) = fs::read_dir(self.user_dir()) {
+            for entry in entries.flatten() {
+                if entry.file_name().to_string_lossy().contains(BATCH_STAGING_INFIX) {
+                    let _ = fs::remove_file(entry.path());
+                }
+            }
+        }
+
+        let path = self.tree_path();
+        self.blob_root = if path.exists() {
+            BlobNode::from_file(&path.to_string_lossy(), &self.files).ok()
+        } else {
+            // A user without a tree yet starts with an empty root named after them.
+            Some(BlobNode::new(self.user_id.clone(), String::from("root"), vec![]))
+        };
+        match trash::load(&self.trash_path(), &self.files) {
+            Result::Ok(entries) => self.trash = entries,
+            Err(e) => {
+                // Rather unusable than saving over a trash we could not read.
+                println!("cannot load trash of {}: {:?}", self.user_id, e);
+                self.blob_root = None;
+            }
+        }
+        match changelog::load(&self.changelog_path(), &self.files) {
+            Result::Ok(log) => self.changelog = log,
+            Err(e) => {
+                // Starting the sequence over would hand clients numbers they have seen.
+                println!("cannot load change log of {}: {:?}", self.user_id, e);
+                self.blob_root = None;
+            }
+        }
+        // Derived from the tree and payloads, so one that cannot be read is rebuilt, as is
+        // one missing changes made after it was last saved. A new user's index is kept from
+        // the first write on.
+        self.search = match search::load(&self.search_index_path(), &self.files) {
+            Result::Ok(None) if !path.exists() => Some(SearchIndex::default()),
+            Result::Ok(None) => None,
+            Result::Ok(Some((index, seq))) => (seq == self.changelog.last_seq).then_some(index),
+            Err(e) => {
+                println!("cannot load search index of {}, rebuilding it: {:?}", self.user_id, e);
+                None
+            }
+        };
+        self.tags = self.blob_root.as_ref().map(TagIndex::build).unwrap_or_default();
+        // Trashed payloads are still on disk, so they still count.
+        self.payload_bytes = match &self.blob_root {
+            Some(root) => root.ids().iter().chain(self.trashed_ids().iter()).map(|id| self.stored_len(id)).sum(),
+            None => 0,
+        };
+    }
+
+    fn trashed_ids(&self) -> Vec<String> {
+        self.trash.iter().flat_map(|e| e.subtree.ids()).collect()
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.blob_root.is_some()
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty || self.trash_dirty || self.changelog_dirty || self.search_dirty
+    }
+
+    fn root(&self) -> Result<&BlobNode> {
+        self.blob_root.as_ref().ok_or_else(|| anyhow!("blob tree for {} could not be loaded", self.user_id))
+    }
+
+    fn root_mut(&mut self) -> Result<&mut BlobNode> {
+        let user_id = &self.user_id;
+        self.blob_root.as_mut().ok_or_else(|| anyhow!("blob tree for {} could not be loaded", user_id))
+    }
 
-        self.blob_root = BlobNode::from_file(&self.data_dir[..]).ok();
+    // Replaces the whole tree; the search index is rebuilt for it on the next search.
+    pub fn set_root(&mut self, root: BlobNode) {
+        self.tags = TagIndex::build(&root);
+        self.blob_root = Some(root);
+        self.search = None;
+        let _ = fs::remove_file(self.search_index_path());
+        self.dirty = true;
+    }
+
+    // Saves everything changed since the last flush, the search index included.
+    pub fn flush(&mut self) -> Result<()> {
+        self.save()?;
+        if self.search_dirty {
+            if let Some(index) = &self.search {
+                search::save(&self.search_index_path(), index, self.changelog.last_seq, &self.files)?;
+            }
+            self.search_dirty = false;
+        }
+        Ok(())
+    }
+
+    // Saves the tree, trash and change log, the change log last, so after a crash it never
+    // holds a change the other files lack. The search index is left to `flush`, which runs
+    // when the user is evicted and on shutdown: it is rebuilt if a crash leaves it behind.
+    fn save(&mut self) -> Result<()> {
+        if !(self.dirty || self.trash_dirty || self.changelog_dirty) {
+            return Ok(());
+        }
+        fs::create_dir_all(self.user_dir())?;
+        if self.dirty {
+            if let Some(root) = &self.blob_root {
+                root.flush_to_file(&self.tree_path().to_string_lossy(), &self.files)?;
+            }
+            self.dirty = false;
+        }
+        if self.trash_dirty {
+            self.save_trash()?;
+            self.trash_dirty = false;
+        }
+        if self.changelog_dirty {
+            changelog::save(&self.changelog_path(), &self.changelog, &self.files)?;
+            self.changelog_dirty = false;
+        }
+        Ok(())
+    }
+
+    // Saves a change right away, unless a batch is being applied: a batch is saved all at
+    // once when it is committed.
+    fn persist(&mut self) -> Result<()> {
+        match self.batch {
+            Some(_) => Ok(()),
+            None => self.save(),
+        }
+    }
+
+    // Rough number of heap bytes held by the loaded tree, used for cache budgeting.
+    pub fn approx_size(&self) -> usize {
+        let base = std::mem::size_of::<Provider>() + self.data_dir.len() + self.user_id.len()
+            + self.trash.iter().map(|e| e.subtree.approx_size() + e.parent_id.len()).sum::<usize>()
+            + self.changelog.approx_size()
+            + self.search.as_ref().map(|s| s.approx_size()).unwrap_or(0)
+            + self.tags.approx_size();
+        match &self.blob_root {
+            Some(root) => base + root.approx_size(),
+            None => base,
+        }
     }
 
     pub fn get_blob(&self, id: &str) -> Option<BlobNode> {
         match &self.blob_root {
-            Some(root) => {
-                //
-                let res = by_id_for_node(root, id);
-                res
-            },
+            Some(root) => by_id_for_node(root, id),
             None => None,
         }
     }
 
+    pub fn contains(&self, id: &str) -> bool {
+        self.blob_root.as_ref().map(|root| root.find(id).is_some()).unwrap_or(false)
+    }
+
+    pub fn usage(&self) -> Result<StorageUsage> {
+        let root = self.root()?;
+        Ok(StorageUsage { payload_bytes: self.payload_bytes, nodes: root.node_count(), depth: root.height() })
+    }
+
+    fn stored_len(&self, id: &str) -> u64 {
+        blobfile::stored_len(&self.payload_path(id)).unwrap_or(0)
+    }
+
+    // Where the payload of `id` is read from: its file, or the staging file of the batch
+    // being applied if the batch wrote it.
+    fn payload_path(&self, id: &str) -> PathBuf {
+        match self.batch.as_ref().and_then(|batch| batch.staged.get(id)) {
+            Some(path) => path.clone(),
+            None => self.blob_path(id),
+        }
+    }
+
+    // Title, content type, revision, parent and children of `id`, as sent ahead of its payload.
+    pub fn node_meta(&self, id: &str) -> Result<NodeMeta> {
+        let root = self.root()?;
+        let node = root.find(id).ok_or_else(|| not_found(id))?;
+        // The root is named after the user and is not a blob of its own.
+        let parent = match root.path_to(id).and_then(|path| path.last().cloned()) {
+            Some(parent) if parent != root.id() => Some(BlobId::parse(&parent)?),
+            _ => None,
+        };
+        Ok(NodeMeta {
+            revision: node.revision(),
+            size: node.size(),
+            parent,
+            title: node.title().to_string(),
+            content_type: node.content_type().to_string(),
+            children: node.children().iter().map(|c| BlobId::parse(c.id())).collect::<Result<Vec<_>>>()?,
+        })
+    }
+
+    // Up to `limit` nodes of the subtree under `root` (the whole tree if `None`), after the
+    // first `offset` in pre-order, `depth` levels deep (all if `None`). Also returns
+    // whether more nodes follow.
+    pub fn list_tree(&self, root: Option<&str>, depth: Option<u32>, offset: usize, limit: usize) -> Result<(Vec<TreeItem>, bool)> {
+        let tree = self.root()?;
+        let top = match root {
+            Some(id) => tree.find(id).ok_or_else(|| not_found(id))?,
+            None => tree,
+        };
+        let nodes = top.descendants(depth);
+        let items = nodes
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .map(|(node, parent, depth, position)| TreeItem {
+                id: node.id().to_string(),
+                title: node.title().to_string(),
+                parent_id: if parent.id() == tree.id() { None } else { Some(parent.id().to_string()) },
+                depth: *depth,
+                position: *position,
+                created: node.created(),
+                modified: node.modified(),
+                size: node.size(),
+                content_type: node.content_type().to_string(),
+                revision: node.revision(),
+                children: node.children().len() as u32,
+                tags: node.tags().iter().cloned().collect(),
+            })
+            .collect();
+        Ok((items, nodes.len() > offset.saturating_add(limit)))
+    }
+
+    // Opens the payload of `id` for reading; `None` if it was never written (empty).
+    pub fn open_blob(&self, id: &str) -> Result<Option<BlobReader>> {
+        if self.root()?.find(id).is_none() {
+            return Err(not_found(id));
+        }
+        match BlobReader::open(&self.payload_path(id), &self.files) {
+            Result::Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn read_blob(&self, id: &str) -> Result<Bytes> {
+        if self.root()?.find(id).is_none() {
+            return Err(not_found(id));
+        }
+        match blobfile::read(&self.payload_path(id), &self.files) {
+            Result::Ok(data) => Ok(Bytes::from(data)),
+            // A node that was never written has an empty payload.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Bytes::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Creates the node `id` under `parent` (the root if `None`) with `data` as its payload.
+    // With `expected_revision`, instead overwrites the payload of the existing node `id`
+    // if it is still at that revision. Returns the node's new revision.
+    pub fn put(&mut self, id: &str, parent: Option<&str>, expected_revision: Option<u64>, data: impl Into<Payload>) -> Result<u64> {
+        self.put_at(id, parent, None, expected_revision, NodeAttrs::default(), data)
+    }
+
+    // Like `put`, but a new node goes in at `position` among its siblings (last if `None`
+    // or past the end), and gets `attrs`.
+    pub fn put_at(&mut self, id: &str, parent: Option<&str>, position: Option<u32>, expected_revision: Option<u64>, attrs: NodeAttrs, data: impl Into<Payload>) -> Result<u64> {
+        let data = data.into();
+        if expected_revision.is_some() {
+            return self.set_with(id, expected_revision, attrs, data);
+        }
+        let root = self.root()?;
+        if root.find(id).is_some() {
+            return Err(RequestError::new(ERR_ALREADY_EXISTS, format!("blob {} already exists", id)).into());
+        }
+        if self.trash.iter().any(|e| e.subtree.find(id).is_some()) {
+            return Err(RequestError::new(ERR_ALREADY_EXISTS, format!("blob {} is in the trash", id)).into());
+        }
+        let parent_id = parent.unwrap_or(root.id()).to_string();
+        let parent_depth = root.depth_of(&parent_id).ok_or_else(|| not_found(&parent_id))?;
+        check_attrs(&attrs)?;
+        self.check_json(id, &data)?;
+
+        let before = self.usage()?;
+        let after = StorageUsage {
+            payload_bytes: before.payload_bytes + data.len(),
+            nodes: before.nodes + 1,
+            depth: before.depth.max(parent_depth + 1),
+        };
+        self.options.quota.check(&before, &after, data.len())?;
+
+        let len = data.len();
+        self.write_blob(id, data)?;
+        let mut node = BlobNode::with_payload(id.to_string(), attrs.title.unwrap_or_default(), len);
+        if let Some(content_type) = attrs.content_type {
+            node.set_content_type(content_type);
+        }
+        let revision = node.revision();
+        let new_parent = self.root_mut()?.find_mut(&parent_id).ok_or_else(|| not_found(&parent_id))?;
+        new_parent.insert_child(position.map(|p| p as usize).unwrap_or(usize::MAX), node);
+        self.payload_bytes = after.payload_bytes;
+        self.dirty = true;
+        self.log_changes(&[id.to_string()], ChangeKind::Added)?;
+        self.persist()?;
+        self.publish(id, ChangeOp::Created, revision)?;
+        Ok(revision)
+    }
+
+    // Replaces the payload of the existing node `id`, provided it is at `expected_revision`
+    // when one is given. Returns the node's new revision.
+    pub fn set(&mut self, id: &str, expected_revision: Option<u64>, data: impl Into<Payload>) -> Result<u64> {
+        self.set_with(id, expected_revision, NodeAttrs::default(), data)
+    }
+
+    // Like `set`, but also changes the node's metadata to `attrs`.
+    pub fn set_with(&mut self, id: &str, expected_revision: Option<u64>, attrs: NodeAttrs, data: impl Into<Payload>) -> Result<u64> {
+        let data = data.into();
+        let node = self.root()?.find(id).ok_or_else(|| not_found(id))?;
+        if let Some(expected) = expected_revision {
+            if node.revision() != expected {
+                let msg = format!("blob {} is at revision {}, expected {}", id, node.revision(), expected);
+                return Err(RequestError::new(ERR_CONFLICT, msg).into());
+            }
+        }
+        check_attrs(&attrs)?;
+        self.check_json(id, &data)?;
+        let old_len = self.stored_len(id);
+        let before = self.usage()?;
+        let after = StorageUsage { payload_bytes: before.payload_bytes.saturating_sub(old_len) + data.len(), ..before };
+        self.options.quota.check(&before, &after, data.len())?;
+
+        let old_revision = self.root()?.find(id).map(|n| n.revision()).unwrap_or(0);
+        let current = self.payload_path(id);
+        if self.options.history.is_enabled() && current.exists() {
+            match &mut self.batch {
+                Some(batch) => {
+                    batch.archived.push((id.to_string(), old_revision, current));
+                    batch.prune.push(id.to_string());
+                },
+                None => {
+                    history::archive(&self.user_dir(), id, old_revision, &current)?;
+                    history::prune(&self.user_dir(), id, &self.options.history)?;
+                },
+            }
+        }
+        let len = data.len();
+        self.write_blob(id, data)?;
+        self.payload_bytes = after.payload_bytes;
+        let node = self.root_mut()?.find_mut(id).ok_or_else(|| not_found(id))?;
+        node.record_write(len);
+        if let Some(title) = attrs.title {
+            node.set_title(title);
+        }
+        if let Some(content_type) = attrs.content_type {
+            node.set_content_type(content_type);
+        }
+        let revision = node.revision();
+        self.dirty = true;
+        self.log_changes(&[id.to_string()], ChangeKind::Modified)?;
+        self.persist()?;
+        self.publish(id, ChangeOp::Updated, revision)?;
+        Ok(revision)
+    }
+
+    // With `validate_json`, fails with `ERR_INVALID_JSON` unless `data` is empty or JSON.
+    fn check_json(&self, id: &str, data: &Payload) -> Result<()> {
+        if !self.options.validate_json || data.is_empty() {
+            return Ok(());
+        }
+        let parsed = match data {
+            Payload::Bytes(data) => serde_json::from_slice::<IgnoredAny>(data),
+            Payload::Staged { path, .. } => serde_json::from_reader::<_, IgnoredAny>(io::BufReader::new(BlobReader::open(path, &self.files)?)),
+        };
+        match parsed {
+            Result::Ok(_) => Ok(()),
+            Err(e) if e.is_io() => Err(e.into()),
+            Err(e) => Err(RequestError::new(ERR_INVALID_JSON, format!("payload of {} is not JSON: {}", id, e)).into()),
+        }
+    }
+
+    // The revision of `id` and the JSON text of the values at `pointers` in its payload.
+    // Pointers without a value are left out; an empty payload has none. The payload size
+    // is taken from `budget`; a payload larger than what is left is not read at all.
+    pub fn project(&self, id: &str, pointers: &[String], budget: &mut u64) -> Result<(u64, BTreeMap<String, String>)> {
+        let node = self.root()?.find(id).ok_or_else(|| not_found(id))?;
+        let revision = node.revision();
+        if node.size() > *budget {
+            return Err(RequestError::new(ERR_BAD_REQUEST, format!("payload of {} is too large to project", id)).into());
+        }
+        *budget -= node.size();
+        let data = self.read_blob(id)?;
+        let mut fields = BTreeMap::new();
+        if data.is_empty() {
+            return Ok((revision, fields));
+        }
+        let value: serde_json::Value = serde_json::from_slice(&data)
+            .map_err(|e| RequestError::new(ERR_INVALID_JSON, format!("payload of {} is not JSON: {}", id, e)))?;
+        for pointer in pointers {
+            if let Some(v) = value.pointer(pointer) {
+                fields.insert(pointer.clone(), v.to_string());
+            }
+        }
+        Ok((revision, fields))
+    }
+
+    // Moves `id` and its whole subtree to the trash. Payloads stay on disk until purged.
+    pub fn remove(&mut self, id: &str) -> Result<()> {
+        if self.root()?.id() == id {
+            return Err(RequestError::new(ERR_BAD_REQUEST, "the root blob cannot be removed").into());
+        }
+        let path = self.root()?.path_to(id).ok_or_else(|| not_found(id))?;
+        let (parent_id, position, subtree) = self.root_mut()?.detach(id).ok_or_else(|| not_found(id))?;
+        let event = ChangeEvent { id: id.to_string(), op: ChangeOp::Removed, revision: subtree.revision(), path };
+        let removed = subtree.ids();
+        self.trash.push(TrashEntry { subtree, parent_id, position: position as u32, deleted_at: now_millis() });
+        self.dirty = true;
+        self.trash_dirty = true;
+        self.log_changes(&removed, ChangeKind::Deleted)?;
+        self.persist()?;
+        self.emit(event);
+        Ok(())
+    }
+
+    // Moves `id` and its subtree under `parent` (the root if `None`), at `position` among
+    // its new siblings (the end if `None`). Returns the node's new revision.
+    pub fn move_node(&mut self, id: &str, parent: Option<&str>, position: Option<u32>) -> Result<u64> {
+        let root = self.root()?;
+        if root.id() == id {
+            return Err(RequestError::new(ERR_BAD_REQUEST, "the root blob cannot be moved").into());
+        }
+        let node = root.find(id).ok_or_else(|| not_found(id))?;
+        let parent_id = parent.unwrap_or(root.id()).to_string();
+        let parent_depth = root.depth_of(&parent_id).ok_or_else(|| not_found(&parent_id))?;
+        if node.find(&parent_id).is_some() {
+            return Err(RequestError::new(ERR_BAD_REQUEST, format!("blob {} cannot be moved under itself", id)).into());
+        }
+        let before = self.usage()?;
+        let after = StorageUsage { depth: before.depth.max(parent_depth + 1 + node.height()), ..before };
+        self.options.quota.check(&before, &after, 0)?;
+
+        let root = self.root_mut()?;
+        let (_, _, mut subtree) = root.detach(id).ok_or_else(|| not_found(id))?;
+        subtree.touch();
+        let revision = subtree.revision();
+        let new_parent = root.find_mut(&parent_id).ok_or_else(|| not_found(&parent_id))?;
+        let position = position.map(|p| p as usize).unwrap_or(new_parent.children().len());
+        new_parent.insert_child(position, subtree);
+        self.dirty = true;
+        self.log_changes(&[id.to_string()], ChangeKind::Moved)?;
+        self.persist()?;
+        self.publish(id, ChangeOp::Moved, revision)?;
+        Ok(revision)
+    }
+
+    // Moves `id` and its subtree next to `sibling`, under the sibling's parent: right
+    // before it, or right after it if `after`. Returns the moved node's new revision.
+    pub fn move_next_to(&mut self, id: &str, sibling: &str, after: bool) -> Result<u64> {
+        if id == sibling {
+            return Err(RequestError::new(ERR_BAD_REQUEST, format!("blob {} cannot be moved next to itself", id)).into());
+        }
+        let root = self.root()?;
+        let parent_id = root.path_to(sibling).and_then(|path| path.last().cloned()).ok_or_else(|| not_found(sibling))?;
+        let parent = root.find(&parent_id).ok_or_else(|| not_found(&parent_id))?;
+        // Where the sibling is once `id` is out of the way.
+        let index = parent.children().iter().filter(|c| c.id() != id).position(|c| c.id() == sibling).ok_or_else(|| not_found(sibling))?;
+        let position = if after { index + 1 } else { index };
+        self.move_node(id, Some(&parent_id), Some(position as u32))
+    }
+
+    // Orders the children of `parent` (the root if `None`) by `key`, keeping the current
+    // order between equal ones. Children that end up at a new position count as moved.
+    pub fn sort_children(&mut self, parent: Option<&str>, key: SortKey, descending: bool) -> Result<()> {
+        let root = self.root_mut()?;
+        let parent_id = parent.unwrap_or(root.id()).to_string();
+        let node = root.find_mut(&parent_id).ok_or_else(|| not_found(&parent_id))?;
+        let before: Vec<String> = node.children().iter().map(|c| c.id().to_string()).collect();
+        node.sort_children_by(|a, b| {
+            let order = match key {
+                SortKey::Title => a.title().to_lowercase().cmp(&b.title().to_lowercase()),
+                SortKey::Modified => a.modified().cmp(&b.modified()),
+            };
+            if descending { order.reverse() } else { order }
+        });
+        let mut moved = vec![];
+        for (idx, old_id) in before.iter().enumerate() {
+            let child = &mut node.children_mut()[idx];
+            if child.id() != old_id {
+                child.touch();
+                moved.push((child.id().to_string(), child.revision()));
+            }
+        }
+        if moved.is_empty() {
+            return Ok(());
+        }
+        self.dirty = true;
+        self.log_changes(&moved.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>(), ChangeKind::Moved)?;
+        self.persist()?;
+        for (id, revision) in moved {
+            self.publish(&id, ChangeOp::Moved, revision)?;
+        }
+        Ok(())
+    }
+
+    // Applies `ops` in order. If one fails, everything the batch changed is undone and the
+    // index of the failed operation is returned with its error. Otherwise returns the new
+    // revision of each blob written or moved.
+    pub fn apply_batch(&mut self, ops: Vec<BatchOp>) -> std::result::Result<Vec<Option<u64>>, (usize, Error)> {
+        let root = self.root().map_err(|e| (0, e))?.clone();
+        self.batch = Some(BatchJournal {
+            root: Some(root),
+            trash: self.trash.clone(),
+            changelog: self.changelog.clone(),
+            search: self.search.clone(),
+            payload_bytes: self.payload_bytes,
+            staged: BTreeMap::new(),
+            archived: vec![],
+            written: vec![],
+            events: vec![],
+            prune: vec![],
+        });
+        let mut revisions = Vec::with_capacity(ops.len());
+        for (idx, op) in ops.into_iter().enumerate() {
+            let result = match op {
+                BatchOp::Put { id, parent, position, expected_revision, title, content_type, data } => {
+                    self.put_at(&id, parent.as_deref(), position, expected_revision, NodeAttrs { title, content_type }, data).map(Some)
+                },
+                BatchOp::Set { id, expected_revision, content_type, data } => {
+                    self.set_with(&id, expected_revision, NodeAttrs { title: None, content_type }, data).map(Some)
+                },
+                BatchOp::Remove { id } => self.remove(&id).map(|_| None),
+                BatchOp::Rename { id, title } => self.rename(&id, &title).map(Some),
+                BatchOp::Move { id, parent, position } => self.move_node(&id, parent.as_deref(), position).map(Some),
+                BatchOp::MoveNextTo { id, sibling, after } => self.move_next_to(&id, &sibling, after).map(Some),
+                BatchOp::Sort { parent, by, descending } => self.sort_children(parent.as_deref(), by, descending).map(|_| None),
+            };
+            match result {
+                Result::Ok(revision) => revisions.push(revision),
+                Err(e) => {
+                    let journal = self.batch.take().expect("batch journal");
+                    self.rollback(journal);
+                    return Err((idx, e));
+                },
+            }
+        }
+        let journal = self.batch.take().expect("batch journal");
+        // Reported on the last operation. What was not saved stays dirty for the next flush.
+        if let Err(e) = self.commit(journal) {
+            return Err((revisions.len().saturating_sub(1), e.context("saving batch")));
+        }
+        Result::Ok(revisions)
+    }
+
+    // Nothing of the batch reached disk but its staging files, so undoing it only takes
+    // deleting those and putting the in-memory state back.
+    fn rollback(&mut self, journal: BatchJournal) {
+        for path in journal.written {
+            let _ = fs::remove_file(path);
+        }
+        self.blob_root = journal.root;
+        self.tags = self.blob_root.as_ref().map(TagIndex::build).unwrap_or_default();
+        self.trash = journal.trash;
+        self.changelog = journal.changelog;
+        self.search = journal.search;
+        self.payload_bytes = journal.payload_bytes;
+    }
+
+    // Moves the payloads of a successful batch into place, saves everything it changed,
+    // and runs what it held back.
+    fn commit(&mut self, journal: BatchJournal) -> Result<()> {
+        for (id, revision, path) in &journal.archived {
+            history::archive(&self.user_dir(), id, *revision, path)?;
+        }
+        for (id, path) in &journal.staged {
+            fs::rename(path, self.blob_path(id))?;
+        }
+        self.save()?;
+        for id in &journal.prune {
+            if let Err(e) = history::prune(&self.user_dir(), id, &self.options.history) {
+                println!("cannot prune history of {}: {:?}", id, e);
+            }
+        }
+        for event in journal.events {
+            self.changes.publish(&self.user_id, event);
+        }
+        Ok(())
+    }
+
+    pub fn trash(&self) -> &[TrashEntry] {
+        &self.trash[..]
+    }
+
+    // Puts the trashed subtree rooted at `id` back where it was. If its old parent is gone,
+    // it goes under the root instead.
+    pub fn restore_from_trash(&mut self, id: &str) -> Result<()> {
+        let idx = self.trash.iter().position(|e| e.subtree.id() == id).ok_or_else(|| not_found_in_trash(id))?;
+        let root = self.root()?;
+        let parent = match root.find(&self.trash[idx].parent_id) {
+            Some(_) => self.trash[idx].parent_id.clone(),
+            None => root.id().to_string(),
+        };
+        // Trashed nodes do not count towards the node and depth limits, and the parent may
+        // have moved deeper since, so the restored subtree is checked like a new one.
+        let parent_depth = root.depth_of(&parent).ok_or_else(|| not_found(&parent))?;
+        let subtree = &self.trash[idx].subtree;
+        let before = self.usage()?;
+        let after = StorageUsage {
+            nodes: before.nodes + subtree.node_count(),
+            depth: before.depth.max(parent_depth + 1 + subtree.height()),
+            ..before
+        };
+        self.options.quota.check(&before, &after, 0)?;
+
+        let entry = self.trash.remove(idx);
+        let root = self.root_mut()?;
+        let revision = entry.subtree.revision();
+        let restored = entry.subtree.ids();
+        root.find_mut(&parent).ok_or_else(|| not_found(&parent))?.insert_child(entry.position as usize, entry.subtree);
+        self.dirty = true;
+        self.trash_dirty = true;
+        self.log_changes(&restored, ChangeKind::Added)?;
+        self.persist()?;
+        self.publish(id, ChangeOp::Restored, revision)
+    }
+
+    // Gives each of `ids` the next sequence number, pruning expired tombstones on the way.
+    fn log_changes(&mut self, ids: &[String], kind: ChangeKind) -> Result<()> {
+        for id in ids {
+            self.changelog.record(id, kind);
+        }
+        if let Some(max_age) = self.options.tombstone_max_age {
+            self.changelog.prune_tombstones(max_age);
+        }
+        self.changelog_dirty = true;
+        self.update_tag_index(ids, kind)?;
+        self.update_search_index(ids, kind)
+    }
+
+    fn update_tag_index(&mut self, ids: &[String], kind: ChangeKind) -> Result<()> {
+        for id in ids {
+            match kind {
+                ChangeKind::Moved => (),
+                ChangeKind::Deleted => self.tags.remove(id),
+                _ => {
+                    let node = self.blob_root.as_ref().and_then(|root| root.find(id)).ok_or_else(|| not_found(id))?;
+                    self.tags.set(id, node.tags());
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // Adds `add` to and then takes `remove` off the tags of `id`. Returns the node's
+    // revision, which is only bumped if its tags changed.
+    pub fn update_tags(&mut self, id: &str, add: &[String], remove: &[String]) -> Result<u64> {
+        if let Some(msg) = add.iter().find_map(|tag| tags::check_tag(tag)) {
+            return Err(RequestError::new(ERR_BAD_REQUEST, msg).into());
+        }
+        let root = self.root_mut()?;
+        if root.id() == id {
+            return Err(RequestError::new(ERR_BAD_REQUEST, "the root blob cannot be tagged").into());
+        }
+        let node = root.find_mut(id).ok_or_else(|| not_found(id))?;
+        let mut next = node.tags().clone();
+        next.extend(add.iter().cloned());
+        for tag in remove {
+            next.remove(tag);
+        }
+        if &next == node.tags() {
+            return Ok(node.revision());
+        }
+        if next.len() > MAX_TAGS_PER_BLOB {
+            let msg = format!("blob {} would have more than {} tags", id, MAX_TAGS_PER_BLOB);
+            return Err(RequestError::new(ERR_BAD_REQUEST, msg).into());
+        }
+        node.set_tags(next);
+        node.touch();
+        let revision = node.revision();
+        self.dirty = true;
+        self.log_changes(&[id.to_string()], ChangeKind::Modified)?;
+        self.persist()?;
+        self.publish(id, ChangeOp::Updated, revision)?;
+        Ok(revision)
+    }
+
+    // Changes the title of `id`. Returns its new revision.
+    pub fn rename(&mut self, id: &str, title: &str) -> Result<u64> {
+        if let Some(msg) = check_title(title) {
+            return Err(RequestError::new(ERR_BAD_REQUEST, msg).into());
+        }
+        let root = self.root_mut()?;
+        if root.id() == id {
+            return Err(RequestError::new(ERR_BAD_REQUEST, "the root blob cannot be renamed").into());
+        }
+        let node = root.find_mut(id).ok_or_else(|| not_found(id))?;
+        if node.title() == title {
+            return Ok(node.revision());
+        }
+        node.set_title(title.to_string());
+        node.touch();
+        let revision = node.revision();
+        self.dirty = true;
+        self.log_changes(&[id.to_string()], ChangeKind::Modified)?;
+        self.persist()?;
+        self.publish(id, ChangeOp::Updated, revision)?;
+        Ok(revision)
+    }
+
+    // Ids of the blobs tagged `tag`, in id order. Blobs in the trash are left out.
+    pub fn tagged(&self, tag: &str) -> Result<Vec<String>> {
+        self.root()?;
+        Ok(self.tags.ids(tag))
+    }
+
+    // Keeps the search index in step with a change logged for `ids`. Without a loaded
+    // index there is nothing to do: the change is picked up when it is rebuilt.
+    fn update_search_index(&mut self, ids: &[String], kind: ChangeKind) -> Result<()> {
+        if kind == ChangeKind::Moved {
+            return Ok(());
+        }
+        // Left out if indexing fails, so it is rebuilt rather than kept stale.
+        let Some(mut index) = self.search.take() else {
+            return Ok(());
+        };
+        for id in ids {
+            match kind {
+                ChangeKind::Deleted => index.remove(id),
+                _ => self.index_blob(&mut index, id)?,
+            }
+        }
+        self.search = Some(index);
+        self.search_dirty = true;
+        Ok(())
+    }
+
+    fn index_blob(&self, index: &mut SearchIndex, id: &str) -> Result<()> {
+        let node = self.root()?.find(id).ok_or_else(|| not_found(id))?;
+        let text = self.searchable_text(id, node.size())?;
+        index.insert(id, node.title(), &text);
+        Ok(())
+    }
+
+    fn searchable_text(&self, id: &str, size: u64) -> Result<String> {
+        if size > MAX_INDEXED_PAYLOAD_BYTES {
+            return Ok(String::new());
+        }
+        Ok(search::payload_text(&self.read_blob(id)?))
+    }
+
+    // Indexes every blob in the tree from scratch and saves the index. Returns how many
+    // blobs it holds.
+    pub fn rebuild_search_index(&mut self) -> Result<usize> {
+        let root = self.root()?;
+        let mut index = SearchIndex::default();
+        for id in root.ids().into_iter().filter(|id| id != root.id()) {
+            self.index_blob(&mut index, &id)?;
+        }
+        // A user with nothing stored gets no directory just for an empty index.
+        if !index.is_empty() || self.user_dir().exists() {
+            fs::create_dir_all(self.user_dir())?;
+            search::save(&self.search_index_path(), &index, self.changelog.last_seq, &self.files)?;
+        }
+        let len = index.len();
+        self.search = Some(index);
+        self.search_dirty = false;
+        Ok(len)
+    }
+
+    // Blobs whose title or payload text has words of `query`, best first, at most `limit`.
+    pub fn search(&mut self, query: &str, limit: usize) -> Result<Vec<SearchResult>> {
+        if self.search.is_none() {
+            self.rebuild_search_index()?;
+        }
+        let hits = self.search.as_ref().map(|index| index.search(query, limit)).unwrap_or_default();
+        let root = self.root()?;
+        let mut results = Vec::with_capacity(hits.len());
+        for hit in hits {
+            let Some(node) = root.find(&hit.id) else {
+                continue;
+            };
+            let text = self.searchable_text(&hit.id, node.size())?;
+            let snippet = search::snippet(&text, query).or_else(|| search::snippet(node.title(), query)).unwrap_or_default();
+            results.push(SearchResult { id: hit.id, title: node.title().to_string(), score: hit.score, snippet });
+        }
+        Ok(results)
+    }
+
+    // Up to `limit` changes after sequence number `since`, whether more follow, and the
+    // sequence number to continue from.
+    pub fn changes_since(&self, since: u64, limit: usize) -> Result<(Vec<ChangeRecord>, bool, u64)> {
+        let (page, has_more) = self.changelog.since(since, limit).ok_or_else(|| {
+            let msg = format!("changes before {} are no longer available", self.changelog.horizon);
+            RequestError::new(ERR_RESYNC_REQUIRED, msg)
+        })?;
+        let next = match page.last() {
+            Some(r) if has_more => r.seq,
+            _ => self.changelog.last_seq.max(since),
+        };
+        Ok((page, has_more, next))
+    }
+
+    // Announces a change to `id`, which must be in the tree, to the user's watchers.
+    fn publish(&mut self, id: &str, op: ChangeOp, revision: u64) -> Result<()> {
+        let path = self.root()?.path_to(id).ok_or_else(|| not_found(id))?;
+        self.emit(ChangeEvent { id: id.to_string(), op, revision, path });
+        Ok(())
+    }
+
+    fn emit(&mut self, event: ChangeEvent) {
+        match &mut self.batch {
+            Some(batch) => batch.events.push(event),
+            None => self.changes.publish(&self.user_id, event),
+        }
+    }
+
+    // Permanently deletes the trashed subtree rooted at `id`, or the whole trash if `None`.
+    pub fn purge_trash(&mut self, id: Option<&str>) -> Result<()> {
+        let purged: Vec<TrashEntry> = match id {
+            Some(id) => {
+                let idx = self.trash.iter().position(|e| e.subtree.id() == id).ok_or_else(|| not_found_in_trash(id))?;
+                vec![self.trash.remove(idx)]
+            },
+            None => std::mem::take(&mut self.trash),
+        };
+        self.save_trash()?;
+        self.delete_payloads(&purged)
+    }
+
+    // Applies the retention policy to the history of every blob. Returns how many
+    // revisions were deleted.
+    pub fn prune_history(&mut self) -> Result<usize> {
+        history::prune_user(&self.user_dir(), &self.options.history)
+    }
+
+    // Purges trash entries deleted more than `max_age` ago. Returns how many.
+    pub fn purge_expired_trash(&mut self, max_age: Duration) -> Result<usize> {
+        let cutoff = now_millis() - max_age.as_millis() as i64;
+        let (expired, kept): (Vec<TrashEntry>, Vec<TrashEntry>) = std::mem::take(&mut self.trash).into_iter().partition(|e| e.deleted_at < cutoff);
+        self.trash = kept;
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        self.save_trash()?;
+        self.delete_payloads(&expired)?;
+        Ok(expired.len())
+    }
+
+    // Re-encrypts every file of the user still encrypted with an older master key, or
+    // not at all. Returns how many files were rewritten.
+    pub fn reencrypt(&mut self) -> Result<usize> {
+        if self.files.keys.is_none() {
+            return Ok(0);
+        }
+        self.flush()?;
+        let mut dirs = vec![self.user_dir()];
+        let mut rewritten = 0;
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Result::Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for entry in entries {
+                let path = entry?.path();
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if (name.ends_with(".json") || name.ends_with(".bson")) && blobfile::reencrypt(&path, &self.files)? {
+                    rewritten += 1;
+                }
+            }
+        }
+        Ok(rewritten)
+    }
+
+    fn save_trash(&self) -> Result<()> {
+        fs::create_dir_all(self.user_dir())?;
+        trash::save(&self.trash_path(), &self.trash, &self.files)
+    }
+
+    fn delete_payloads(&mut self, entries: &[TrashEntry]) -> Result<()> {
+        for rid in entries.iter().flat_map(|e| e.subtree.ids()) {
+            self.payload_bytes = self.payload_bytes.saturating_sub(self.stored_len(&rid));
+            match fs::remove_file(self.blob_path(&rid)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+            history::remove_all(&self.user_dir(), &rid)?;
+        }
+        Ok(())
+    }
+
+    // Stored revisions of `id`, oldest first, ending with the current one.
+    pub fn list_revisions(&self, id: &str) -> Result<Vec<RevisionInfo>> {
+        let node = self.root()?.find(id).ok_or_else(|| not_found(id))?;
+        let mut revisions = history::list(&self.user_dir(), id)?;
+        revisions.push(RevisionInfo { revision: node.revision(), modified: node.modified(), size: node.size() });
+        Ok(revisions)
+    }
+
+    pub fn read_revision(&self, id: &str, revision: u64) -> Result<Bytes> {
+        let node = self.root()?.find(id).ok_or_else(|| not_found(id))?;
+        if node.revision() == revision {
+            return self.read_blob(id);
+        }
+        match blobfile::read(&history::revision_path(&self.user_dir(), id, revision), &self.files) {
+            Result::Ok(data) => Ok(Bytes::from(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(RequestError::new(ERR_NOT_FOUND, format!("blob {} has no revision {}", id, revision)).into())
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Writes the payload of an old revision back as a new revision. Returns that revision.
+    pub fn restore_revision(&mut self, id: &str, revision: u64) -> Result<u64> {
+        let data = self.read_revision(id, revision)?;
+        self.set(id, None, data)
+    }
+
+    fn write_blob(&mut self, id: &str, data: Payload) -> Result<()> {
+        let user_dir = self.user_dir();
+        fs::create_dir_all(&user_dir)?;
+        let path = match &mut self.batch {
+            Some(batch) => {
+                // Numbered, as one batch may write the same payload more than once.
+                let path = user_dir.join(format!("{}{}{}", id, BATCH_STAGING_INFIX, batch.written.len()));
+                batch.written.push(path.clone());
+                if let Some(replaced) = batch.staged.insert(id.to_string(), path.clone()) {
+                    // Unless it is still to go into the history on commit.
+                    if !batch.archived.iter().any(|(_, _, archived)| *archived == replaced) {
+                        let _ = fs::remove_file(replaced);
+                    }
+                }
+                path
+            },
+            None => self.blob_path(id),
+        };
+        match data {
+            Payload::Bytes(data) => blobfile::write(&path, &data, &self.files)?,
+            Payload::Staged { path: staged, .. } => fs::rename(staged, path)?,
+        }
+        Ok(())
+    }
+
+}
+
+fn not_found(id: &str) -> Error {
+    RequestError::new(ERR_NOT_FOUND, format!("blob {} not found", id)).into()
+}
+
+// Fails with `ERR_BAD_REQUEST` if any of `attrs` cannot be stored.
+fn check_attrs(attrs: &NodeAttrs) -> Result<()> {
+    let title = attrs.title.as_deref().and_then(check_title);
+    match title.or_else(|| attrs.content_type.as_deref().and_then(check_content_type)) {
+        Some(msg) => Err(RequestError::new(ERR_BAD_REQUEST, msg).into()),
+        None => Ok(()),
+    }
+}
+
+fn not_found_in_trash(id: &str) -> Error {
+    RequestError::new(ERR_NOT_FOUND, format!("blob {} is not in the trash", id)).into()
 }
 
 fn by_id_for_node(node: &BlobNode, id: &str) -> Option<BlobNode> {
@@ -47,7 +1176,6 @@ fn by_id_for_node(node: &BlobNode, id: &str) -> Option<BlobNode> {
                 return rv;
             }
         }
-        return None;
+        None
     }
 }
-
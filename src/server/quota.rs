@@ -0,0 +1,83 @@
+use anyhow::*;
+
+use crate::protocol::types::{RequestError, ERR_QUOTA_EXCEEDED};
+
+// Per-user storage limits. `None` means unlimited.
+#[derive(Debug, Clone, Default)]
+pub struct QuotaLimits {
+    // Sum of all payload sizes.
+    pub max_payload_bytes: Option<u64>,
+    pub max_nodes: Option<u64>,
+    // Depth of the deepest node, the root being at depth 0.
+    pub max_depth: Option<u32>,
+    // Size of any single payload.
+    pub max_blob_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StorageUsage {
+    pub payload_bytes: u64,
+    pub nodes: u64,
+    pub depth: u32,
+}
+
+impl QuotaLimits {
+    // Checks a write of a `blob_len` byte payload that moves a user from `before` to
+    // `after`. A limit only rejects writes that grow past it, so a user left over a
+    // lowered limit can still shrink their data.
+    pub fn check(&self, before: &StorageUsage, after: &StorageUsage, blob_len: u64) -> Result<()> {
+        if let Some(max) = self.max_blob_bytes {
+            if blob_len > max {
+                return Err(exceeded(format!("blob of {} bytes exceeds the {} byte limit", blob_len, max)));
+            }
+        }
+        if let Some(max) = self.max_payload_bytes {
+            if after.payload_bytes > max && after.payload_bytes > before.payload_bytes {
+                return Err(exceeded(format!("total payload limit of {} bytes reached", max)));
+            }
+        }
+        if let Some(max) = self.max_nodes {
+            if after.nodes > max && after.nodes > before.nodes {
+                return Err(exceeded(format!("node limit of {} reached", max)));
+            }
+        }
+        if let Some(max) = self.max_depth {
+            if after.depth > max && after.depth > before.depth {
+                return Err(exceeded(format!("tree depth limit of {} reached", max)));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn exceeded(description: String) -> Error {
+    RequestError::new(ERR_QUOTA_EXCEEDED, description).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code_of(res: Result<()>) -> u32 {
+        res.unwrap_err().downcast::<RequestError>().unwrap().code
+    }
+
+    #[test]
+    fn test_limits_reject_growth_only() {
+        let limits = QuotaLimits { max_payload_bytes: Some(100), max_nodes: Some(3), max_depth: Some(2), max_blob_bytes: Some(50) };
+        let before = StorageUsage { payload_bytes: 90, nodes: 3, depth: 2 };
+
+        let bigger = StorageUsage { payload_bytes: 110, ..before };
+        assert_eq!(code_of(limits.check(&before, &bigger, 20)), ERR_QUOTA_EXCEEDED);
+        assert_eq!(code_of(limits.check(&before, &before, 51)), ERR_QUOTA_EXCEEDED);
+        let more_nodes = StorageUsage { nodes: 4, ..before };
+        assert_eq!(code_of(limits.check(&before, &more_nodes, 0)), ERR_QUOTA_EXCEEDED);
+        let deeper = StorageUsage { depth: 3, ..before };
+        assert_eq!(code_of(limits.check(&before, &deeper, 0)), ERR_QUOTA_EXCEEDED);
+
+        // Already over a lowered limit, but shrinking.
+        let over = StorageUsage { payload_bytes: 150, ..before };
+        let smaller = StorageUsage { payload_bytes: 120, ..before };
+        assert!(limits.check(&over, &smaller, 10).is_ok());
+    }
+}
@@ -0,0 +1,431 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::*;
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::server::changes::ChangeHub;
+use crate::server::provider::{Provider, ProviderOptions};
+use crate::server::sharding::shard_index;
+use crate::storage::history::HISTORY_DIR_NAME;
+use crate::storage::trash::TRASH_FILE_NAME;
+
+// Holding the lock on a handle is holding that user's lock: every operation on a user's
+// tree goes through it.
+pub type ProviderHandle = Arc<AsyncMutex<Provider>>;
+
+struct Entry {
+    provider: ProviderHandle,
+    // Value of the registry clock when this entry was last handed out.
+    last_used: u64,
+    // Last known `Provider::approx_size`, refreshed whenever the provider is idle.
+    size: usize,
+}
+
+impl Entry {
+    // Only the registry holds a reference, so nobody is using or waiting on it.
+    fn is_idle(&self) -> bool {
+        Arc::strong_count(&self.provider) == 1
+    }
+}
+
+type Shard = Mutex<HashMap<String, Entry>>;
+
+// Server-wide cache of per-user providers. Users are spread over shards the same way as
+// `ShardedMutexKvStore`, and the total size of loaded trees is kept under `memory_budget`
+// by evicting the least recently used idle providers, flushing them first if dirty.
+pub struct ProviderRegistry {
+    data_dir: String,
+    options: Arc<ProviderOptions>,
+    changes: Arc<ChangeHub>,
+    shards: Vec<Shard>,
+    memory_budget: usize,
+    clock: AtomicU64,
+}
+
+impl ProviderRegistry {
+    pub fn new(data_dir: String, num_shards: usize, memory_budget: usize, options: ProviderOptions) -> ProviderRegistry {
+        let mut shards = Vec::with_capacity(num_shards);
+        for _ in 0..num_shards {
+            shards.push(Mutex::new(HashMap::new()));
+        }
+        ProviderRegistry {
+            data_dir,
+            options: Arc::new(options),
+            changes: Arc::new(ChangeHub::default()),
+            shards,
+            memory_budget,
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    pub fn data_dir(&self) -> &str {
+        &self.data_dir[..]
+    }
+
+    pub fn options(&self) -> &ProviderOptions {
+        &self.options
+    }
+
+    // Shared by all providers, so subscriptions survive the eviction of a user's provider.
+    pub fn changes(&self) -> &Arc<ChangeHub> {
+        &self.changes
+    }
+
+    fn shard(&self, user_id: &str) -> &Shard {
+        &self.shards[shard_index(user_id, self.shards.len())]
+    }
+
+    // Returns the provider for `user_id` with its tree loaded. Concurrent callers for the
+    // same user share one provider, and only the first of them loads the tree from disk.
+    pub async fn get(&self, user_id: &str) -> ProviderHandle {
+        let handle = {
+            let mut shard = self.shard(user_id).lock().unwrap();
+            let now = self.clock.fetch_add(1, Ordering::Relaxed);
+            let entry = shard.entry(user_id.to_string()).or_insert_with(|| Entry {
+                provider: Arc::new(AsyncMutex::new(Provider::with_options(
+                    self.data_dir.clone(),
+                    user_id.to_string(),
+                    self.options.clone(),
+                    self.changes.clone(),
+                ))),
+                last_used: now,
+                size: 0,
+            });
+            entry.last_used = now;
+            entry.provider.clone()
+        };
+
+        let size = {
+            let mut provider = handle.lock().await;
+            provider.cheeck_root_structure();
+            provider.approx_size()
+        };
+        if let Some(entry) = self.shard(user_id).lock().unwrap().get_mut(user_id) {
+            if Arc::ptr_eq(&entry.provider, &handle) {
+                entry.size = size;
+            }
+        }
+
+        self.evict_to_budget();
+        handle
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn resident_bytes(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().values().map(|e| e.size).sum::<usize>()).sum()
+    }
+
+    // Evicts idle providers, oldest first, until the loaded trees fit in the budget or
+    // everything left is in use.
+    pub fn evict_to_budget(&self) {
+        loop {
+            let mut total = 0;
+            let mut victim: Option<(u64, usize, String)> = None;
+            for (idx, shard) in self.shards.iter().enumerate() {
+                let mut shard = shard.lock().unwrap();
+                for (user_id, entry) in shard.iter_mut() {
+                    if let Result::Ok(provider) = entry.provider.try_lock() {
+                        entry.size = provider.approx_size();
+                    }
+                    total += entry.size;
+                    if entry.is_idle() && victim.as_ref().map(|v| entry.last_used < v.0).unwrap_or(true) {
+                        victim = Some((entry.last_used, idx, user_id.clone()));
+                    }
+                }
+            }
+            if total <= self.memory_budget {
+                return;
+            }
+            let Some((_, idx, user_id)) = victim else {
+                return;
+            };
+            if !self.try_evict(idx, &user_id) {
+                return;
+            }
+        }
+    }
+
+    // The shard stays locked while flushing, so a concurrent `get` for the same user
+    // cannot load the tree from disk before the flush has finished.
+    fn try_evict(&self, shard_idx: usize, user_id: &str) -> bool {
+        let mut shard = self.shards[shard_idx].lock().unwrap();
+        let Some(entry) = shard.get(user_id) else {
+            return true;
+        };
+        if !entry.is_idle() {
+            return false;
+        }
+        let Result::Ok(mut provider) = entry.provider.try_lock() else {
+            return false;
+        };
+        if let Err(e) = provider.flush() {
+            println!("not evicting {}: flush failed: {:?}", user_id, e);
+            return false;
+        }
+        drop(provider);
+        shard.remove(user_id);
+        true
+    }
+
+    // Purges trash older than `max_age` for every user on disk that has any. Goes through
+    // the providers, so it takes each user's lock like any other request.
+    pub async fn purge_expired_trash(&self, max_age: Duration) -> Result<usize> {
+        let mut users = vec![];
+        if let Result::Ok(entries) = std::fs::read_dir(&self.data_dir) {
+            for entry in entries {
+                let entry = entry?;
+                if entry.path().join(TRASH_FILE_NAME).is_file() {
+                    users.push(entry.file_name().to_string_lossy().to_string());
+                }
+            }
+        }
+        let mut purged = 0;
+        for user_id in users {
+            let handle = self.get(&user_id).await;
+            let mut provider = handle.lock().await;
+            // One failing user does not hold up the others.
+            match provider.purge_expired_trash(max_age) {
+                Result::Ok(n) => purged += n,
+                Err(e) => println!("cannot purge trash of {}: {:?}", user_id, e),
+            }
+        }
+        Ok(purged)
+    }
+
+    // Prunes old revisions for every user on disk that has any history, one user at a
+    // time under that user's lock, so it never races a write archiving a revision.
+    pub async fn prune_history(&self) -> Result<usize> {
+        let mut users = vec![];
+        if let Result::Ok(entries) = std::fs::read_dir(&self.data_dir) {
+            for entry in entries {
+                let entry = entry?;
+                if entry.path().join(HISTORY_DIR_NAME).is_dir() {
+                    users.push(entry.file_name().to_string_lossy().to_string());
+                }
+            }
+        }
+        let mut pruned = 0;
+        for user_id in users {
+            let handle = self.get(&user_id).await;
+            let mut provider = handle.lock().await;
+            // One failing user does not hold up the others.
+            match provider.prune_history() {
+                Result::Ok(n) => pruned += n,
+                Err(e) => println!("cannot prune history of {}: {:?}", user_id, e),
+            }
+        }
+        Ok(pruned)
+    }
+
+    // Re-encrypts the files of every user on disk that are not yet under the current
+    // master key, one user at a time under that user's lock. Returns how many files.
+    pub async fn rotate_keys(&self) -> Result<usize> {
+        if self.options.keyring.is_none() {
+            return Ok(0);
+        }
+        let mut users = vec![];
+        if let Result::Ok(entries) = std::fs::read_dir(&self.data_dir) {
+            for entry in entries {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    users.push(entry.file_name().to_string_lossy().to_string());
+                }
+            }
+        }
+        let mut rewritten = 0;
+        for user_id in users {
+            let handle = self.get(&user_id).await;
+            let mut provider = handle.lock().await;
+            // One unreadable user does not hold up the others.
+            match provider.reencrypt() {
+                Result::Ok(n) => rewritten += n,
+                Err(e) => println!("cannot re-encrypt files of {}: {:?}", user_id, e),
+            }
+        }
+        Ok(rewritten)
+    }
+
+    // Writes every dirty tree to disk, waiting for providers that are in use.
+    pub async fn flush_all(&self) -> Result<()> {
+        let handles: Vec<ProviderHandle> = self
+            .shards
+            .iter()
+            .flat_map(|s| s.lock().unwrap().values().map(|e| e.provider.clone()).collect::<Vec<_>>())
+            .collect();
+        for handle in handles {
+            handle.lock().await.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::crypto::Keyring;
+    use crate::storage::format::BlobNode;
+    use crate::storage::history::RetentionPolicy;
+
+    fn test_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("bearcub-registry-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.to_string_lossy().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_gets_share_provider() {
+        let registry = Arc::new(ProviderRegistry::new(test_dir("share"), 4, usize::MAX, ProviderOptions::default()));
+        let r1 = registry.clone();
+        let r2 = registry.clone();
+        let (a, b) = tokio::join!(
+            tokio::spawn(async move { r1.get("alice").await }),
+            tokio::spawn(async move { r2.get("alice").await })
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(a.lock().await.is_loaded());
+        assert_eq!(registry.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_eviction_flushes_dirty_tree() {
+        let dir = test_dir("evict");
+        let registry = ProviderRegistry::new(dir.clone(), 4, 1, ProviderOptions::default());
+
+        let alice = registry.get("alice").await;
+        {
+            let mut p = alice.lock().await;
+            let child = BlobNode::new("n1".to_string(), "notes".to_string(), vec![]);
+            p.set_root(BlobNode::new("alice".to_string(), "root".to_string(), vec![child]));
+        }
+        drop(alice);
+
+        // Over budget: fetching bob evicts alice, who is idle and least recently used.
+        let _bob = registry.get("bob").await;
+        assert_eq!(registry.len(), 1);
+        let flushed = std::path::Path::new(&dir).join("alice").join("blobs.bson");
+        assert!(flushed.exists());
+
+        let alice = registry.get("alice").await;
+        assert!(alice.lock().await.get_blob("n1").is_some());
+        // bob is still held by the test, so he cannot be evicted.
+        assert_eq!(registry.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_purge_expired_trash() {
+        let registry = ProviderRegistry::new(test_dir("trash"), 4, usize::MAX, ProviderOptions::default());
+        {
+            let alice = registry.get("alice").await;
+            let mut p = alice.lock().await;
+            p.put("n1", None, None, bytes::Bytes::from_static(b"x")).unwrap();
+            p.remove("n1").unwrap();
+        }
+        assert_eq!(registry.purge_expired_trash(Duration::from_secs(3600)).await.unwrap(), 0);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(registry.purge_expired_trash(Duration::ZERO).await.unwrap(), 1);
+        assert!(registry.get("alice").await.lock().await.trash().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_purge_expired_trash_skips_failing_users() {
+        let dir = test_dir("trash-skip");
+        let registry = ProviderRegistry::new(dir.clone(), 4, usize::MAX, ProviderOptions::default());
+        for user in ["alice", "bob"] {
+            let handle = registry.get(user).await;
+            let mut p = handle.lock().await;
+            p.put("n1", None, None, bytes::Bytes::from_static(b"x")).unwrap();
+            p.remove("n1").unwrap();
+        }
+        // A payload that is a non-empty directory cannot be deleted.
+        let payload = std::path::Path::new(&dir).join("bob").join("n1.json");
+        std::fs::remove_file(&payload).unwrap();
+        std::fs::create_dir_all(payload.join("x")).unwrap();
+
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(registry.purge_expired_trash(Duration::ZERO).await.unwrap(), 1);
+        assert!(registry.get("alice").await.lock().await.trash().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_prune_history_skips_failing_users() {
+        let dir = test_dir("prune");
+        {
+            let registry = ProviderRegistry::new(dir.clone(), 4, usize::MAX, ProviderOptions::default());
+            let alice = registry.get("alice").await;
+            let mut p = alice.lock().await;
+            p.put("n1", None, None, bytes::Bytes::from_static(b"1")).unwrap();
+            for v in [b"2", b"3", b"4"] {
+                p.set("n1", None, bytes::Bytes::from_static(v)).unwrap();
+            }
+            p.flush().unwrap();
+        }
+        // A history entry that is not a directory cannot be listed.
+        std::fs::create_dir_all(std::path::Path::new(&dir).join("bob").join(HISTORY_DIR_NAME)).unwrap();
+        std::fs::write(std::path::Path::new(&dir).join("bob").join(HISTORY_DIR_NAME).join("n1"), b"").unwrap();
+
+        let keep_one = ProviderOptions { history: RetentionPolicy { max_revisions: Some(1), max_age: None }, ..ProviderOptions::default() };
+        let registry = ProviderRegistry::new(dir, 4, usize::MAX, keep_one);
+        assert_eq!(registry.prune_history().await.unwrap(), 2);
+        let alice = registry.get("alice").await;
+        let revisions: Vec<u64> = alice.lock().await.list_revisions("n1").unwrap().iter().map(|r| r.revision).collect();
+        assert_eq!(revisions, vec![3, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_rotate_keys_reencrypts_everything() {
+        let dir = test_dir("rotate");
+        let keyring = |ids: &[u32]| Some(Arc::new(Keyring::new(ids.iter().map(|id| (*id, [*id as u8; 32]))).unwrap()));
+        let options = ProviderOptions { keyring: keyring(&[1]), ..Default::default() };
+        let registry = ProviderRegistry::new(dir.clone(), 4, usize::MAX, options);
+        {
+            let alice = registry.get("alice").await;
+            let mut p = alice.lock().await;
+            p.put("n1", None, None, bytes::Bytes::from_static(b"{\"password\":\"hunter2\"}")).unwrap();
+            p.put("n2", None, None, bytes::Bytes::from_static(b"{}")).unwrap();
+            p.set("n1", None, bytes::Bytes::from_static(b"{\"password\":\"hunter3\"}")).unwrap();
+            p.remove("n2").unwrap();
+            p.flush().unwrap();
+        }
+        let user_dir = std::path::Path::new(&dir).join("alice");
+        let mut files = vec![];
+        let mut dirs = vec![user_dir.clone()];
+        while let Some(d) = dirs.pop() {
+            for entry in std::fs::read_dir(d).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() { dirs.push(path) } else { files.push(path) }
+            }
+        }
+        // Tree, trash, change log, search index, two payloads and one old revision.
+        assert_eq!(files.len(), 7);
+        for path in &files {
+            let bytes = std::fs::read(path).unwrap();
+            assert!(!bytes.windows(6).any(|w| w == b"hunter" || w == b"passwo"), "{:?} is in plaintext", path);
+        }
+        assert_eq!(registry.rotate_keys().await.unwrap(), 0);
+
+        let options = ProviderOptions { keyring: keyring(&[1, 2]), ..Default::default() };
+        let registry = ProviderRegistry::new(dir.clone(), 4, usize::MAX, options);
+        assert_eq!(registry.rotate_keys().await.unwrap(), 7);
+        assert_eq!(registry.rotate_keys().await.unwrap(), 0);
+
+        // Only the new key is needed from now on.
+        let options = ProviderOptions { keyring: keyring(&[2]), ..Default::default() };
+        let registry = ProviderRegistry::new(dir.clone(), 4, usize::MAX, options);
+        let alice = registry.get("alice").await;
+        let p = alice.lock().await;
+        assert_eq!(&p.read_blob("n1").unwrap()[..], b"{\"password\":\"hunter3\"}");
+        assert_eq!(&p.read_revision("n1", 1).unwrap()[..], b"{\"password\":\"hunter2\"}");
+        assert_eq!(p.trash().len(), 1);
+    }
+}
@@ -4,8 +4,10 @@ use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 
+type Shard = Mutex<HashMap<String, Vec<u8>>>;
+
 pub struct ShardedMutexKvStore {
-    data: Arc<Vec<Mutex<HashMap<String, Vec<u8>>>>>,
+    data: Arc<Vec<Shard>>,
 }
 
 
@@ -19,14 +21,15 @@ impl ShardedMutexKvStore {
         ShardedMutexKvStore{data: Arc::new(db)}
     }
 
-    fn hash(&self, key:&str) -> usize {
-        let mut h = DefaultHasher::new();
-        key.hash(&mut h);
-        h.finish() as usize
-    }
-    
     pub fn get_shard(&self, user_id:String) -> usize {
-        self.hash(&user_id[..]) % self.data.len()
+        shard_index(&user_id[..], self.data.len())
     }
 }
 
+// Shared by every sharded structure so a given user always lands on the same shard index.
+pub fn shard_index(key: &str, num_shards: usize) -> usize {
+    let mut h = DefaultHasher::new();
+    key.hash(&mut h);
+    (h.finish() as usize) % num_shards
+}
+
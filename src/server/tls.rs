@@ -0,0 +1,209 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::*;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::server::connection::Connection;
+
+// Certificate files of the server side of TLS connections.
+#[derive(Debug, Clone)]
+pub struct ServerTlsFiles {
+    pub cert_file: String,
+    pub key_file: String,
+    // CA that client certificates must be signed by; `None` accepts clients without one.
+    pub client_ca_file: Option<String>,
+}
+
+// Certificate files of the client side of TLS connections.
+#[derive(Debug, Clone)]
+pub struct ClientTlsFiles {
+    // CA the server's certificate must be signed by.
+    pub ca_file: String,
+    // Certificate and key to present to servers that ask for one.
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+}
+
+// Always ring, whichever providers other crates in the build enable.
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+pub fn acceptor(files: &ServerTlsFiles) -> Result<TlsAcceptor> {
+    let builder = ServerConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+    let builder = match &files.client_ca_file {
+        Some(ca_file) => {
+            let roots = Arc::new(load_roots(Path::new(ca_file))?);
+            builder.with_client_cert_verifier(WebPkiClientVerifier::builder_with_provider(roots, provider()).build()?)
+        },
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(Path::new(&files.cert_file))?, load_key(Path::new(&files.key_file))?)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+pub fn connector(files: &ClientTlsFiles) -> Result<TlsConnector> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(load_roots(Path::new(&files.ca_file))?);
+    let config = match (&files.cert_file, &files.key_file) {
+        (Some(cert_file), Some(key_file)) => builder.with_client_auth_cert(load_certs(Path::new(cert_file))?, load_key(Path::new(key_file))?)?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => bail!("a client certificate needs both a certificate and a key file"),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+// Opens a TLS connection to `addr`, checking the server's certificate against `server_name`.
+pub async fn connect(connector: &TlsConnector, addr: &str, server_name: &str) -> Result<Connection<TlsStream<TcpStream>>> {
+    let name = ServerName::try_from(server_name.to_string()).with_context(|| format!("bad server name {:?}", server_name))?;
+    let socket = TcpStream::connect(addr).await?;
+    Ok(Connection::new(connector.connect(name, socket).await?))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("cannot read certificate file {:?}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).collect::<std::result::Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        bail!("no certificates in {:?}", path);
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("cannot read key file {:?}", path))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))?.ok_or_else(|| anyhow!("no private key in {:?}", path))
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ids::UserId;
+    use crate::protocol::types::{RequestMessage, ResponseMessage};
+    use crate::server::handler::{serve_connection, ConnectionOptions};
+    use crate::server::provider::ProviderOptions;
+    use crate::server::registry::ProviderRegistry;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use std::path::PathBuf;
+
+    const USER: &str = "2ab3da63-e24f-47e2-9b56-f3d19fade0cf";
+
+    // A CA, and a certificate for `names` signed by it, written as PEM files to `dir`.
+    struct TestCa {
+        cert: rcgen::Certificate,
+        key: KeyPair,
+        dir: PathBuf,
+    }
+
+    impl TestCa {
+        fn new(dir: &Path, name: &str) -> TestCa {
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let key = KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key).unwrap();
+            std::fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+            TestCa { cert, key, dir: dir.to_path_buf() }
+        }
+
+        fn file(&self, name: &str) -> String {
+            self.dir.join(name).to_string_lossy().to_string()
+        }
+
+        // Writes `<name>.pem` and `<name>.key`.
+        fn issue(&self, name: &str, names: &[&str]) {
+            let key = KeyPair::generate().unwrap();
+            let params = CertificateParams::new(names.iter().map(|n| n.to_string()).collect::<Vec<_>>()).unwrap();
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+            std::fs::write(self.dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+            std::fs::write(self.dir.join(format!("{}.key", name)), key.serialize_pem()).unwrap();
+        }
+    }
+
+    // Serves TLS connections from `acceptor` until the test ends; returns the address.
+    async fn serve(acceptor: TlsAcceptor, dir: &Path) -> String {
+        let registry = Arc::new(ProviderRegistry::new(dir.join("data").to_string_lossy().to_string(), 4, usize::MAX, ProviderOptions::default()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let (acceptor, registry) = (acceptor.clone(), registry.clone());
+                tokio::spawn(async move {
+                    if let Result::Ok(stream) = acceptor.accept(socket).await {
+                        serve_connection(Connection::new(stream), registry, Arc::new(ConnectionOptions::default())).await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let dir = std::env::temp_dir().join(format!("bearcub-tls-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca = TestCa::new(&dir, "ca");
+        ca.issue("server", &["localhost"]);
+        ca.issue("client", &["cubby"]);
+        let other_ca = TestCa::new(&dir, "other-ca");
+        other_ca.issue("stranger", &["cubby"]);
+
+        let server = ServerTlsFiles { cert_file: ca.file("server.pem"), key_file: ca.file("server.key"), client_ca_file: Some(ca.file("ca.pem")) };
+        let addr = serve(acceptor(&server).unwrap(), &dir).await;
+
+        let client = ClientTlsFiles { ca_file: ca.file("ca.pem"), cert_file: Some(ca.file("client.pem")), key_file: Some(ca.file("client.key")) };
+        let mut conn = connect(&connector(&client).unwrap(), &addr, "localhost").await.unwrap();
+        for f in (RequestMessage::Usage { user_id: UserId::parse(USER).unwrap() }).to_frames() {
+            conn.write_frame(&f).await.unwrap();
+        }
+        let usage = ResponseMessage::from_frames(vec![conn.read_frame().await.unwrap().unwrap()]).unwrap();
+        assert!(matches!(usage, ResponseMessage::Usage { .. }));
+
+        // The server is only trusted under its own name.
+        assert!(connect(&connector(&client).unwrap(), &addr, "bearcub.example").await.is_err());
+
+        // Without a certificate, or with one from another CA, the server hangs up.
+        for client in [
+            ClientTlsFiles { cert_file: None, key_file: None, ..client.clone() },
+            ClientTlsFiles { cert_file: Some(other_ca.file("stranger.pem")), key_file: Some(other_ca.file("stranger.key")), ..client.clone() },
+        ] {
+            let refused = match connect(&connector(&client).unwrap(), &addr, "localhost").await {
+                Result::Ok(mut conn) => {
+                    // TLS 1.3 clients finish before the server has checked their certificate.
+                    let _ = conn.write_frame(&(RequestMessage::Usage { user_id: UserId::parse(USER).unwrap() }).to_frames()[0]).await;
+                    conn.read_frame().await.is_err()
+                },
+                Err(_) => true,
+            };
+            assert!(refused);
+        }
+
+        // Without a client CA, clients do not need a certificate.
+        let server = ServerTlsFiles { client_ca_file: None, ..server };
+        let addr = serve(acceptor(&server).unwrap(), &dir).await;
+        let client = ClientTlsFiles { cert_file: None, key_file: None, ..client };
+        let mut conn = connect(&connector(&client).unwrap(), &addr, "localhost").await.unwrap();
+        assert_eq!(conn.negotiate(&[]).await.unwrap(), crate::protocol::compression::Codec::None);
+
+        assert!(acceptor(&ServerTlsFiles { cert_file: ca.file("missing.pem"), ..server }).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+
+use anyhow::*;
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::server::connection::Connection;
+
+// Listens on a Unix socket at `path` that only users allowed by `mode` (e.g. 0o660) can
+// connect to. A socket file left behind by a server that is no longer running is
+// replaced; anything else at `path` is an error.
+pub fn bind(path: &Path, mode: u32) -> Result<UnixListener> {
+    if let Result::Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            bail!("{:?} exists and is not a socket", path);
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            bail!("another server is listening on {:?}", path);
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path).with_context(|| format!("cannot listen on {:?}", path))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+pub async fn connect(path: &Path) -> Result<Connection<UnixStream>> {
+    let stream = UnixStream::connect(path).await.with_context(|| format!("cannot connect to {:?}", path))?;
+    Ok(Connection::new(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::protocol::ids::UserId;
+    use crate::protocol::types::{RequestMessage, ResponseMessage};
+    use crate::server::handler::{serve_connection, ConnectionOptions};
+    use crate::server::provider::ProviderOptions;
+    use crate::server::registry::ProviderRegistry;
+
+    #[tokio::test]
+    async fn test_unix_socket_listener() {
+        let dir = std::env::temp_dir().join(format!("bearcub-unix-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bearcub.sock");
+        let registry = Arc::new(ProviderRegistry::new(dir.join("data").to_string_lossy().to_string(), 4, usize::MAX, ProviderOptions::default()));
+
+        let listener = bind(&path, 0o600).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            serve_connection(Connection::new(stream), registry, Arc::new(ConnectionOptions::default())).await;
+        });
+
+        let mut conn = connect(&path).await.unwrap();
+        for f in (RequestMessage::Usage { user_id: UserId::parse("2ab3da63-e24f-47e2-9b56-f3d19fade0cf").unwrap() }).to_frames() {
+            conn.write_frame(&f).await.unwrap();
+        }
+        let usage = ResponseMessage::from_frames(vec![conn.read_frame().await.unwrap().unwrap()]).unwrap();
+        assert!(matches!(usage, ResponseMessage::Usage { .. }));
+        assert!(bind(&path, 0o600).is_err());
+        drop(conn);
+        server.await.unwrap();
+
+        // Once nothing listens, the leftover socket file is taken over; other files are not.
+        assert!(bind(&path, 0o660).is_ok());
+        fs::write(dir.join("not-a-socket"), b"").unwrap();
+        assert!(bind(&dir.join("not-a-socket"), 0o660).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
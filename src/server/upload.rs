@@ -0,0 +1,146 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::*;
+use bytes::Bytes;
+
+use crate::protocol::ids::{BlobId, UserId};
+use crate::protocol::types::{decode_write_header, RequestError, ResponseMessage, ERR_BAD_REQUEST, ERR_QUOTA_EXCEEDED};
+use crate::protocol::wire::Frame;
+use crate::server::provider::{staging_path, Payload};
+use crate::server::registry::ProviderRegistry;
+use crate::storage::blobfile::{BlobWriter, FileOptions};
+
+// A Put or Set spanning several frames, written to a staging file as its frames arrive
+// so a connection never holds more than one block of it in memory. The staging file is
+// compressed and encrypted like any payload file, and the user's lock is only taken once
+// the last frame is in, to install it.
+pub struct Upload {
+    msg_type_flag: u8,
+    // Where the payload goes, once the first frame has been decoded.
+    target: Option<WriteTarget>,
+    path: PathBuf,
+    files: FileOptions,
+    file: Option<BlobWriter>,
+    len: u64,
+    max_len: Option<u64>,
+    // The first thing that went wrong. The rest of the message is still read, and the
+    // error is the response.
+    error: Option<Error>,
+}
+
+struct WriteTarget {
+    user_id: UserId,
+    id: BlobId,
+    parent: Option<BlobId>,
+    expected_revision: Option<u64>,
+}
+
+impl Upload {
+    pub async fn start(registry: &ProviderRegistry, first: Frame) -> Upload {
+        let mut upload = Upload {
+            msg_type_flag: first.msg_type_flag,
+            target: None,
+            path: PathBuf::new(),
+            files: FileOptions::default(),
+            file: None,
+            len: 0,
+            max_len: registry.options().quota.max_blob_bytes,
+            error: None,
+        };
+        let mut body = first.data;
+        // Both ids are checked before the user id names a directory to stage in.
+        let target = UserId::parse(first.user_id.as_deref().unwrap_or_default()).and_then(|user_id| {
+            let (id, parent, expected_revision) = decode_write_header(&mut body)?;
+            Ok(WriteTarget { user_id, id, parent, expected_revision })
+        });
+        match target {
+            Result::Ok(target) => {
+                upload.path = staging_path(registry.data_dir(), &target.user_id);
+                upload.files = registry.options().file_options(&target.user_id);
+                upload.target = Some(target);
+            },
+            Err(e) => {
+                upload.error = Some(e);
+                return upload;
+            },
+        }
+        if let Err(e) = upload.open().await {
+            upload.error = Some(e);
+            return upload;
+        }
+        upload.append(b'd', body).await;
+        upload
+    }
+
+    async fn open(&mut self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        self.file = Some(BlobWriter::create(&self.path, &self.files)?);
+        Ok(())
+    }
+
+    // Writes the data of the next frame of the message.
+    pub async fn append(&mut self, msg_type_flag: u8, data: Bytes) {
+        if self.error.is_some() {
+            return;
+        }
+        if msg_type_flag != b'd' {
+            self.fail(RequestError::new(ERR_BAD_REQUEST, "expected continued data frame").into());
+            return;
+        }
+        self.len += data.len() as u64;
+        // Caught here too, so an oversized upload is not written out in full.
+        if let Some(max) = self.max_len {
+            if self.len > max {
+                self.fail(RequestError::new(ERR_QUOTA_EXCEEDED, format!("blob exceeds the {} byte limit", max)).into());
+                return;
+            }
+        }
+        if let Some(file) = &mut self.file {
+            if let Err(e) = file.write_all(&data[..]) {
+                self.fail(e.into());
+            }
+        }
+    }
+
+    fn fail(&mut self, e: Error) {
+        self.error = Some(e);
+        // Stop writing; the staging file is removed on drop.
+        self.file = None;
+    }
+
+    // Installs the staged payload once the last frame is in.
+    pub async fn finish(mut self, registry: &ProviderRegistry) -> ResponseMessage {
+        match self.install(registry).await {
+            Result::Ok(revision) => ResponseMessage::Written { revision },
+            Err(e) => e.into(),
+        }
+    }
+
+    async fn install(&mut self, registry: &ProviderRegistry) -> Result<u64> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        let file = self.file.take().ok_or_else(|| anyhow!("upload was not started"))?;
+        file.finish()?;
+
+        let target = self.target.as_ref().ok_or_else(|| anyhow!("upload was not started"))?;
+        let payload = Payload::Staged { path: self.path.clone(), len: self.len };
+        let handle = registry.get(&target.user_id).await;
+        let mut provider = handle.lock().await;
+        if self.msg_type_flag == b'p' {
+            provider.put(&target.id, target.parent.as_deref(), target.expected_revision, payload)
+        } else {
+            provider.set(&target.id, target.expected_revision, payload)
+        }
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        // Gone already if it was installed, or never created.
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,488 @@
+use std::fs;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use anyhow::*;
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::protocol::compression::Codec;
+use crate::storage::crypto::{random_bytes, UserKey, UserKeys, NONCE_BYTES, TAG_BYTES};
+
+// Files written with a codec start with `MAGIC`, the codec (u8) and the payload length
+// (u64). Encrypted files start with `SEALED_MAGIC`, the codec, the id of the master key
+// (u32), a random file id (16 bytes) and the payload length. Files with neither are the
+// raw payload, as they always were.
+const MAGIC: &[u8; 4] = b"\0BCB";
+const SEALED_MAGIC: &[u8; 4] = b"\0BCE";
+const HEADER_SZ: usize = 4 + 1 + 8;
+const SEALED_HEADER_SZ: usize = 4 + 1 + 4 + 16 + 8;
+
+// Payload bytes compressed together. Each block is stored as its raw length (u32), its
+// stored length (u32) and the stored bytes. A block that did not shrink is stored raw.
+// In an encrypted file the stored bytes are sealed, authenticating the header, the
+// block's index and raw length, and whether it is the last block, so blocks cannot be
+// dropped, reordered or moved between files. An encrypted file always has a last block,
+// empty if the payload is.
+const BLOCK_BYTES: usize = 64 * 1024;
+
+// How files are written: compressed with `codec`, and encrypted with the current key of
+// `keys` if set. Reading needs `keys` for encrypted files only.
+#[derive(Debug, Clone, Default)]
+pub struct FileOptions {
+    pub codec: Codec,
+    pub keys: Option<UserKeys>,
+}
+
+// Writes `data` to `path` through a temporary file, so a crash never leaves a
+// half-written file behind.
+pub fn write(path: &Path, data: &[u8], options: &FileOptions) -> Result<()> {
+    let tmp = tmp_path(path);
+    let mut out = BlobWriter::create(&tmp, options)?;
+    out.write_all(data)?;
+    out.finish()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// Reads the whole payload at `path`, whichever codec and key it was written with.
+pub fn read(path: &Path, options: &FileOptions) -> io::Result<Vec<u8>> {
+    let mut reader = BlobReader::open(path, options)?;
+    let mut out = Vec::with_capacity(reader.len() as usize);
+    reader.read_to_end(&mut out)?;
+    io::Result::Ok(out)
+}
+
+// Length of the payload at `path`, which is not the file size if it is compressed or
+// encrypted. Only reads the header, so it needs no key.
+pub fn stored_len(path: &Path) -> io::Result<u64> {
+    let file = fs::File::open(path)?;
+    let file_len = file.metadata()?.len();
+    match read_header(&mut io::BufReader::new(file))? {
+        (Some(header), _) => io::Result::Ok(header.len),
+        (None, _) => io::Result::Ok(file_len),
+    }
+}
+
+// Writes the file at `path` again with `options` unless it is already encrypted with
+// their current key. Keeps the modification time, which history retention goes by.
+// Returns whether the file was rewritten.
+pub fn reencrypt(path: &Path, options: &FileOptions) -> Result<bool> {
+    let current = options.keys.as_ref().map(|k| k.current_id());
+    let mut reader = BlobReader::open(path, options)?;
+    if reader.key_id() == current {
+        return Ok(false);
+    }
+    let modified = fs::metadata(path)?.modified()?;
+    let tmp = tmp_path(path);
+    let mut out = BlobWriter::create(&tmp, options)?;
+    io::copy(&mut reader, &mut out)?;
+    out.finish()?;
+    fs::File::options().write(true).open(&tmp)?.set_modified(modified)?;
+    fs::rename(&tmp, path)?;
+    Ok(true)
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+struct Header {
+    codec: Codec,
+    key_id: Option<u32>,
+    len: u64,
+    // What each sealed block authenticates besides itself.
+    aad: Vec<u8>,
+}
+
+// Returns the header, or `None` and the bytes read so far for a raw file.
+fn read_header(file: &mut impl Read) -> io::Result<(Option<Header>, Vec<u8>)> {
+    let mut magic = [0_u8; 4];
+    let n = read_full(file, &mut magic)?;
+    let size = match &magic {
+        m if n == 4 && m == MAGIC => HEADER_SZ,
+        m if n == 4 && m == SEALED_MAGIC => SEALED_HEADER_SZ,
+        _ => return io::Result::Ok((None, magic[..n].to_vec())),
+    };
+    let mut header = magic.to_vec();
+    header.resize(size, 0);
+    file.read_exact(&mut header[4..])?;
+    let codec = Codec::from_u8(header[4]).ok_or_else(|| invalid(format!("unknown codec {}", header[4])))?;
+    let key_id = (size == SEALED_HEADER_SZ).then(|| u32::from_be_bytes(header[5..9].try_into().unwrap()));
+    let len = u64::from_be_bytes(header[size - 8..].try_into().unwrap());
+    header.truncate(size - 8);
+    io::Result::Ok((Some(Header { codec, key_id, len, aad: header }), vec![]))
+}
+
+fn block_aad(header: &[u8], index: u64, last: bool, raw_len: u32) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad.push(last as u8);
+    aad.extend_from_slice(&raw_len.to_be_bytes());
+    aad
+}
+
+// Like `read_exact`, but stops early at the end of the file. Returns the bytes read.
+fn read_full(src: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match src.read(&mut buf[n..])? {
+            0 => break,
+            read => n += read,
+        }
+    }
+    io::Result::Ok(n)
+}
+
+// Writes a file in the format chosen by its `FileOptions`, a block at a time, so a payload
+// of any size can be streamed into it. Nothing is complete until `finish`.
+pub struct BlobWriter {
+    out: io::BufWriter<fs::File>,
+    codec: Codec,
+    key: Option<UserKey>,
+    // Set once the first block decides whether the file has a header.
+    header: Option<Vec<u8>>,
+    raw: bool,
+    block: Vec<u8>,
+    index: u64,
+    len: u64,
+}
+
+impl BlobWriter {
+    pub fn create(path: &Path, options: &FileOptions) -> Result<BlobWriter> {
+        let key = match &options.keys {
+            Some(keys) => Some(keys.current()?),
+            None => None,
+        };
+        Ok(BlobWriter {
+            out: io::BufWriter::new(fs::File::create(path)?),
+            codec: options.codec,
+            key,
+            header: None,
+            raw: false,
+            block: Vec::with_capacity(BLOCK_BYTES),
+            index: 0,
+            len: 0,
+        })
+    }
+
+    // Writes the header, with the length left to `finish`. A raw payload only gets one if
+    // it starts like a file that has one.
+    fn start(&mut self) -> io::Result<()> {
+        let header = match &self.key {
+            Some(key) => {
+                let mut header = SEALED_MAGIC.to_vec();
+                header.push(self.codec as u8);
+                header.extend_from_slice(&key.id().to_be_bytes());
+                header.extend_from_slice(&random_bytes::<16>());
+                header
+            },
+            None if self.codec != Codec::None || self.block.starts_with(MAGIC) || self.block.starts_with(SEALED_MAGIC) => {
+                let mut header = MAGIC.to_vec();
+                header.push(self.codec as u8);
+                header
+            },
+            None => {
+                self.raw = true;
+                self.header = Some(vec![]);
+                return io::Result::Ok(());
+            },
+        };
+        self.out.write_all(&header)?;
+        self.out.write_all(&0_u64.to_be_bytes())?;
+        self.header = Some(header);
+        io::Result::Ok(())
+    }
+
+    fn write_block(&mut self, last: bool) -> io::Result<()> {
+        if self.header.is_none() {
+            self.start()?;
+        }
+        if self.raw {
+            self.out.write_all(&self.block)?;
+            self.block.clear();
+            return io::Result::Ok(());
+        }
+        let raw_len = self.block.len() as u32;
+        let packed = self.codec.compress(&self.block).map_err(|e| invalid(e.to_string()))?;
+        let inner = if packed.len() < self.block.len() { &packed[..] } else { &self.block[..] };
+        let sealed;
+        let stored = match &self.key {
+            Some(key) => {
+                let aad = block_aad(self.header.as_deref().unwrap_or_default(), self.index, last, raw_len);
+                sealed = key.seal(inner, &aad).map_err(|e| invalid(e.to_string()))?;
+                &sealed[..]
+            },
+            None => inner,
+        };
+        self.out.write_all(&raw_len.to_be_bytes())?;
+        self.out.write_all(&(stored.len() as u32).to_be_bytes())?;
+        self.out.write_all(stored)?;
+        self.index += 1;
+        self.block.clear();
+        io::Result::Ok(())
+    }
+
+    // Writes what is left, fills in the length and syncs the file. Returns the length of
+    // the payload.
+    pub fn finish(mut self) -> Result<u64> {
+        if self.header.is_none() || !self.block.is_empty() || (self.key.is_some() && self.index == 0) {
+            self.write_block(true)?;
+        }
+        if let Some(header) = &self.header {
+            if !self.raw {
+                self.out.seek(SeekFrom::Start(header.len() as u64))?;
+                self.out.write_all(&self.len.to_be_bytes())?;
+            }
+        }
+        let file = self.out.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        Ok(self.len)
+    }
+}
+
+impl Write for BlobWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            // A full block is only written once more data follows, as the last block is
+            // sealed differently.
+            if self.block.len() == BLOCK_BYTES {
+                self.write_block(false)?;
+            }
+            let n = rest.len().min(BLOCK_BYTES - self.block.len());
+            self.block.extend_from_slice(&rest[..n]);
+            rest = &rest[n..];
+        }
+        self.len += buf.len() as u64;
+        io::Result::Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::Result::Ok(())
+    }
+}
+
+// Reads the payload of a file written by `BlobWriter`, or of a raw file, decrypting and
+// decompressing one block at a time.
+pub struct BlobReader {
+    file: io::BufReader<fs::File>,
+    // `None` for a raw file.
+    header: Option<Header>,
+    key: Option<UserKey>,
+    len: u64,
+    // The block being read, and how much of it has been returned.
+    block: Vec<u8>,
+    pos: usize,
+    index: u64,
+    read: u64,
+}
+
+impl BlobReader {
+    pub fn open(path: &Path, options: &FileOptions) -> io::Result<BlobReader> {
+        let file = fs::File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut file = io::BufReader::new(file);
+        let (header, start) = read_header(&mut file)?;
+        let key = match (&header, &options.keys) {
+            (Some(Header { key_id: Some(id), .. }), Some(keys)) => Some(keys.get(*id).map_err(|e| invalid(e.to_string()))?),
+            (Some(Header { key_id: Some(_), .. }), None) => return Err(invalid(format!("{:?} is encrypted and no key file is configured", path))),
+            _ => None,
+        };
+        let len = header.as_ref().map(|h| h.len).unwrap_or(file_len);
+        // The raw file's first bytes are handed out before the rest of the file.
+        io::Result::Ok(BlobReader { file, header, key, len, block: start, pos: 0, index: 0, read: 0 })
+    }
+
+    // Length of the payload, not of the file.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Id of the master key the file is encrypted with, if it is.
+    pub fn key_id(&self) -> Option<u32> {
+        self.header.as_ref().and_then(|h| h.key_id)
+    }
+
+    // Loads the next block; `false` at the end of the file.
+    fn next_block(&mut self) -> io::Result<bool> {
+        let Some(header) = &self.header else {
+            return io::Result::Ok(false);
+        };
+        let mut lens = [0_u8; 8];
+        if read_full(&mut self.file, &mut lens)? == 0 {
+            if self.read != header.len || (self.key.is_some() && self.index == 0) {
+                return Err(invalid("payload file is truncated".to_string()));
+            }
+            return io::Result::Ok(false);
+        }
+        let raw_len = u32::from_be_bytes(lens[..4].try_into().unwrap());
+        let stored_len = u32::from_be_bytes(lens[4..].try_into().unwrap()) as usize;
+        let overhead = if self.key.is_some() { NONCE_BYTES + TAG_BYTES } else { 0 };
+        if raw_len as usize > BLOCK_BYTES || stored_len > raw_len as usize + overhead {
+            return Err(invalid("corrupt payload block".to_string()));
+        }
+        let mut stored = vec![0_u8; stored_len];
+        self.file.read_exact(&mut stored)?;
+        let inner = match &self.key {
+            Some(key) => {
+                let last = self.file.fill_buf()?.is_empty();
+                let aad = block_aad(&header.aad, self.index, last, raw_len);
+                key.open(&stored, &aad).map_err(|e| invalid(e.to_string()))?
+            },
+            None => stored,
+        };
+        self.block = if inner.len() == raw_len as usize {
+            inner
+        } else {
+            header.codec.decompress(&inner, raw_len as usize).map_err(|e| invalid(e.to_string()))?
+        };
+        self.pos = 0;
+        self.index += 1;
+        self.read += raw_len as u64;
+        io::Result::Ok(true)
+    }
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.block.len() {
+            if self.header.is_none() {
+                return self.file.read(buf);
+            }
+            if !self.next_block()? {
+                return io::Result::Ok(0);
+            }
+        }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        io::Result::Ok(n)
+    }
+}
+
+// Lets a payload be streamed to a connection. Reads are of local files, one block at a
+// time, so they are done inline.
+impl AsyncRead for BlobReader {
+    fn poll_read(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let n = self.get_mut().read(buf.initialize_unfilled())?;
+        buf.advance(n);
+        Poll::Ready(io::Result::Ok(()))
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::crypto::Keyring;
+    use std::sync::Arc;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bearcub-blobfile-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn keys(ids: &[u32]) -> UserKeys {
+        let keyring = Keyring::new(ids.iter().map(|id| (*id, [*id as u8; 32]))).unwrap();
+        UserKeys::new(Arc::new(keyring), "alice")
+    }
+
+    #[test]
+    fn test_codecs_roundtrip_and_mix() {
+        let dir = test_dir("codecs");
+        let json = "{\"title\":\"groceries\",\"items\":[\"milk\",\"eggs\"]},".repeat(5000).into_bytes();
+
+        for (name, codec) in [("none", Codec::None), ("lz4", Codec::Lz4), ("zstd", Codec::Zstd)] {
+            let path = dir.join(format!("{}.json", name));
+            let options = FileOptions { codec, keys: None };
+            write(&path, &json, &options).unwrap();
+            assert_eq!(read(&path, &FileOptions::default()).unwrap(), json);
+            assert_eq!(stored_len(&path).unwrap(), json.len() as u64);
+            if codec == Codec::None {
+                // Raw, as before compression existed.
+                assert_eq!(fs::read(&path).unwrap(), json);
+            } else {
+                assert!(fs::metadata(&path).unwrap().len() < json.len() as u64 / 4);
+            }
+        }
+
+        // A raw payload that looks like a header is wrapped, so it reads back as written.
+        let tricky = [&MAGIC[..], &[2, 0, 0, 0, 0, 0, 0, 0, 9, 1]].concat();
+        let path = dir.join("tricky.json");
+        write(&path, &tricky, &FileOptions::default()).unwrap();
+        assert_eq!(read(&path, &FileOptions::default()).unwrap(), tricky);
+        write(&path, b"", &FileOptions::default()).unwrap();
+        assert_eq!(read(&path, &FileOptions::default()).unwrap(), b"");
+        assert_eq!(read(&dir.join("missing.json"), &FileOptions::default()).map_err(|e| e.kind()), Err(io::ErrorKind::NotFound));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted_files_are_authenticated() {
+        let dir = test_dir("sealed");
+        let options = FileOptions { codec: Codec::Zstd, keys: Some(keys(&[1])) };
+        let notes = "{\"title\":\"passwords\",\"bank\":\"hunter2\"},".repeat(5000).into_bytes();
+        let path = dir.join("notes.json");
+        write(&path, &notes, &options).unwrap();
+        let on_disk = fs::read(&path).unwrap();
+        assert!(!on_disk.windows(7).any(|w| w == b"hunter2"));
+        assert_eq!(read(&path, &options).unwrap(), notes);
+        assert_eq!(stored_len(&path).unwrap(), notes.len() as u64);
+        assert!(read(&path, &FileOptions::default()).is_err());
+        let other_user = FileOptions { keys: Some(UserKeys::new(Arc::new(Keyring::new([(1, [1; 32])]).unwrap()), "bob")), ..options.clone() };
+        assert!(read(&path, &other_user).is_err());
+
+        // Flipped bits, and a missing last block, are both caught.
+        let mut flipped = on_disk.clone();
+        flipped[SEALED_HEADER_SZ + 20] ^= 1;
+        fs::write(&path, &flipped).unwrap();
+        assert!(read(&path, &options).is_err());
+        let plain = "x".repeat(BLOCK_BYTES * 2).into_bytes();
+        write(&path, &plain, &FileOptions { codec: Codec::None, ..options.clone() }).unwrap();
+        let whole = fs::read(&path).unwrap();
+        fs::write(&path, &whole[..SEALED_HEADER_SZ + 8 + BLOCK_BYTES + NONCE_BYTES + TAG_BYTES]).unwrap();
+        assert!(read(&path, &options).is_err());
+
+        // Empty payloads still get a sealed block.
+        write(&path, b"", &options).unwrap();
+        assert_eq!(read(&path, &options).unwrap(), b"");
+        fs::write(&path, &fs::read(&path).unwrap()[..SEALED_HEADER_SZ]).unwrap();
+        assert!(read(&path, &options).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reencrypt_moves_files_to_the_current_key() {
+        let dir = test_dir("rotate");
+        let path = dir.join("notes.json");
+        write(&path, b"{\"a\":1}", &FileOptions::default()).unwrap();
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+
+        let old = FileOptions { codec: Codec::None, keys: Some(keys(&[1])) };
+        assert!(reencrypt(&path, &old).unwrap());
+        assert!(!reencrypt(&path, &old).unwrap());
+        let new = FileOptions { codec: Codec::Lz4, keys: Some(keys(&[1, 2])) };
+        assert!(reencrypt(&path, &new).unwrap());
+        assert_eq!(BlobReader::open(&path, &new).unwrap().key_id(), Some(2));
+        assert_eq!(read(&path, &new).unwrap(), b"{\"a\":1}");
+        assert!(read(&path, &old).is_err());
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), modified);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
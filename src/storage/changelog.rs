@@ -0,0 +1,167 @@
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::*;
+use bson::{doc, Document};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::blobfile::{self, FileOptions};
+use crate::storage::format::now_millis;
+use crate::storage::schema::CURRENT_SCHEMA_VERSION;
+
+// Latest change of every blob of a user, stored as <user id>/changes.bson. Each mutation
+// takes the next number of a per-user sequence, so clients can ask for everything after
+// the last number they saw.
+pub const CHANGELOG_FILE_NAME: &str = "changes.bson";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Moved,
+    // A tombstone: the blob was removed.
+    Deleted,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChangeRecord {
+    pub id: String,
+    pub seq: u64,
+    // Sequence number at which the blob was (last) added, to tell a client whether it
+    // has seen the blob at all.
+    pub created_seq: u64,
+    pub kind: ChangeKind,
+    // ms since the Unix epoch.
+    pub at: i64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChangeLog {
+    pub last_seq: u64,
+    // Tombstones up to this sequence number may have been pruned, so a client that last
+    // synced before it has to start over.
+    pub horizon: u64,
+    // One record per blob, ordered by `seq`.
+    records: Vec<ChangeRecord>,
+}
+
+impl ChangeLog {
+    // Records a change to `id` under the next sequence number, which is returned.
+    pub fn record(&mut self, id: &str, kind: ChangeKind) -> u64 {
+        self.last_seq += 1;
+        let seq = self.last_seq;
+        let previous = self.records.iter().position(|r| r.id == id).map(|idx| self.records.remove(idx));
+        let created_seq = match (kind, previous) {
+            (ChangeKind::Added, _) | (_, None) => seq,
+            (_, Some(p)) => p.created_seq,
+        };
+        self.records.push(ChangeRecord { id: id.to_string(), seq, created_seq, kind, at: now_millis() });
+        seq
+    }
+
+    // Changes after `since`, oldest first, at most `limit` of them, and whether more
+    // follow. A blob added after `since` is reported as added whatever happened to it
+    // next, unless it is gone again. `None` if `since` is before the horizon.
+    pub fn since(&self, since: u64, limit: usize) -> Option<(Vec<ChangeRecord>, bool)> {
+        if since < self.horizon {
+            return None;
+        }
+        let start = self.records.partition_point(|r| r.seq <= since);
+        let page: Vec<ChangeRecord> = self.records[start..]
+            .iter()
+            .take(limit)
+            .map(|r| {
+                let mut r = r.clone();
+                if r.kind != ChangeKind::Deleted && r.created_seq > since {
+                    r.kind = ChangeKind::Added;
+                }
+                r
+            })
+            .collect();
+        let has_more = self.records.len() - start > page.len();
+        Some((page, has_more))
+    }
+
+    // Forgets tombstones older than `max_age`, moving the horizon past them. Returns how
+    // many were dropped.
+    pub fn prune_tombstones(&mut self, max_age: Duration) -> usize {
+        let cutoff = now_millis() - max_age.as_millis() as i64;
+        let before = self.records.len();
+        let mut horizon = self.horizon;
+        self.records.retain(|r| {
+            let expired = r.kind == ChangeKind::Deleted && r.at < cutoff;
+            if expired {
+                horizon = horizon.max(r.seq);
+            }
+            !expired
+        });
+        self.horizon = horizon;
+        before - self.records.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn approx_size(&self) -> usize {
+        self.records.iter().map(|r| std::mem::size_of::<ChangeRecord>() + r.id.len()).sum()
+    }
+}
+
+// A missing file is an empty log: the user has not changed anything since change
+// tracking was added.
+pub fn load(path: &Path, files: &FileOptions) -> Result<ChangeLog> {
+    if !path.exists() {
+        return Ok(ChangeLog::default());
+    }
+    let doc: Document = bson::from_slice(&blobfile::read(path, files)?)?;
+    doc.get_i32("schema_version").with_context(|| "change log without schema_version")?;
+    Ok(bson::from_document(doc.get_document("log")?.clone())?)
+}
+
+pub fn save(path: &Path, log: &ChangeLog, files: &FileOptions) -> Result<()> {
+    let doc = doc! { "schema_version": CURRENT_SCHEMA_VERSION as i32, "log": bson::to_bson(log)? };
+    blobfile::write(path, &bson::to_vec(&doc)?, files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_since_pages_and_tombstones() {
+        let mut log = ChangeLog::default();
+        log.record("a", ChangeKind::Added);
+        log.record("b", ChangeKind::Added);
+        let synced = log.last_seq;
+        log.record("a", ChangeKind::Modified);
+        log.record("c", ChangeKind::Added);
+        log.record("c", ChangeKind::Modified);
+        log.record("b", ChangeKind::Deleted);
+
+        let (page, has_more) = log.since(synced, 2).unwrap();
+        let got: Vec<(&str, ChangeKind)> = page.iter().map(|r| (r.id.as_str(), r.kind)).collect();
+        assert_eq!(got, vec![("a", ChangeKind::Modified), ("c", ChangeKind::Added)]);
+        assert!(has_more);
+        let (page, has_more) = log.since(page[1].seq, 2).unwrap();
+        assert_eq!((page[0].id.as_str(), page[0].kind, has_more), ("b", ChangeKind::Deleted, false));
+
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(log.prune_tombstones(Duration::ZERO), 1);
+        assert_eq!(log.horizon, 6);
+        assert!(log.since(synced, 10).is_none());
+        assert!(log.since(6, 10).unwrap().0.is_empty());
+
+        let path = std::env::temp_dir().join(format!("bearcub-changes-{}.bson", std::process::id()));
+        save(&path, &log, &FileOptions::default()).unwrap();
+        let loaded = load(&path, &FileOptions::default()).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!((loaded.last_seq, loaded.horizon, loaded.len()), (6, 6, 2));
+    }
+}
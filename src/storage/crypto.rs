@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::*;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, OsRng, Payload};
+use chacha20poly1305::{AeadCore, KeyInit, XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+pub const KEY_BYTES: usize = 32;
+pub const NONCE_BYTES: usize = 24;
+pub const TAG_BYTES: usize = 16;
+
+// Salt of the per-user key derivation. Changing it makes every encrypted file unreadable.
+const USER_KEY_SALT: &[u8] = b"bearcub user key";
+
+// Master keys, read from a key file with one `<key id> <64 hex digits>` line per key.
+// New files are encrypted with keys derived from the highest id; the others are kept to
+// read files that have not been re-encrypted yet.
+pub struct Keyring {
+    masters: BTreeMap<u32, [u8; KEY_BYTES]>,
+}
+
+impl std::fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keyring").field("key_ids", &self.masters.keys().collect::<Vec<_>>()).finish()
+    }
+}
+
+impl Keyring {
+    pub fn new(keys: impl IntoIterator<Item = (u32, [u8; KEY_BYTES])>) -> Result<Keyring> {
+        let masters: BTreeMap<_, _> = keys.into_iter().collect();
+        if masters.is_empty() {
+            bail!("a keyring needs at least one key");
+        }
+        Ok(Keyring { masters })
+    }
+
+    pub fn load(path: &Path) -> Result<Keyring> {
+        let text = fs::read_to_string(path).with_context(|| format!("cannot read key file {:?}", path))?;
+        let mut keys = vec![];
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = line.split_once(' ').and_then(|(id, key)| Some((id.parse().ok()?, decode_hex(key.trim())?)));
+            let Some(key) = parsed else {
+                bail!("{:?} line {}: expected `<key id> <64 hex digits>`", path, n + 1);
+            };
+            keys.push(key);
+        }
+        Keyring::new(keys)
+    }
+
+    // Adds a fresh random key to the key file at `path`, creating it if needed, and returns
+    // its id. It becomes the current key the next time the file is loaded.
+    pub fn generate(path: &Path) -> Result<u32> {
+        let id = if path.exists() { Keyring::load(path)?.current_id() + 1 } else { 1 };
+        let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+        let mut options = fs::OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        writeln!(file, "{} {}", id, encode_hex(&key))?;
+        file.sync_all()?;
+        Ok(id)
+    }
+
+    pub fn current_id(&self) -> u32 {
+        *self.masters.keys().next_back().unwrap()
+    }
+
+    // The key of `user_id` derived from master key `key_id`.
+    pub fn user_key(&self, user_id: &str, key_id: u32) -> Result<UserKey> {
+        let master = self.masters.get(&key_id).ok_or_else(|| anyhow!("key {} is not in the keyring", key_id))?;
+        let mut key = [0_u8; KEY_BYTES];
+        Hkdf::<Sha256>::new(Some(USER_KEY_SALT), master)
+            .expand(user_id.as_bytes(), &mut key)
+            .map_err(|_| anyhow!("key derivation failed"))?;
+        Ok(UserKey { id: key_id, cipher: XChaCha20Poly1305::new(&key.into()) })
+    }
+}
+
+// The keys of one user, for every master key in the keyring.
+#[derive(Debug, Clone)]
+pub struct UserKeys {
+    keyring: Arc<Keyring>,
+    user_id: String,
+}
+
+impl UserKeys {
+    pub fn new(keyring: Arc<Keyring>, user_id: &str) -> UserKeys {
+        UserKeys { keyring, user_id: user_id.to_string() }
+    }
+
+    pub fn current_id(&self) -> u32 {
+        self.keyring.current_id()
+    }
+
+    pub fn current(&self) -> Result<UserKey> {
+        self.get(self.current_id())
+    }
+
+    pub fn get(&self, key_id: u32) -> Result<UserKey> {
+        self.keyring.user_key(&self.user_id, key_id)
+    }
+}
+
+pub struct UserKey {
+    id: u32,
+    cipher: XChaCha20Poly1305,
+}
+
+impl UserKey {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    // Encrypts `plain` under a random nonce, authenticating `aad` along with it. The
+    // output is the nonce followed by the ciphertext and tag.
+    pub fn seal(&self, plain: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self.cipher.encrypt(&nonce, Payload { msg: plain, aad }).map_err(|_| anyhow!("encryption failed"))?;
+        let mut out = Vec::with_capacity(NONCE_BYTES + sealed.len());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&sealed);
+        Ok(out)
+    }
+
+    // Inverse of `seal`. Fails if the data or `aad` is not what was sealed.
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_BYTES + TAG_BYTES {
+            bail!("sealed data too short");
+        }
+        let (nonce, msg) = sealed.split_at(NONCE_BYTES);
+        self.cipher.decrypt(XNonce::from_slice(nonce), Payload { msg, aad }).map_err(|_| anyhow!("decryption failed: wrong key or corrupt data"))
+    }
+}
+
+// Random bytes, for identifiers that must not repeat.
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut out = [0_u8; N];
+    OsRng.fill_bytes(&mut out);
+    out
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<[u8; KEY_BYTES]> {
+    if hex.len() != KEY_BYTES * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut out = [0_u8; KEY_BYTES];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_file_and_per_user_keys() {
+        let path = std::env::temp_dir().join(format!("bearcub-keys-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert_eq!(Keyring::generate(&path).unwrap(), 1);
+        assert_eq!(Keyring::generate(&path).unwrap(), 2);
+        let keyring = Keyring::load(&path).unwrap();
+        assert_eq!(keyring.current_id(), 2);
+
+        let alice = keyring.user_key("alice", 1).unwrap();
+        let sealed = alice.seal(b"passwords", b"aad").unwrap();
+        assert_eq!(alice.open(&sealed, b"aad").unwrap(), b"passwords");
+        assert!(alice.open(&sealed, b"other aad").is_err());
+        assert!(keyring.user_key("bob", 1).unwrap().open(&sealed, b"aad").is_err());
+        assert!(keyring.user_key("alice", 2).unwrap().open(&sealed, b"aad").is_err());
+        assert!(keyring.user_key("alice", 3).is_err());
+
+        fs::write(&path, "1 abcd\n").unwrap();
+        assert!(Keyring::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
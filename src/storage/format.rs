@@ -1,36 +1,114 @@
 
 use serde::{Serialize, Deserialize};
 use anyhow::*;
-use std::fs;
-use std::fs::File;
-use std::io::prelude::*;
+use std::collections::BTreeSet;
+use std::path::Path;
 use bson::*;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::server::metrics::metrics;
+use crate::storage::blobfile::{self, FileOptions};
+use crate::storage::schema;
 
 // Step 1: maintain a file with the hierarchy of blobs, to store in <user id>/blobs.bson
 // Step 2: maintain individual files for blobs at <user id>/<blob id>.json
 
 
+pub const DEFAULT_CONTENT_TYPE: &str = "application/json";
+
+// Content types are `type/subtype` MIME types, at most this many bytes of printable ASCII.
+pub const MAX_CONTENT_TYPE_BYTES: usize = 255;
+
+// Why `content_type` cannot be used as a content type, if it cannot.
+pub fn check_content_type(content_type: &str) -> Option<String> {
+    if content_type.len() > MAX_CONTENT_TYPE_BYTES {
+        return Some(format!("content type is longer than {} bytes", MAX_CONTENT_TYPE_BYTES));
+    }
+    if !content_type.bytes().all(|b| b.is_ascii_graphic() || b == b' ') {
+        return Some(format!("content type {:?} is not printable ASCII", content_type));
+    }
+    match content_type.split_once('/') {
+        Some((kind, subtype)) if !kind.is_empty() && !subtype.is_empty() => None,
+        _ => Some(format!("content type {:?} is not of the form type/subtype", content_type)),
+    }
+}
+
+// Titles are at most this many bytes of UTF-8 without control characters.
+pub const MAX_TITLE_BYTES: usize = 1024;
+
+// Why `title` cannot be used as a title, if it cannot.
+pub fn check_title(title: &str) -> Option<String> {
+    if title.len() > MAX_TITLE_BYTES {
+        return Some(format!("title is longer than {} bytes", MAX_TITLE_BYTES));
+    }
+    if title.chars().any(char::is_control) {
+        return Some(format!("title {:?} has control characters", title));
+    }
+    None
+}
+
+// Milliseconds since the Unix epoch, the unit of all `BlobNode` timestamps.
+pub fn now_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or(0)
+}
+
 // The root blob node owns all child blobs.
+//
+// Trees are stored inside a versioned envelope, see `storage::schema`. Older trees are
+// migrated on load; the metadata fields also default when missing as a last resort.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BlobNode {
   id: String,
   title: String,
   children: Vec<BlobNode>,
+  #[serde(default)]
+  created: i64,
+  #[serde(default)]
+  modified: i64,
+  // Payload size in bytes.
+  #[serde(default)]
+  size: u64,
+  #[serde(default)]
+  content_type: String,
+  // Bumped on every change to the node's payload or metadata.
+  #[serde(default)]
+  revision: u64,
+  // Free-form labels, see `storage::tags`.
+  #[serde(default)]
+  tags: BTreeSet<String>,
 }
 
 impl BlobNode {
     pub fn new(id: String, title: String, children: Vec<BlobNode>) -> BlobNode {
-        return BlobNode{id, title, children};
+        let now = now_millis();
+        BlobNode{
+            id,
+            title,
+            children,
+            created: now,
+            modified: now,
+            size: 0,
+            content_type: DEFAULT_CONTENT_TYPE.to_string(),
+            revision: 1,
+            tags: BTreeSet::new(),
+        }
+    }
+
+    // A new childless node holding a `size` byte payload.
+    pub fn with_payload(id: String, title: String, size: u64) -> BlobNode {
+        let mut node = BlobNode::new(id, title, vec![]);
+        node.size = size;
+        node
     }
 
     pub fn id(&self) -> &str {
         &self.id[..]
     }
 
-    pub fn from_file(path: &str) -> Result<BlobNode> {
+    pub fn from_file(path: &str, files: &FileOptions) -> Result<BlobNode> {
         // let fpath = path.to_string();
-        let fp = fs::read(path)?;
-        let deser: BlobNode = bson::from_slice(&fp[..])?;
+        let fp = blobfile::read(Path::new(path), files)?;
+        let (deser, _) = schema::decode_tree(&fp[..])?;
         Ok(deser)
 
     }
@@ -39,15 +117,188 @@ impl BlobNode {
         &self.children[..]
     }
 
-    pub fn flush_to_file(&self, path: &str) -> Result<()> {
-        let mut file = File::create(path)?;
-        let bs_obj = bson::to_bson(&self)?;
-        let bs = bson::to_vec(&bs_obj)?;
-        file.write_all(&bs[..])?;
+    pub fn children_mut(&mut self) -> &mut [BlobNode] {
+        &mut self.children[..]
+    }
+
+    pub fn flush_to_file(&self, path: &str, files: &FileOptions) -> Result<()> {
+        let started = Instant::now();
+        let bs = schema::encode_tree(self)?;
+        blobfile::write(Path::new(path), &bs[..], files)?;
+        metrics().flush_duration.observe(started.elapsed());
         Ok(())
     }
 
-    pub fn eq(&self, other: BlobNode) -> bool {
+    pub fn title(&self) -> &str {
+        &self.title[..]
+    }
+
+    // Does not touch the node.
+    pub fn set_title(&mut self, title: String) {
+        self.title = title;
+    }
+
+    pub fn created(&self) -> i64 {
+        self.created
+    }
+
+    pub fn modified(&self) -> i64 {
+        self.modified
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn content_type(&self) -> &str {
+        &self.content_type[..]
+    }
+
+    // Does not touch the node.
+    pub fn set_content_type(&mut self, content_type: String) {
+        self.content_type = content_type;
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn tags(&self) -> &BTreeSet<String> {
+        &self.tags
+    }
+
+    // Returns whether `tag` was not there yet. Does not touch the node.
+    pub fn add_tag(&mut self, tag: &str) -> bool {
+        self.tags.insert(tag.to_string())
+    }
+
+    // Returns whether `tag` was there. Does not touch the node.
+    pub fn remove_tag(&mut self, tag: &str) -> bool {
+        self.tags.remove(tag)
+    }
+
+    // Does not touch the node.
+    pub fn set_tags(&mut self, tags: BTreeSet<String>) {
+        self.tags = tags;
+    }
+
+    // Records a new payload of `size` bytes: bumps the revision and the modified time.
+    pub fn record_write(&mut self, size: u64) {
+        self.size = size;
+        self.touch();
+    }
+
+    // Bumps the revision and modified time after a metadata-only change.
+    pub fn touch(&mut self) {
+        self.revision += 1;
+        // Never go backwards, even if the clock does.
+        self.modified = now_millis().max(self.modified);
+    }
+
+    pub fn find(&self, id: &str) -> Option<&BlobNode> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.children.iter().find_map(|c| c.find(id))
+    }
+
+    pub fn find_mut(&mut self, id: &str) -> Option<&mut BlobNode> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.children.iter_mut().find_map(|c| c.find_mut(id))
+    }
+
+    // Distance from this node down to `id`; 0 if `id` is this node.
+    pub fn depth_of(&self, id: &str) -> Option<u32> {
+        if self.id == id {
+            return Some(0);
+        }
+        self.children.iter().find_map(|c| c.depth_of(id)).map(|d| d + 1)
+    }
+
+    // Ids of the ancestors of `id`, the root first, or `None` if `id` is not in the tree.
+    pub fn path_to(&self, id: &str) -> Option<Vec<String>> {
+        if self.id == id {
+            return Some(vec![]);
+        }
+        let mut path = self.children.iter().find_map(|c| c.path_to(id))?;
+        path.insert(0, self.id.clone());
+        Some(path)
+    }
+
+    // Length of the longest path from this node to a leaf.
+    pub fn height(&self) -> u32 {
+        self.children.iter().map(|c| c.height() + 1).max().unwrap_or(0)
+    }
+
+    pub fn node_count(&self) -> u64 {
+        1 + self.children.iter().map(|c| c.node_count()).sum::<u64>()
+    }
+
+    // Ids of this node and all of its descendants.
+    pub fn ids(&self) -> Vec<String> {
+        let mut out = vec![self.id.clone()];
+        for c in &self.children {
+            out.extend(c.ids());
+        }
+        out
+    }
+
+    pub fn add_child(&mut self, child: BlobNode) {
+        self.children.push(child);
+    }
+
+    // Inserts `child` at `position` among the children, or last if past the end.
+    pub fn insert_child(&mut self, position: usize, child: BlobNode) {
+        let position = position.min(self.children.len());
+        self.children.insert(position, child);
+    }
+
+    // Reorders the children with `compare`, keeping equal ones in their current order.
+    pub fn sort_children_by(&mut self, compare: impl FnMut(&BlobNode, &BlobNode) -> std::cmp::Ordering) {
+        self.children.sort_by(compare);
+    }
+
+    // Detaches the descendant `id` (with its subtree) from wherever it is below this node.
+    pub fn remove_descendant(&mut self, id: &str) -> Option<BlobNode> {
+        self.detach(id).map(|(_, _, node)| node)
+    }
+
+    // Like `remove_descendant`, but also returns the id of the parent it was detached
+    // from and its position among that parent's children.
+    pub fn detach(&mut self, id: &str) -> Option<(String, usize, BlobNode)> {
+        if let Some(pos) = self.children.iter().position(|c| c.id == id) {
+            return Some((self.id.clone(), pos, self.children.remove(pos)));
+        }
+        self.children.iter_mut().find_map(|c| c.detach(id))
+    }
+
+    // Descendants of this node in pre-order, down to `max_depth` levels below it (all if
+    // `None`), each with its parent, its depth (1 for children) and its position.
+    pub fn descendants(&self, max_depth: Option<u32>) -> Vec<(&BlobNode, &BlobNode, u32, u32)> {
+        let mut out = vec![];
+        self.collect_descendants(1, max_depth, &mut out);
+        out
+    }
+
+    fn collect_descendants<'a>(&'a self, depth: u32, max_depth: Option<u32>, out: &mut Vec<(&'a BlobNode, &'a BlobNode, u32, u32)>) {
+        if max_depth.map(|max| depth > max).unwrap_or(false) {
+            return;
+        }
+        for (position, child) in self.children.iter().enumerate() {
+            out.push((child, self, depth, position as u32));
+            child.collect_descendants(depth + 1, max_depth, out);
+        }
+    }
+
+    pub fn approx_size(&self) -> usize {
+        let own = std::mem::size_of::<BlobNode>() + self.id.len() + self.title.len() + self.content_type.len()
+            + self.tags.iter().map(|t| t.len()).sum::<usize>();
+        own + self.children.iter().map(|c| c.approx_size()).sum::<usize>()
+    }
+
+    pub fn shallow_eq(&self, other: BlobNode) -> bool {
         self.id.eq(&other.id) && self.children.len() == other.children.len()
     }
 }
@@ -56,6 +307,7 @@ impl BlobNode {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs;
 
     #[test]
     fn test_node_flush() {
@@ -68,10 +320,52 @@ mod tests {
         let bc1 = BlobNode::new(ID1.to_string(), TITLE1.to_string(), vec![]);
         let bc2 = BlobNode::new(ID2.to_string(), TITLE2.to_string(), vec![]);
         let bc0 = BlobNode::new(ID0.to_string(), ROOT.to_string(), vec![bc1, bc2]);
-        let res = bc0.flush_to_file("testfile.bson");
-        assert_eq!(res.is_ok(), true);
+        let res = bc0.flush_to_file("testfile.bson", &FileOptions::default());
+        assert!(res.is_ok());
         let input_file = fs::read("testfile.bson").unwrap();
-        let deserialized: BlobNode = bson::from_slice_utf8_lossy(&input_file).unwrap();
-        assert_eq!(deserialized.eq(bc0), true)
+        let envelope: Document = bson::from_slice_utf8_lossy(&input_file).unwrap();
+        let deserialized: BlobNode = bson::from_document(envelope.get_document("root").unwrap().clone()).unwrap();
+        assert!(deserialized.shallow_eq(bc0))
+    }
+
+    #[test]
+    fn test_tree_edits() {
+        let leaf = BlobNode::new("2".to_string(), "leaf".to_string(), vec![]);
+        let mid = BlobNode::new("1".to_string(), "mid".to_string(), vec![leaf]);
+        let mut root = BlobNode::new("0".to_string(), "root".to_string(), vec![mid]);
+        assert_eq!(root.height(), 2);
+        assert_eq!(root.node_count(), 3);
+        assert_eq!(root.depth_of("2"), Some(2));
+        assert_eq!(root.path_to("2"), Some(vec!["0".to_string(), "1".to_string()]));
+
+        root.find_mut("2").unwrap().add_child(BlobNode::new("3".to_string(), "deep".to_string(), vec![]));
+        assert_eq!(root.height(), 3);
+
+        let removed = root.remove_descendant("1").unwrap();
+        assert_eq!(removed.ids(), vec!["1", "2", "3"]);
+        assert_eq!(root.node_count(), 1);
+        assert!(root.find("3").is_none());
+    }
+
+    #[test]
+    fn test_legacy_tree_loads_with_migrated_metadata() {
+        let legacy = doc! {
+            "id": "0",
+            "title": "root",
+            "children": [ { "id": "1", "title": "notes", "children": [] } ],
+        };
+        let path = std::env::temp_dir().join(format!("bearcub-legacy-{}.bson", std::process::id()));
+        fs::write(&path, bson::to_vec(&legacy).unwrap()).unwrap();
+        let mut root = BlobNode::from_file(&path.to_string_lossy(), &FileOptions::default()).unwrap();
+        let _ = fs::remove_file(&path);
+
+        let notes = root.find_mut("1").unwrap();
+        assert_eq!(notes.revision(), 1);
+        assert_eq!(notes.size(), 0);
+        assert_eq!(notes.content_type(), DEFAULT_CONTENT_TYPE);
+        notes.record_write(12);
+        assert_eq!(notes.revision(), 2);
+        assert_eq!(notes.size(), 12);
+        assert!(notes.modified() > 0);
     }
 }
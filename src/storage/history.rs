@@ -0,0 +1,156 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::*;
+
+use crate::storage::blobfile;
+use crate::storage::format::now_millis;
+
+// Old payloads live at <user id>/history/<blob id>/<revision>.json. A payload is moved
+// there (not copied) when it is replaced, so the file keeps the mtime of the write that
+// created it, which is what the retention window is measured against.
+pub const HISTORY_DIR_NAME: &str = "history";
+
+// Which superseded revisions to keep. A revision is pruned once it falls outside either
+// limit; with neither set, history is kept forever.
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    // Newest revisions to keep per blob. `Some(0)` disables history.
+    pub max_revisions: Option<usize>,
+    pub max_age: Option<Duration>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> RetentionPolicy {
+        RetentionPolicy { max_revisions: Some(10), max_age: None }
+    }
+}
+
+impl RetentionPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.max_revisions != Some(0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RevisionInfo {
+    pub revision: u64,
+    // Time the revision was written, ms since the Unix epoch.
+    pub modified: i64,
+    pub size: u64,
+}
+
+pub fn history_dir(user_dir: &Path, id: &str) -> PathBuf {
+    user_dir.join(HISTORY_DIR_NAME).join(id)
+}
+
+pub fn revision_path(user_dir: &Path, id: &str, revision: u64) -> PathBuf {
+    history_dir(user_dir, id).join(format!("{}.json", revision))
+}
+
+// Moves the payload at `current` into the history of `id` as `revision`.
+pub fn archive(user_dir: &Path, id: &str, revision: u64, current: &Path) -> Result<()> {
+    fs::create_dir_all(history_dir(user_dir, id))?;
+    fs::rename(current, revision_path(user_dir, id, revision))?;
+    Ok(())
+}
+
+// Archived revisions of `id`, oldest first.
+pub fn list(user_dir: &Path, id: &str) -> Result<Vec<RevisionInfo>> {
+    let dir = history_dir(user_dir, id);
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut out = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(revision) = name.strip_suffix(".json").and_then(|r| r.parse::<u64>().ok()) else {
+            continue;
+        };
+        let meta = entry.metadata()?;
+        let modified = meta.modified()?.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or(0);
+        let size = blobfile::stored_len(&entry.path())?;
+        out.push(RevisionInfo { revision, modified, size });
+    }
+    out.sort_by_key(|r| r.revision);
+    Ok(out)
+}
+
+pub fn remove_all(user_dir: &Path, id: &str) -> Result<()> {
+    match fs::remove_dir_all(history_dir(user_dir, id)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// Deletes the revisions of `id` that `policy` no longer keeps. Returns how many.
+pub fn prune(user_dir: &Path, id: &str, policy: &RetentionPolicy) -> Result<usize> {
+    let revisions = list(user_dir, id)?;
+    let keep_from = match policy.max_revisions {
+        Some(n) => revisions.len().saturating_sub(n),
+        None => 0,
+    };
+    let cutoff = policy.max_age.map(|age| now_millis() - age.as_millis() as i64);
+    let mut pruned = 0;
+    for (idx, rev) in revisions.iter().enumerate() {
+        let too_old = cutoff.map(|c| rev.modified < c).unwrap_or(false);
+        if idx < keep_from || too_old {
+            fs::remove_file(revision_path(user_dir, id, rev.revision))?;
+            pruned += 1;
+        }
+    }
+    if pruned == revisions.len() && pruned > 0 {
+        let _ = fs::remove_dir(history_dir(user_dir, id));
+    }
+    Ok(pruned)
+}
+
+// Applies `policy` to every blob with history under `user_dir`. Returns how many
+// revisions were deleted. The caller holds the user's lock, as for `archive`.
+pub fn prune_user(user_dir: &Path, policy: &RetentionPolicy) -> Result<usize> {
+    let dir = user_dir.join(HISTORY_DIR_NAME);
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+    let mut pruned = 0;
+    for blob in fs::read_dir(dir)? {
+        let id = blob?.file_name().to_string_lossy().to_string();
+        pruned += prune(user_dir, &id, policy)?;
+    }
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_archive_list_and_prune() {
+        let data_dir = std::env::temp_dir().join(format!("bearcub-history-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        let user_dir = data_dir.join("u");
+        fs::create_dir_all(&user_dir).unwrap();
+        let current = user_dir.join("b.json");
+        for rev in 1..=4 {
+            fs::write(&current, format!("v{}", rev)).unwrap();
+            archive(&user_dir, "b", rev, &current).unwrap();
+        }
+        let revs: Vec<u64> = list(&user_dir, "b").unwrap().iter().map(|r| r.revision).collect();
+        assert_eq!(revs, vec![1, 2, 3, 4]);
+        assert_eq!(fs::read(revision_path(&user_dir, "b", 2)).unwrap(), b"v2");
+
+        let keep_two = RetentionPolicy { max_revisions: Some(2), max_age: None };
+        assert_eq!(prune_user(&user_dir, &keep_two).unwrap(), 2);
+        let revs: Vec<u64> = list(&user_dir, "b").unwrap().iter().map(|r| r.revision).collect();
+        assert_eq!(revs, vec![3, 4]);
+
+        // Everything was written just now, so a zero-length window drops it all.
+        std::thread::sleep(Duration::from_millis(5));
+        let no_age = RetentionPolicy { max_revisions: None, max_age: Some(Duration::ZERO) };
+        assert_eq!(prune(&user_dir, "b", &no_age).unwrap(), 2);
+        assert!(list(&user_dir, "b").unwrap().is_empty());
+        let _ = fs::remove_dir_all(&data_dir);
+    }
+}
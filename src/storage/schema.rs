@@ -0,0 +1,218 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::*;
+use bson::{doc, Bson, Document};
+
+use crate::storage::blobfile::{self, FileOptions};
+use crate::storage::format::{BlobNode, DEFAULT_CONTENT_TYPE};
+
+// Version written by this build. Bump it together with a new entry in `MIGRATIONS`
+// whenever the on-disk shape of `BlobNode` changes.
+pub const CURRENT_SCHEMA_VERSION: u32 = 3;
+
+// Files from before the envelope existed hold a bare tree and count as version 1.
+const UNVERSIONED: u32 = 1;
+
+type Migration = fn(Document) -> Result<Document>;
+
+// Each step upgrades a serialized root node from `from` to `from + 1`.
+struct MigrationStep {
+    from: u32,
+    migrate: Migration,
+}
+
+const MIGRATIONS: &[MigrationStep] = &[
+    MigrationStep { from: 1, migrate: v1_add_metadata },
+    MigrationStep { from: 2, migrate: v2_add_tags },
+];
+
+// v1 trees predate the metadata fields: give every node explicit values for them.
+fn v1_add_metadata(mut node: Document) -> Result<Document> {
+    for key in ["created", "modified", "size"] {
+        if !node.contains_key(key) {
+            node.insert(key, 0_i64);
+        }
+    }
+    if !node.contains_key("content_type") {
+        node.insert("content_type", DEFAULT_CONTENT_TYPE);
+    }
+    if !node.contains_key("revision") {
+        node.insert("revision", 1_i64);
+    }
+    let children = match node.remove("children") {
+        Some(Bson::Array(children)) => children,
+        _ => vec![],
+    };
+    let mut migrated = Vec::with_capacity(children.len());
+    for child in children {
+        match child {
+            Bson::Document(d) => migrated.push(Bson::Document(v1_add_metadata(d)?)),
+            other => bail!("unexpected child node {:?}", other),
+        }
+    }
+    node.insert("children", migrated);
+    Ok(node)
+}
+
+// v2 trees predate tags: give every node an empty set of them.
+fn v2_add_tags(mut node: Document) -> Result<Document> {
+    if !node.contains_key("tags") {
+        node.insert("tags", Bson::Array(vec![]));
+    }
+    let children = match node.remove("children") {
+        Some(Bson::Array(children)) => children,
+        _ => vec![],
+    };
+    let mut migrated = Vec::with_capacity(children.len());
+    for child in children {
+        match child {
+            Bson::Document(d) => migrated.push(Bson::Document(v2_add_tags(d)?)),
+            other => bail!("unexpected child node {:?}", other),
+        }
+    }
+    node.insert("children", migrated);
+    Ok(node)
+}
+
+// Splits a serialized tree into its schema version and root node document.
+pub fn read_envelope(bytes: &[u8]) -> Result<(u32, Document)> {
+    let mut doc: Document = bson::from_slice(bytes)?;
+    if !doc.contains_key("schema_version") {
+        return Ok((UNVERSIONED, doc));
+    }
+    let version = match doc.get("schema_version") {
+        Some(Bson::Int32(v)) => *v as u32,
+        Some(Bson::Int64(v)) => *v as u32,
+        other => bail!("bad schema_version {:?}", other),
+    };
+    let root = doc.get_document_mut("root").with_context(|| "envelope without root")?;
+    Ok((version, std::mem::take(root)))
+}
+
+// Runs every registered migration needed to bring `root` from `version` to current.
+pub fn upgrade(mut version: u32, mut root: Document) -> Result<Document> {
+    if version > CURRENT_SCHEMA_VERSION {
+        bail!("schema version {} is newer than this build supports ({})", version, CURRENT_SCHEMA_VERSION);
+    }
+    while version < CURRENT_SCHEMA_VERSION {
+        let step = MIGRATIONS
+            .iter()
+            .find(|m| m.from == version)
+            .ok_or_else(|| anyhow!("no migration from schema version {}", version))?;
+        root = (step.migrate)(root).with_context(|| format!("migrating from schema version {}", version))?;
+        version += 1;
+    }
+    Ok(root)
+}
+
+// Decodes a tree file of any supported version, returning the tree and the version it
+// was stored as.
+pub fn decode_tree(bytes: &[u8]) -> Result<(BlobNode, u32)> {
+    let (version, root) = read_envelope(bytes)?;
+    let root = upgrade(version, root)?;
+    Ok((bson::from_document(root)?, version))
+}
+
+pub fn encode_tree(root: &BlobNode) -> Result<Vec<u8>> {
+    let envelope = doc! {
+        "schema_version": CURRENT_SCHEMA_VERSION as i32,
+        "root": bson::to_document(root)?,
+    };
+    Ok(bson::to_vec(&envelope)?)
+}
+
+#[derive(Debug)]
+pub enum MigrationOutcome {
+    Upgraded { from: u32 },
+    AlreadyCurrent,
+    Failed(String),
+}
+
+#[derive(Debug)]
+pub struct MigrationReport {
+    pub user_id: String,
+    pub outcome: MigrationOutcome,
+}
+
+// Upgrades every `<user id>/<tree_file>` under `data_dir` to the current schema version,
+// reading and writing each with `files(user id)`. One user failing does not stop the
+// others; check the reports.
+pub fn migrate_data_dir(data_dir: &Path, tree_file: &str, files: impl Fn(&str) -> FileOptions) -> Result<Vec<MigrationReport>> {
+    let mut reports = vec![];
+    let mut entries: Vec<_> = fs::read_dir(data_dir)?.collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path().join(tree_file);
+        if !path.is_file() {
+            continue;
+        }
+        let user_id = entry.file_name().to_string_lossy().to_string();
+        let outcome = match migrate_file(&path, &files(&user_id)) {
+            Result::Ok(Some(from)) => MigrationOutcome::Upgraded { from },
+            Result::Ok(None) => MigrationOutcome::AlreadyCurrent,
+            Err(e) => MigrationOutcome::Failed(format!("{:#}", e)),
+        };
+        reports.push(MigrationReport { user_id, outcome });
+    }
+    Ok(reports)
+}
+
+// Returns the version the file was upgraded from, or `None` if it was already current.
+fn migrate_file(path: &Path, files: &FileOptions) -> Result<Option<u32>> {
+    let bytes = blobfile::read(path, files)?;
+    let (root, version) = decode_tree(&bytes)?;
+    if version == CURRENT_SCHEMA_VERSION {
+        return Ok(None);
+    }
+    root.flush_to_file(&path.to_string_lossy(), files)?;
+    Ok(Some(version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate_data_dir_reports_per_user() {
+        let dir = std::env::temp_dir().join(format!("bearcub-migrate-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for user in ["a", "b", "c"] {
+            fs::create_dir_all(dir.join(user)).unwrap();
+        }
+        let legacy = doc! { "id": "a", "title": "root", "children": [ { "id": "1", "title": "notes", "children": [] } ] };
+        fs::write(dir.join("a").join("blobs.bson"), bson::to_vec(&legacy).unwrap()).unwrap();
+        BlobNode::new("b".to_string(), "root".to_string(), vec![]).flush_to_file(&dir.join("b").join("blobs.bson").to_string_lossy(), &FileOptions::default()).unwrap();
+        fs::write(dir.join("c").join("blobs.bson"), b"not bson").unwrap();
+
+        let reports = migrate_data_dir(&dir, "blobs.bson", |_| FileOptions::default()).unwrap();
+        assert_eq!(reports.len(), 3);
+        assert!(matches!(reports[0].outcome, MigrationOutcome::Upgraded { from: 1 }));
+        assert!(matches!(reports[1].outcome, MigrationOutcome::AlreadyCurrent));
+        assert!(matches!(reports[2].outcome, MigrationOutcome::Failed(_)));
+
+        let (root, version) = decode_tree(&fs::read(dir.join("a").join("blobs.bson")).unwrap()).unwrap();
+        assert_eq!(version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(root.find("1").unwrap().revision(), 1);
+        assert_eq!(root.find("1").unwrap().content_type(), DEFAULT_CONTENT_TYPE);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_v2_tree_gains_empty_tags() {
+        let envelope = doc! {
+            "schema_version": 2,
+            "root": { "id": "0", "title": "", "children": [ { "id": "1", "title": "notes", "children": [], "revision": 4_i64 } ] },
+        };
+        let (root, version) = decode_tree(&bson::to_vec(&envelope).unwrap()).unwrap();
+        assert_eq!(version, 2);
+        assert!(root.find("1").unwrap().tags().is_empty());
+        assert_eq!(root.find("1").unwrap().revision(), 4);
+    }
+
+    #[test]
+    fn test_newer_version_is_rejected() {
+        let envelope = doc! { "schema_version": (CURRENT_SCHEMA_VERSION + 1) as i32, "root": { "id": "0", "title": "", "children": [] } };
+        assert!(decode_tree(&bson::to_vec(&envelope).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::*;
+use bson::{doc, Document};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::blobfile::{self, FileOptions};
+use crate::storage::schema::CURRENT_SCHEMA_VERSION;
+
+// Inverted index over the titles and payload text of a user's blobs, stored as
+// <user id>/search.bson. Blobs in the trash are not in it. It only holds what can be
+// derived from the tree and the payloads, so a missing file is rebuilt from those. The
+// file records the last change log sequence number it covers, so one saved before later
+// changes is known to be stale and rebuilt as well.
+pub const SEARCH_INDEX_FILE_NAME: &str = "search.bson";
+
+// Payloads larger than this are only found by their title.
+pub const MAX_INDEXED_PAYLOAD_BYTES: u64 = 1 << 20;
+
+// A word of the title counts as much as this many words of the payload.
+const TITLE_WEIGHT: u32 = 3;
+
+// Longer words are indexed by their first this many characters.
+const MAX_TERM_CHARS: usize = 64;
+
+// Characters of context kept on each side of the word a snippet is centered on.
+const SNIPPET_CONTEXT_CHARS: usize = 40;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchIndex {
+    // Term -> id -> weighted number of occurrences.
+    postings: BTreeMap<String, BTreeMap<String, u32>>,
+    // Id -> its terms, to take a blob out of `postings` again.
+    docs: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    pub id: String,
+    pub score: f64,
+}
+
+impl SearchIndex {
+    // Indexes `id` under the words of `title` and `text`, replacing what it was indexed
+    // under before.
+    pub fn insert(&mut self, id: &str, title: &str, text: &str) {
+        self.remove(id);
+        let mut counts: HashMap<String, u32> = HashMap::new();
+        for term in tokenize(title) {
+            *counts.entry(term).or_default() += TITLE_WEIGHT;
+        }
+        for term in tokenize(text) {
+            *counts.entry(term).or_default() += 1;
+        }
+        let mut terms = Vec::with_capacity(counts.len());
+        for (term, n) in counts {
+            self.postings.entry(term.clone()).or_default().insert(id.to_string(), n);
+            terms.push(term);
+        }
+        terms.sort();
+        self.docs.insert(id.to_string(), terms);
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        for term in self.docs.remove(id).unwrap_or_default() {
+            if let Some(postings) = self.postings.get_mut(&term) {
+                postings.remove(id);
+                if postings.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.docs.contains_key(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.docs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.docs.is_empty()
+    }
+
+    // Blobs with any word of `query`, best first, at most `limit` of them. Each word adds
+    // its inverse document frequency, scaled by how often the blob has it (saturating),
+    // so blobs with more of the words, and with rarer ones, come first.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<SearchHit> {
+        let n = self.docs.len() as f64;
+        let terms: HashSet<String> = tokenize(query).into_iter().collect();
+        let mut scores: HashMap<&str, f64> = HashMap::new();
+        for term in &terms {
+            let Some(postings) = self.postings.get(term) else {
+                continue;
+            };
+            let idf = (1.0 + n / postings.len() as f64).ln();
+            for (id, tf) in postings {
+                let tf = *tf as f64;
+                *scores.entry(id).or_default() += idf * tf / (tf + 1.2);
+            }
+        }
+        let mut hits: Vec<SearchHit> = scores.into_iter().map(|(id, score)| SearchHit { id: id.to_string(), score }).collect();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.id.cmp(&b.id)));
+        hits.truncate(limit);
+        hits
+    }
+
+    pub fn approx_size(&self) -> usize {
+        let postings: usize = self.postings.iter().map(|(term, ids)| term.len() + ids.keys().map(|id| id.len() + 4).sum::<usize>()).sum();
+        let docs: usize = self.docs.iter().map(|(id, terms)| id.len() + terms.iter().map(|t| t.len()).sum::<usize>()).sum();
+        postings + docs
+    }
+}
+
+// Byte ranges of the words of `text`: runs of letters and digits.
+fn word_spans(text: &str) -> Vec<(usize, usize)> {
+    let mut spans = vec![];
+    let mut start = None;
+    for (idx, c) in text.char_indices() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(idx),
+            (false, Some(s)) => {
+                spans.push((s, idx));
+                start = None;
+            },
+            _ => (),
+        }
+    }
+    if let Some(s) = start {
+        spans.push((s, text.len()));
+    }
+    spans
+}
+
+fn normalize(word: &str) -> String {
+    word.chars().take(MAX_TERM_CHARS).collect::<String>().to_lowercase()
+}
+
+// The words of `text` as they are indexed: lowercase, and cut to `MAX_TERM_CHARS`.
+pub fn tokenize(text: &str) -> Vec<String> {
+    word_spans(text).into_iter().map(|(s, e)| normalize(&text[s..e])).collect()
+}
+
+// The searchable text of a payload: the string values of a JSON payload, or the payload
+// itself if it is not JSON but UTF-8.
+pub fn payload_text(data: &[u8]) -> String {
+    match serde_json::from_slice::<serde_json::Value>(data) {
+        Result::Ok(value) => {
+            let mut out = String::new();
+            collect_strings(&value, &mut out);
+            out
+        },
+        Err(_) => String::from_utf8(data.to_vec()).unwrap_or_default(),
+    }
+}
+
+fn collect_strings(value: &serde_json::Value, out: &mut String) {
+    match value {
+        serde_json::Value::String(s) => {
+            out.push_str(s);
+            out.push('\n');
+        },
+        serde_json::Value::Array(values) => values.iter().for_each(|v| collect_strings(v, out)),
+        serde_json::Value::Object(fields) => fields.values().for_each(|v| collect_strings(v, out)),
+        _ => (),
+    }
+}
+
+// A short excerpt of `text` around the first word of `query` in it, on one line, with '…'
+// where it was cut. `None` if `text` has none of the words.
+pub fn snippet(text: &str, query: &str) -> Option<String> {
+    let terms: HashSet<String> = tokenize(query).into_iter().collect();
+    let (start, end) = word_spans(text).into_iter().find(|(s, e)| terms.contains(&normalize(&text[*s..*e])))?;
+    let mut from = text[..start].char_indices().rev().nth(SNIPPET_CONTEXT_CHARS - 1).map(|(idx, _)| idx).unwrap_or(0);
+    let mut to = text[end..].char_indices().nth(SNIPPET_CONTEXT_CHARS).map(|(idx, _)| end + idx).unwrap_or(text.len());
+    // Not in the middle of a word.
+    if from > 0 {
+        from = text[from..start].find(char::is_whitespace).map(|idx| from + idx).unwrap_or(from);
+    }
+    if to < text.len() {
+        to = text[end..to].rfind(char::is_whitespace).map(|idx| end + idx).unwrap_or(to);
+    }
+    let mut out = String::new();
+    if from > 0 {
+        out.push('…');
+    }
+    out.push_str(&text[from..to].split_whitespace().collect::<Vec<_>>().join(" "));
+    if to < text.len() {
+        out.push('…');
+    }
+    Some(out)
+}
+
+// The index at `path` and the change log sequence number it was saved at, `None` if
+// there is no index file yet.
+pub fn load(path: &Path, files: &FileOptions) -> Result<Option<(SearchIndex, u64)>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let doc: Document = bson::from_slice(&blobfile::read(path, files)?)?;
+    doc.get_i32("schema_version").with_context(|| "search index without schema_version")?;
+    let seq = doc.get_i64("seq").with_context(|| "search index without seq")?;
+    Ok(Some((bson::from_document(doc.get_document("index")?.clone())?, seq as u64)))
+}
+
+pub fn save(path: &Path, index: &SearchIndex, seq: u64, files: &FileOptions) -> Result<()> {
+    let doc = doc! { "schema_version": CURRENT_SCHEMA_VERSION as i32, "seq": seq as i64, "index": bson::to_bson(index)? };
+    blobfile::write(path, &bson::to_vec(&doc)?, files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ranking_updates_and_snippets() {
+        let mut index = SearchIndex::default();
+        index.insert("a", "Groceries", &payload_text(br#"{"items": ["milk", "eggs", "bread"], "qty": 3}"#));
+        index.insert("b", "Recipes", &payload_text(br#"{"text": "Whisk the eggs with milk. More milk if too thick."}"#));
+        index.insert("c", "Passwords", &payload_text(b"not json: bank pin"));
+
+        let ids = |hits: Vec<SearchHit>| hits.into_iter().map(|h| h.id).collect::<Vec<_>>();
+        assert_eq!(ids(index.search("MILK", 10)), vec!["b", "a"]);
+        // A title word outweighs a payload word; unknown words change nothing.
+        assert_eq!(ids(index.search("groceries eggs zucchini", 10)), vec!["a", "b"]);
+        assert_eq!(ids(index.search("bank", 10)), vec!["c"]);
+        assert_eq!(index.search("milk", 1).len(), 1);
+        assert!(index.search("qty", 10).is_empty());
+
+        index.insert("a", "Groceries", &payload_text(br#"{"items": ["apples"]}"#));
+        index.remove("b");
+        assert!(index.search("milk", 10).is_empty());
+        assert_eq!(ids(index.search("apples", 10)), vec!["a"]);
+        assert!(!index.contains("b"));
+
+        let text = payload_text(br#"{"text": "Preheat the oven.\nWhisk the eggs with milk, then fold in the flour slowly and evenly."}"#);
+        assert_eq!(snippet(&text, "FLOUR").unwrap(), "…the eggs with milk, then fold in the flour slowly and evenly.");
+        assert_eq!(snippet("Short note", "note").unwrap(), "Short note");
+        assert!(snippet("Short note", "long").is_none());
+        assert_eq!(tokenize("Crème brûlée, x2!"), vec!["crème", "brûlée", "x2"]);
+    }
+
+    #[test]
+    fn test_index_file_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("bearcub-search-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(SEARCH_INDEX_FILE_NAME);
+        assert!(load(&path, &FileOptions::default()).unwrap().is_none());
+        let mut index = SearchIndex::default();
+        index.insert("a", "Groceries", "milk eggs");
+        save(&path, &index, 7, &FileOptions::default()).unwrap();
+        let (loaded, seq) = load(&path, &FileOptions::default()).unwrap().unwrap();
+        assert_eq!(loaded.search("eggs", 10), index.search("eggs", 10));
+        assert_eq!(seq, 7);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::storage::format::BlobNode;
+
+// Tags are case-sensitive, at most this many bytes of UTF-8 without control characters.
+pub const MAX_TAG_BYTES: usize = 128;
+
+pub const MAX_TAGS_PER_BLOB: usize = 64;
+
+// Which blobs carry each tag, so listing a tag does not walk the tree. The tags themselves
+// live on the nodes in blobs.bson; this only mirrors them, is built when the tree is
+// loaded and kept up to date with every change. Blobs in the trash are not in it.
+#[derive(Debug, Clone, Default)]
+pub struct TagIndex {
+    // Tag -> ids of the blobs with it.
+    by_tag: BTreeMap<String, BTreeSet<String>>,
+    // Id -> its tags, to take a blob out of `by_tag` again.
+    by_id: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl TagIndex {
+    // Indexes the tags of every node below `root`.
+    pub fn build(root: &BlobNode) -> TagIndex {
+        let mut index = TagIndex::default();
+        for (node, _, _, _) in root.descendants(None) {
+            index.set(node.id(), node.tags());
+        }
+        index
+    }
+
+    // Replaces the tags `id` is listed under with `tags`.
+    pub fn set(&mut self, id: &str, tags: &BTreeSet<String>) {
+        self.remove(id);
+        if tags.is_empty() {
+            return;
+        }
+        for tag in tags {
+            self.by_tag.entry(tag.clone()).or_default().insert(id.to_string());
+        }
+        self.by_id.insert(id.to_string(), tags.clone());
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        for tag in self.by_id.remove(id).unwrap_or_default() {
+            if let Some(ids) = self.by_tag.get_mut(&tag) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.by_tag.remove(&tag);
+                }
+            }
+        }
+    }
+
+    // Ids of the blobs tagged `tag`, in id order.
+    pub fn ids(&self, tag: &str) -> Vec<String> {
+        self.by_tag.get(tag).map(|ids| ids.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    pub fn approx_size(&self) -> usize {
+        let by_tag: usize = self.by_tag.iter().map(|(tag, ids)| tag.len() + ids.iter().map(|id| id.len()).sum::<usize>()).sum();
+        let by_id: usize = self.by_id.iter().map(|(id, tags)| id.len() + tags.iter().map(|t| t.len()).sum::<usize>()).sum();
+        by_tag + by_id
+    }
+}
+
+// Why `tag` cannot be used as a tag, if it cannot.
+pub fn check_tag(tag: &str) -> Option<String> {
+    if tag.is_empty() {
+        return Some("tags cannot be empty".to_string());
+    }
+    if tag.len() > MAX_TAG_BYTES {
+        return Some(format!("tag {:?} is longer than {} bytes", tag, MAX_TAG_BYTES));
+    }
+    if tag.chars().any(char::is_control) {
+        return Some(format!("tag {:?} has control characters", tag));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tagged(id: &str, tags: &[&str]) -> BlobNode {
+        let mut node = BlobNode::new(id.to_string(), String::new(), vec![]);
+        for tag in tags {
+            node.add_tag(tag);
+        }
+        node
+    }
+
+    #[test]
+    fn test_index_follows_tags() {
+        let mut folder = tagged("1", &["work"]);
+        folder.add_child(tagged("2", &["work", "urgent"]));
+        let root = BlobNode::new("0".to_string(), "root".to_string(), vec![folder, tagged("3", &[])]);
+        let mut index = TagIndex::build(&root);
+        assert_eq!(index.ids("work"), vec!["1", "2"]);
+        assert_eq!(index.ids("urgent"), vec!["2"]);
+        assert!(index.ids("Work").is_empty());
+
+        index.set("3", tagged("3", &["urgent"]).tags());
+        index.set("2", tagged("2", &["work"]).tags());
+        index.remove("1");
+        assert_eq!(index.ids("work"), vec!["2"]);
+        assert_eq!(index.ids("urgent"), vec!["3"]);
+        index.remove("3");
+        assert!(!index.by_tag.contains_key("urgent"));
+
+        assert!(check_tag("to read").is_none());
+        assert!(check_tag("").is_some());
+        assert!(check_tag("a\nb").is_some());
+        assert!(check_tag(&"x".repeat(MAX_TAG_BYTES + 1)).is_some());
+    }
+}
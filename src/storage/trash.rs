@@ -0,0 +1,87 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::*;
+use bson::{doc, Bson, Document};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::blobfile::{self, FileOptions};
+use crate::storage::format::BlobNode;
+use crate::storage::schema::{self, CURRENT_SCHEMA_VERSION};
+
+// Removed subtrees of a user, stored next to the tree as <user id>/trash.bson. Their
+// payloads and history stay where they are until the entry is purged.
+pub const TRASH_FILE_NAME: &str = "trash.bson";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TrashEntry {
+    pub subtree: BlobNode,
+    // Where the subtree was attached, so a restore can put it back in place.
+    pub parent_id: String,
+    pub position: u32,
+    // ms since the Unix epoch.
+    pub deleted_at: i64,
+}
+
+// Reads the trash of a user; a missing file is an empty trash. Subtrees are migrated
+// like trees, and the file is versioned with the same schema version.
+pub fn load(path: &Path, files: &FileOptions) -> Result<Vec<TrashEntry>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let doc: Document = bson::from_slice(&blobfile::read(path, files)?)?;
+    let version = doc.get_i32("schema_version").with_context(|| "trash without schema_version")? as u32;
+    let mut entries = vec![];
+    for entry in doc.get_array("entries")? {
+        let Bson::Document(entry) = entry else {
+            bail!("bad trash entry {:?}", entry);
+        };
+        let mut entry = entry.clone();
+        let subtree = entry.get_document("subtree")?.clone();
+        entry.insert("subtree", schema::upgrade(version, subtree)?);
+        entries.push(bson::from_document(entry)?);
+    }
+    Ok(entries)
+}
+
+pub fn save(path: &Path, entries: &[TrashEntry], files: &FileOptions) -> Result<()> {
+    if entries.is_empty() {
+        return match fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        };
+    }
+    let mut docs = Vec::with_capacity(entries.len());
+    for e in entries {
+        docs.push(bson::to_bson(e)?);
+    }
+    let doc = doc! { "schema_version": CURRENT_SCHEMA_VERSION as i32, "entries": docs };
+    blobfile::write(path, &bson::to_vec(&doc)?, files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_load_roundtrip() {
+        let path = std::env::temp_dir().join(format!("bearcub-trash-{}.bson", std::process::id()));
+        let leaf = BlobNode::new("2".to_string(), "leaf".to_string(), vec![]);
+        let entry = TrashEntry {
+            subtree: BlobNode::new("1".to_string(), "notes".to_string(), vec![leaf]),
+            parent_id: "0".to_string(),
+            position: 3,
+            deleted_at: 1234,
+        };
+        let files = FileOptions::default();
+        save(&path, &[entry], &files).unwrap();
+        let loaded = load(&path, &files).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].subtree.ids(), vec!["1", "2"]);
+        assert_eq!((loaded[0].parent_id.as_str(), loaded[0].position, loaded[0].deleted_at), ("0", 3, 1234));
+
+        save(&path, &[], &files).unwrap();
+        assert!(!path.exists());
+        assert!(load(&path, &files).unwrap().is_empty());
+    }
+}